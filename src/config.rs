@@ -0,0 +1,353 @@
+//! Runtime configuration. Values come from built-in defaults, then an
+//! optional TOML file, then environment variables (including those loaded
+//! from `.env`), each layer overriding the previous one.
+
+use std::fmt;
+use std::time::Duration;
+use serde::Deserialize;
+use crate::db::PoolConfig;
+use crate::services::crud::{DEFAULT_MAX_PENDING, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+
+/// Used when `FASTFOOD_CONFIG` is not set; it is fine for it not to exist.
+pub const DEFAULT_CONFIG_FILE: &str = "fastfood.toml";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub log_level: String,
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub schema: SchemaConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    /// Number of actix workers; defaults to the number of physical cores.
+    pub workers: Option<usize>,
+    /// Maximum size of a JSON request body, in bytes.
+    pub json_limit: usize,
+    /// Rows per page of a listing when `?limit=` is not given.
+    pub default_page_size: i64,
+    /// The largest `?limit=` a listing accepts.
+    pub max_page_size: i64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: String,
+    pub pool_max_size: u32,
+    pub pool_min_idle: Option<u32>,
+    pub pool_timeout_secs: u64,
+    pub max_pending: usize,
+}
+
+/// A declarative schema file applied at startup; see `services::declarative`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchemaConfig {
+    /// YAML, or JSON when the name ends in `.json`.
+    pub file: Option<String>,
+    /// Let the file drop tables and columns it no longer lists.
+    pub allow_destructive: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            log_level: "info".to_string(),
+            server: ServerConfig::default(),
+            database: DatabaseConfig::default(),
+            schema: SchemaConfig::default(),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: "0.0.0.0".to_string(),
+            port: 8080,
+            workers: None,
+            json_limit: 256 * 1024,
+            default_page_size: DEFAULT_PAGE_SIZE,
+            max_page_size: MAX_PAGE_SIZE,
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        let pool = PoolConfig::default();
+
+        Self {
+            url: "app.sqlite".to_string(),
+            pool_max_size: pool.max_size,
+            pool_min_idle: pool.min_idle,
+            pool_timeout_secs: pool.connection_timeout.as_secs(),
+            max_pending: DEFAULT_MAX_PENDING,
+        }
+    }
+}
+
+impl DatabaseConfig {
+    pub fn pool(&self) -> PoolConfig {
+        PoolConfig {
+            max_size: self.pool_max_size,
+            min_idle: self.pool_min_idle,
+            connection_timeout: Duration::from_secs(self.pool_timeout_secs),
+            max_pending: self.max_pending,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String, std::io::Error),
+    Parse(String, toml::de::Error),
+    Env(String),
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "could not read config file {}: {}", path, e),
+            ConfigError::Parse(path, e) => write!(f, "could not parse config file {}: {}", path, e),
+            ConfigError::Env(message) => write!(f, "{}", message),
+            ConfigError::Invalid(problems) => write!(f, "invalid configuration: {}", problems.join("; ")),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Loads the configuration from `FASTFOOD_CONFIG` (or `fastfood.toml`
+    /// when present) and the process environment, then validates it.
+    pub fn load() -> Result<Self, ConfigError> {
+        let (path, required) = match std::env::var("FASTFOOD_CONFIG") {
+            Ok(path) => (path, true),
+            Err(_) => (DEFAULT_CONFIG_FILE.to_string(), false),
+        };
+
+        let file = match std::fs::read_to_string(&path) {
+            Ok(contents) => Some((path, contents)),
+            Err(e) if required || e.kind() != std::io::ErrorKind::NotFound => return Err(ConfigError::Io(path, e)),
+            Err(_) => None,
+        };
+
+        Self::from_sources(file, |name| std::env::var(name).ok())
+    }
+
+    /// Builds the configuration from an optional `(path, contents)` TOML
+    /// file and an environment lookup.
+    pub fn from_sources(file: Option<(String, String)>, env: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let mut config = match file {
+            Some((path, contents)) => toml::from_str(&contents).map_err(|e| ConfigError::Parse(path, e))?,
+            None => Config::default(),
+        };
+
+        if let Some(level) = env("RUST_LOG") {
+            config.log_level = level;
+        }
+        if let Some(host) = env("FASTFOOD_HOST") {
+            config.server.host = host;
+        }
+        if let Some(port) = parse_env(&env, "FASTFOOD_PORT")? {
+            config.server.port = port;
+        }
+        if let Some(workers) = parse_env(&env, "FASTFOOD_WORKERS")? {
+            config.server.workers = Some(workers);
+        }
+        if let Some(json_limit) = parse_env(&env, "FASTFOOD_JSON_LIMIT")? {
+            config.server.json_limit = json_limit;
+        }
+        if let Some(default_page_size) = parse_env(&env, "FASTFOOD_DEFAULT_PAGE_SIZE")? {
+            config.server.default_page_size = default_page_size;
+        }
+        if let Some(max_page_size) = parse_env(&env, "FASTFOOD_MAX_PAGE_SIZE")? {
+            config.server.max_page_size = max_page_size;
+        }
+        if let Some(url) = env("DATABASE_URL") {
+            config.database.url = url;
+        }
+        if let Some(max_size) = parse_env(&env, "DATABASE_POOL_MAX_SIZE")? {
+            config.database.pool_max_size = max_size;
+        }
+        if let Some(min_idle) = parse_env(&env, "DATABASE_POOL_MIN_IDLE")? {
+            config.database.pool_min_idle = Some(min_idle);
+        }
+        if let Some(timeout) = parse_env(&env, "DATABASE_POOL_TIMEOUT_SECS")? {
+            config.database.pool_timeout_secs = timeout;
+        }
+        if let Some(max_pending) = parse_env(&env, "DATABASE_MAX_PENDING")? {
+            config.database.max_pending = max_pending;
+        }
+        if let Some(file) = env("FASTFOOD_SCHEMA_FILE") {
+            config.schema.file = Some(file);
+        }
+        if let Some(allow_destructive) = parse_env(&env, "FASTFOOD_SCHEMA_ALLOW_DESTRUCTIVE")? {
+            config.schema.allow_destructive = allow_destructive;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Reports every problem at once rather than stopping at the first.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        if self.log_level.trim().is_empty() {
+            problems.push("log_level must not be empty".to_string());
+        }
+        if self.server.host.trim().is_empty() {
+            problems.push("server.host must not be empty".to_string());
+        }
+        if self.server.port == 0 {
+            problems.push("server.port must be between 1 and 65535".to_string());
+        }
+        if self.server.workers == Some(0) {
+            problems.push("server.workers must be at least 1".to_string());
+        }
+        if self.server.json_limit == 0 {
+            problems.push("server.json_limit must be at least 1 byte".to_string());
+        }
+        if self.server.max_page_size < 1 {
+            problems.push("server.max_page_size must be at least 1".to_string());
+        }
+        if !(1..=self.server.max_page_size.max(1)).contains(&self.server.default_page_size) {
+            problems.push(format!("server.default_page_size ({}) must be between 1 and server.max_page_size ({})",
+                                  self.server.default_page_size, self.server.max_page_size));
+        }
+        if self.database.url.trim().is_empty() {
+            problems.push("database.url must not be empty".to_string());
+        }
+        if self.database.pool_max_size == 0 {
+            problems.push("database.pool_max_size must be at least 1".to_string());
+        }
+        if let Some(min_idle) = self.database.pool_min_idle {
+            if min_idle > self.database.pool_max_size {
+                problems.push(format!("database.pool_min_idle ({}) must not exceed database.pool_max_size ({})",
+                                      min_idle, self.database.pool_max_size));
+            }
+        }
+        if self.database.pool_timeout_secs == 0 {
+            problems.push("database.pool_timeout_secs must be at least 1".to_string());
+        }
+        if self.database.max_pending == 0 {
+            problems.push("database.max_pending must be at least 1".to_string());
+        }
+
+        if self.schema.file.as_ref().is_some_and(|file| file.trim().is_empty()) {
+            problems.push("schema.file must not be empty".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+}
+
+fn parse_env<T: std::str::FromStr>(env: &impl Fn(&str) -> Option<String>, name: &str) -> Result<Option<T>, ConfigError> {
+    match env(name) {
+        Some(value) => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| ConfigError::Env(format!("{} has an invalid value {:?}", name, value))),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = Config::from_sources(None, env(&[])).unwrap();
+        assert_eq!(config.server.port, 8080);
+        assert_eq!(config.database.url, "app.sqlite");
+        assert_eq!(config.log_level, "info");
+    }
+
+    #[test]
+    fn test_env_overrides_file() {
+        let file = r#"
+            log_level = "warn"
+
+            [server]
+            port = 9000
+            workers = 2
+
+            [database]
+            url = "file.sqlite"
+            pool_max_size = 4
+        "#;
+        let config = Config::from_sources(
+            Some(("fastfood.toml".to_string(), file.to_string())),
+            env(&[("FASTFOOD_PORT", "9100"), ("RUST_LOG", "debug")]),
+        ).unwrap();
+
+        assert_eq!(config.server.port, 9100);
+        assert_eq!(config.server.workers, Some(2));
+        assert_eq!(config.database.url, "file.sqlite");
+        assert_eq!(config.database.pool().max_size, 4);
+        assert_eq!(config.log_level, "debug");
+        assert_eq!(config.schema.file, None);
+    }
+
+    #[test]
+    fn test_schema_file() {
+        let file = "[schema]\nfile = \"schema.yaml\"\n";
+        let config = Config::from_sources(
+            Some(("fastfood.toml".to_string(), file.to_string())),
+            env(&[("FASTFOOD_SCHEMA_ALLOW_DESTRUCTIVE", "true")]),
+        ).unwrap();
+
+        assert_eq!(config.schema.file.as_deref(), Some("schema.yaml"));
+        assert!(config.schema.allow_destructive);
+    }
+
+    #[test]
+    fn test_unknown_file_keys_are_rejected() {
+        let file = "[server]\nprot = 9000\n";
+        let result = Config::from_sources(Some(("fastfood.toml".to_string(), file.to_string())), env(&[]));
+        assert!(matches!(result, Err(ConfigError::Parse(_, _))));
+    }
+
+    #[test]
+    fn test_invalid_env_value() {
+        let result = Config::from_sources(None, env(&[("FASTFOOD_PORT", "eighty")]));
+        assert!(matches!(result, Err(ConfigError::Env(_))));
+    }
+
+    #[test]
+    fn test_validation_reports_every_problem() {
+        let result = Config::from_sources(None, env(&[
+            ("FASTFOOD_PORT", "0"),
+            ("DATABASE_POOL_MAX_SIZE", "2"),
+            ("DATABASE_POOL_MIN_IDLE", "5"),
+            ("FASTFOOD_DEFAULT_PAGE_SIZE", "500"),
+            ("FASTFOOD_MAX_PAGE_SIZE", "200"),
+        ]));
+
+        match result {
+            Err(ConfigError::Invalid(problems)) => assert_eq!(problems.len(), 3),
+            _ => panic!("expected validation errors"),
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use std::time::Duration;
+use diesel::connection::SimpleConnection;
+use diesel::r2d2;
+use diesel::sqlite::SqliteConnection;
+use crate::services::crud::DEFAULT_MAX_PENDING;
+
+pub type DbPool = r2d2::Pool<r2d2::ConnectionManager<SqliteConnection>>;
+
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    pub max_size: u32,
+    pub min_idle: Option<u32>,
+    /// How long a request waits for a free connection before giving up.
+    pub connection_timeout: Duration,
+    /// How many database operations may be queued or running at once
+    /// before new requests are turned away with 503.
+    pub max_pending: usize,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 10,
+            min_idle: None,
+            connection_timeout: Duration::from_secs(5),
+            max_pending: DEFAULT_MAX_PENDING,
+        }
+    }
+}
+
+/// Settings SQLite keeps per connection, applied to every pooled one.
+#[derive(Debug)]
+struct ConnectionOptions;
+
+impl r2d2::CustomizeConnection<SqliteConnection, r2d2::Error> for ConnectionOptions {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), r2d2::Error> {
+        conn.batch_execute("PRAGMA foreign_keys = ON").map_err(r2d2::Error::QueryError)
+    }
+}
+
+pub fn build_pool(database_url: &str, config: &PoolConfig) -> Result<DbPool, r2d2::PoolError> {
+    let manager = r2d2::ConnectionManager::<SqliteConnection>::new(database_url);
+
+    r2d2::Pool::builder()
+        .max_size(config.max_size)
+        .min_idle(config.min_idle)
+        .connection_timeout(config.connection_timeout)
+        .connection_customizer(Box::new(ConnectionOptions))
+        .build(manager)
+}
@@ -1,40 +1,96 @@
+mod config;
+mod db;
 mod routes;
 mod services;
 
-use actix_web::{web, App, HttpServer, Responder};
-use diesel::prelude::*;
-use diesel::sqlite::SqliteConnection;
+use actix_web::{web, App, HttpServer};
 use dotenv::dotenv;
-use std::sync::{Arc, Mutex};
-use diesel::r2d2;
-use serde::{Deserialize, Serialize};
-
-struct AppState {
-    db: Arc<Mutex<SqliteConnection>>,
-}
-
-pub type DbPool = r2d2::Pool<r2d2::ConnectionManager<SqliteConnection>>;
+use services::declarative::{ApplyOptions, SchemaDocument};
+use std::sync::Arc;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
-    std::env::set_var("RUST_LOG", "debug");
-    env_logger::init();
 
-    let manager = r2d2::ConnectionManager::<SqliteConnection>::new("app.sqlite");
-    let pool = r2d2::Pool::builder()
-        .build(manager)
-        .expect("database URL should be valid path to SQLite DB file");
+    let config = match config::Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    env_logger::Builder::new()
+        .parse_filters(&config.log_level)
+        .init();
+
+    let pool_config = config.database.pool();
+    let pool = db::build_pool(&config.database.url, &pool_config)
+        .map_err(|e| std::io::Error::other(format!("failed to open database {}: {}", config.database.url, e)))?;
+
+    let crud_service = Arc::new(services::crud::CrudService::new(pool.clone())
+        .with_max_pending(pool_config.max_pending)
+        .with_page_size(config.server.default_page_size, config.server.max_page_size));
 
-    let crud_service = Arc::new(services::crud::CrudService::new(pool.clone()));
+    if let Err(e) = crud_service.init().await {
+        return Err(std::io::Error::other(format!("failed to initialise schema registry: {}", e)));
+    }
 
-    HttpServer::new(move || {
+    if let Some(ref path) = config.schema.file {
+        let options = ApplyOptions { dry_run: false, allow_destructive: config.schema.allow_destructive };
+        let plan = match SchemaDocument::load(path) {
+            Ok(document) => crud_service.apply_schema(document, options).await,
+            Err(e) => Err(e),
+        };
+
+        match plan {
+            Ok(plan) if plan.steps.is_empty() => log::info!("Schema file {} matches the database", path),
+            Ok(plan) => {
+                for step in &plan.steps {
+                    log::info!("Applied from {}: {}", path, step);
+                }
+            }
+            Err(e) => return Err(std::io::Error::other(format!("failed to apply schema file {}: {}", path, e))),
+        }
+    }
+
+    let json_limit = config.server.json_limit;
+
+    let mut server = HttpServer::new(move || {
         App::new()
             .app_data(web::Data::from(crud_service.clone()))
+            .app_data(routes::json_config(json_limit))
+            .app_data(routes::path_config())
+            .app_data(routes::query_config())
             .service(routes::create_table)
+            .service(routes::list_tables)
+            .service(routes::describe_table)
+            .service(routes::alter_table)
+            .service(routes::drop_table)
+            .service(routes::create_index)
+            .service(routes::list_indexes)
+            .service(routes::drop_index)
+            .service(routes::table_version)
+            .service(routes::list_migrations)
+            .service(routes::rollback_migration)
+            .service(routes::apply_schema)
+            .service(routes::insert_row)
+            .service(routes::list_rows)
+            .service(routes::get_row)
+            .service(routes::update_row)
+            .service(routes::delete_row)
             .service(routes::health)
-    })
-        .bind("0.0.0.0:8080")?
+            .default_service(web::route().to(routes::not_found))
+    });
+
+    if let Some(workers) = config.server.workers {
+        server = server.workers(workers);
+    }
+
+    log::info!("Listening on {}:{}", config.server.host, config.server.port);
+
+    server
+        .bind((config.server.host.as_str(), config.server.port))?
         .run()
         .await
 }
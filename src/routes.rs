@@ -1,5 +1,74 @@
-use actix_web::{get, HttpResponse, post, Responder, web};
-use crate::services::crud::{CrudService, Error, TableSchema};
+use actix_web::{delete, get, HttpRequest, HttpResponse, HttpResponseBuilder, post, Responder, ResponseError, route, web};
+use actix_web::http::StatusCode;
+use actix_web::http::header::{ETAG, IF_MATCH, RETRY_AFTER};
+use serde::Deserialize;
+use serde_json::json;
+use crate::services::alter::AlterTable;
+use crate::services::crud::{CrudService, Error, Row, TableSchema};
+use crate::services::declarative::{ApplyOptions, SchemaDocument};
+use crate::services::indexes::IndexSchema;
+use crate::services::versioning::{IfMatch, VersionedRow};
+
+/// Seconds clients are asked to wait before retrying when the database is
+/// saturated or locked.
+const RETRY_AFTER_SECS: u64 = 1;
+
+impl ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Error::DieselError(_) | Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::PoolError(_) | Error::Busy(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::AlreadyExists(_) | Error::UniqueViolation(_) | Error::ForeignKeyViolation(_) => StatusCode::CONFLICT,
+            Error::Validation(_) => StatusCode::BAD_REQUEST,
+            Error::InvalidRow(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        if self.status_code().is_server_error() {
+            log::error!("{}", self);
+        }
+
+        let mut response = HttpResponse::build(self.status_code());
+        if let Error::PoolError(_) | Error::Busy(_) = self {
+            response.insert_header((RETRY_AFTER, RETRY_AFTER_SECS.to_string()));
+        }
+
+        let mut error = json!({
+            "code": self.code(),
+            "message": self.to_string(),
+        });
+        if let Error::InvalidRow(fields) = self {
+            error["fields"] = json!(fields);
+        }
+
+        response.json(json!({ "error": error }))
+    }
+}
+
+/// Limits JSON bodies to `limit` bytes and reports malformed ones in the
+/// same shape as every other error.
+pub fn json_config(limit: usize) -> web::JsonConfig {
+    web::JsonConfig::default()
+        .limit(limit)
+        .error_handler(|e, _| Error::Validation(e.to_string()).into())
+}
+
+pub fn path_config() -> web::PathConfig {
+    web::PathConfig::default().error_handler(|e, _| Error::Validation(e.to_string()).into())
+}
+
+pub fn query_config() -> web::QueryConfig {
+    web::QueryConfig::default().error_handler(|e, _| Error::Validation(e.to_string()).into())
+}
+
+/// Fallback for requests that match no route.
+pub async fn not_found(req: HttpRequest) -> Result<HttpResponse, Error> {
+    Err(Error::NotFound(format!("no route for {} {}", req.method(), req.path())))
+}
 
 #[get("/health")]
 async fn health() -> impl Responder {
@@ -7,11 +76,216 @@ async fn health() -> impl Responder {
 }
 
 #[post("/tables")]
-async fn create_table(schema: web::Json<TableSchema>, service: web::Data<CrudService>) -> impl Responder {
+async fn create_table(schema: web::Json<TableSchema>, service: web::Data<CrudService>) -> Result<HttpResponse, Error> {
     let table_schema = schema.into_inner();
-    match service.create_table(table_schema).await {
-        Ok(data) => HttpResponse::Ok().json(data),
-        Err(Error::DieselError(e)) => HttpResponse::InternalServerError().body(format!("Diesel error: {}", e)),
-        Err(Error::PoolError(e)) => HttpResponse::InternalServerError().body(format!("Pool error: {}", e)),
+    let data = service.create_table(table_schema).await?;
+    Ok(HttpResponse::Ok().json(data))
+}
+
+#[get("/tables")]
+async fn list_tables(service: web::Data<CrudService>) -> Result<HttpResponse, Error> {
+    let data = service.list_tables().await?;
+    Ok(HttpResponse::Ok().json(data))
+}
+
+#[get("/tables/{name}")]
+async fn describe_table(path: web::Path<String>, service: web::Data<CrudService>) -> Result<HttpResponse, Error> {
+    let table_name = path.into_inner();
+    let data = service.describe_table(&table_name).await?;
+    Ok(HttpResponse::Ok().json(data))
+}
+
+#[route("/tables/{name}", method = "PATCH")]
+async fn alter_table(path: web::Path<String>, alter: web::Json<AlterTable>, service: web::Data<CrudService>) -> Result<HttpResponse, Error> {
+    let table_name = path.into_inner();
+    let alter = alter.into_inner();
+
+    if alter.dry_run {
+        let report = service.preview_alter(&table_name, alter).await?;
+        return Ok(HttpResponse::Ok().json(report));
+    }
+
+    let data = service.alter_table(&table_name, alter).await?;
+    Ok(HttpResponse::Ok().json(data))
+}
+
+#[derive(Deserialize)]
+struct DropTableQuery {
+    confirm: Option<String>,
+}
+
+/// Dropping a table requires `?confirm=<table name>` to guard against accidents.
+#[delete("/tables/{name}")]
+async fn drop_table(path: web::Path<String>, query: web::Query<DropTableQuery>, service: web::Data<CrudService>) -> Result<HttpResponse, Error> {
+    let table_name = path.into_inner();
+    if query.confirm.as_deref() != Some(table_name.as_str()) {
+        return Err(Error::Validation(format!("confirm={} is required to drop table {}", table_name, table_name)));
+    }
+
+    service.drop_table(&table_name).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[post("/tables/{name}/indexes")]
+async fn create_index(path: web::Path<String>, index: web::Json<IndexSchema>, service: web::Data<CrudService>) -> Result<HttpResponse, Error> {
+    let table_name = path.into_inner();
+    let data = service.create_index(&table_name, index.into_inner()).await?;
+    Ok(HttpResponse::Created().json(data))
+}
+
+#[get("/tables/{name}/indexes")]
+async fn list_indexes(path: web::Path<String>, service: web::Data<CrudService>) -> Result<HttpResponse, Error> {
+    let table_name = path.into_inner();
+    let data = service.list_indexes(&table_name).await?;
+    Ok(HttpResponse::Ok().json(data))
+}
+
+#[delete("/tables/{name}/indexes/{index}")]
+async fn drop_index(path: web::Path<(String, String)>, service: web::Data<CrudService>) -> Result<HttpResponse, Error> {
+    let (table_name, index_name) = path.into_inner();
+    service.drop_index(&table_name, &index_name).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/tables/{name}/versions/{version}")]
+async fn table_version(path: web::Path<(String, i64)>, service: web::Data<CrudService>) -> Result<HttpResponse, Error> {
+    let (table_name, version) = path.into_inner();
+    let data = service.schema_at(&table_name, version).await?;
+    Ok(HttpResponse::Ok().json(data))
+}
+
+#[get("/migrations")]
+async fn list_migrations(service: web::Data<CrudService>) -> Result<HttpResponse, Error> {
+    let data = service.list_migrations().await?;
+    Ok(HttpResponse::Ok().json(data))
+}
+
+#[derive(Deserialize)]
+struct RollbackQuery {
+    #[serde(default)]
+    allow_destructive: bool,
+}
+
+/// Undoes the most recent schema change that has not been rolled back.
+/// Undoing a create or an added column needs `?allow_destructive=true`.
+/// Undoing a drop restores the table's structure only, not its rows; the
+/// response carries a `warning` saying so.
+#[post("/migrations/rollback")]
+async fn rollback_migration(query: web::Query<RollbackQuery>, service: web::Data<CrudService>) -> Result<HttpResponse, Error> {
+    let data = service.rollback_migration(query.allow_destructive).await?;
+    Ok(HttpResponse::Ok().json(data))
+}
+
+/// Brings the managed tables in line with the posted schema document, or
+/// with `?dry_run=true` only reports the plan. See `services::declarative`.
+#[post("/schema/apply")]
+async fn apply_schema(document: web::Json<SchemaDocument>, options: web::Query<ApplyOptions>, service: web::Data<CrudService>) -> Result<HttpResponse, Error> {
+    let data = service.apply_schema(document.into_inner(), options.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(data))
+}
+
+#[post("/tables/{name}/rows")]
+async fn insert_row(path: web::Path<String>, row: web::Json<Row>, service: web::Data<CrudService>) -> Result<HttpResponse, Error> {
+    let table_name = path.into_inner();
+    let data = service.insert_row(&table_name, row.into_inner()).await?;
+    Ok(versioned_row(HttpResponse::Created(), data))
+}
+
+/// Lists rows, filtered by the query string as described in `services::filter`.
+#[get("/tables/{name}/rows")]
+async fn list_rows(path: web::Path<String>, query: web::Query<Vec<(String, String)>>, service: web::Data<CrudService>) -> Result<HttpResponse, Error> {
+    let table_name = path.into_inner();
+    let data = service.list_rows(&table_name, query.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(data))
+}
+
+/// Sends a row with `response`, with its version as `ETag` when the table
+/// keeps one.
+fn versioned_row(mut response: HttpResponseBuilder, data: VersionedRow) -> HttpResponse {
+    if let Some(etag) = data.etag {
+        response.insert_header((ETAG, etag));
+    }
+    response.json(data.row)
+}
+
+/// The `If-Match` precondition of a request, if it has one.
+fn if_match(req: &HttpRequest) -> Result<Option<IfMatch>, Error> {
+    match req.headers().get(IF_MATCH) {
+        Some(header) => {
+            let header = header.to_str().map_err(|_| Error::Validation("invalid If-Match header".to_string()))?;
+            IfMatch::parse(header).map(Some).map_err(Error::Validation)
+        }
+        None => Ok(None),
+    }
+}
+
+#[get("/tables/{name}/rows/{id}")]
+async fn get_row(path: web::Path<(String, String)>, service: web::Data<CrudService>) -> Result<HttpResponse, Error> {
+    let (table_name, id) = path.into_inner();
+    let data = service.get_row(&table_name, &id).await?;
+    Ok(versioned_row(HttpResponse::Ok(), data))
+}
+
+/// Updates a row; with `If-Match`, only while it is at a version listed.
+#[route("/tables/{name}/rows/{id}", method = "PATCH", method = "PUT")]
+async fn update_row(req: HttpRequest, path: web::Path<(String, String)>, row: web::Json<Row>, service: web::Data<CrudService>) -> Result<HttpResponse, Error> {
+    let (table_name, id) = path.into_inner();
+    let data = service.update_row(&table_name, &id, row.into_inner(), if_match(&req)?).await?;
+    Ok(versioned_row(HttpResponse::Ok(), data))
+}
+
+/// Deletes a row; with `If-Match`, only while it is at a version listed.
+#[delete("/tables/{name}/rows/{id}")]
+async fn delete_row(req: HttpRequest, path: web::Path<(String, String)>, service: web::Data<CrudService>) -> Result<HttpResponse, Error> {
+    let (table_name, id) = path.into_inner();
+    service.delete_row(&table_name, &id, if_match(&req)?).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::to_bytes;
+    use crate::services::validation::FieldError;
+
+    #[actix_web::test]
+    async fn test_error_response_body() {
+        let response = Error::NotFound("table people not found".to_string()).error_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let body = to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, json!({"error": {"code": "not_found", "message": "table people not found"}}));
+    }
+
+    #[actix_web::test]
+    async fn test_invalid_row_lists_fields() {
+        let response = Error::InvalidRow(vec![
+            FieldError { field: "age".to_string(), message: "column age expects a INTEGER value, got \"old\"".to_string() },
+            FieldError { field: "name".to_string(), message: "column name is required".to_string() },
+        ]).error_response();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let body = to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["code"], json!("invalid_row"));
+        assert_eq!(body["error"]["fields"][1], json!({"field": "name", "message": "column name is required"}));
+    }
+
+    #[test]
+    fn test_error_status_codes() {
+        assert_eq!(Error::Validation(String::new()).status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(Error::AlreadyExists(String::new()).status_code(), StatusCode::CONFLICT);
+        assert_eq!(Error::UniqueViolation(String::new()).status_code(), StatusCode::CONFLICT);
+        assert_eq!(Error::ForeignKeyViolation(String::new()).status_code(), StatusCode::CONFLICT);
+        assert_eq!(Error::Busy(String::new()).status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(Error::PreconditionFailed(String::new()).status_code(), StatusCode::PRECONDITION_FAILED);
+    }
+
+    #[test]
+    fn test_busy_sets_retry_after() {
+        let response = Error::Busy("database is locked".to_string()).error_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get(RETRY_AFTER).unwrap(), "1");
     }
 }
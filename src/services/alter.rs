@@ -0,0 +1,382 @@
+//! Operations accepted by `PATCH /tables/{name}`, applied in order to the
+//! stored schema and translated to `ALTER TABLE` statements.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::services::crud::{ColumnSchema, Error, TableSchema};
+use crate::services::defaults::ColumnDefault;
+use crate::services::identifier::Identifier;
+use crate::services::indexes::IndexSchema;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlterTable {
+    pub operations: Vec<AlterOperation>,
+    /// Report what the operations would do, including rows that would fail
+    /// conversion, without changing anything.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(clippy::enum_variant_names)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+pub enum AlterOperation {
+    AddColumn { column: ColumnSchema },
+    RenameColumn { from: String, to: Identifier },
+    DropColumn { name: String },
+    /// Replaces the definition of column `name`, possibly renaming it. This
+    /// needs the table to be rebuilt.
+    ChangeColumn { name: String, column: ColumnSchema },
+    CreateIndex { index: IndexSchema },
+    DropIndex { name: String },
+}
+
+/// The outcome of applying operations to a stored schema.
+#[derive(Debug, Clone)]
+pub struct AlterPlan {
+    /// The resulting user-defined columns.
+    pub schema: TableSchema,
+    /// For each resulting column, the column of the original table its
+    /// values come from; `None` for added columns.
+    pub sources: Vec<Option<ColumnSchema>>,
+    /// `ALTER TABLE` statements performing the operations in place, used
+    /// unless `rebuild` is set.
+    pub statements: Vec<String>,
+    pub rebuild: bool,
+}
+
+impl AlterPlan {
+    pub fn new(schema: TableSchema) -> Self {
+        Self {
+            sources: schema.columns.iter().cloned().map(Some).collect(),
+            schema,
+            statements: Vec::new(),
+            rebuild: false,
+        }
+    }
+
+    /// Original and resulting definitions of the columns whose stored
+    /// values must be converted or checked when the table is rebuilt.
+    pub fn conversions(&self) -> Vec<(&ColumnSchema, &ColumnSchema)> {
+        self.sources
+            .iter()
+            .zip(&self.schema.columns)
+            .filter_map(|(source, column)| source.as_ref().map(|source| (source, column)))
+            .filter(|(source, column)| {
+                source.data_type != column.data_type
+                    || (column.not_null.unwrap_or(false) && !source.not_null.unwrap_or(false))
+                    || (column.unique.unwrap_or(false) && !source.unique.unwrap_or(false))
+                    || column.rules != source.rules
+            })
+            .collect()
+    }
+
+    /// For each column of `previous`, the resulting column its values can
+    /// be restored from when undoing the plan.
+    pub fn reverse_sources(&self, previous: &TableSchema) -> Vec<Option<ColumnSchema>> {
+        previous.columns
+            .iter()
+            .map(|original| {
+                self.sources
+                    .iter()
+                    .position(|source| source.as_ref().is_some_and(|source| source.name == original.name))
+                    .map(|index| self.schema.columns[index].clone())
+            })
+            .collect()
+    }
+
+    /// The plan rolling `current` back to `previous`, filling each column
+    /// of `previous` from the column of `current` named at its position in
+    /// `sources`. Without `sources` columns are paired by name.
+    pub fn reverting(current: &TableSchema, previous: TableSchema, sources: Option<&[Option<Identifier>]>) -> Self {
+        let restored_from = |index: usize, column: &ColumnSchema| -> Option<ColumnSchema> {
+            let name = match sources {
+                Some(sources) => sources.get(index)?.as_ref()?,
+                None => &column.name,
+            };
+            current.column(name.as_str()).cloned()
+        };
+
+        Self {
+            sources: previous.columns.iter().enumerate().map(|(index, column)| restored_from(index, column)).collect(),
+            schema: previous,
+            statements: Vec::new(),
+            rebuild: true,
+        }
+    }
+}
+
+impl AlterTable {
+    /// Applies every operation in order to `schema`, which holds the
+    /// user-defined columns only. `managed` lists the columns fastfood
+    /// maintains, which cannot be altered but can be indexed.
+    pub fn plan(&self, schema: TableSchema, managed: &[&ColumnSchema]) -> Result<AlterPlan, Error> {
+        if self.operations.is_empty() {
+            return Err(Error::Validation("at least one operation is required".to_string()));
+        }
+
+        let mut plan = AlterPlan::new(schema);
+        for operation in &self.operations {
+            operation.apply(&mut plan, managed)?;
+        }
+
+        Ok(plan)
+    }
+}
+
+impl AlterOperation {
+    fn apply(&self, plan: &mut AlterPlan, managed: &[&ColumnSchema]) -> Result<(), Error> {
+        let schema = &mut plan.schema;
+
+        let sql = match self {
+            AlterOperation::AddColumn { column } => {
+                column.validate()?;
+                check_addable(column)?;
+
+                schema.columns.push(column.clone());
+                plan.sources.push(None);
+                format!("ALTER TABLE {} ADD COLUMN {}", schema.name, column)
+            }
+            AlterOperation::RenameColumn { from, to } => {
+                let index = find_column(schema, managed, from)?;
+                let from = std::mem::replace(&mut schema.columns[index].name, to.clone());
+                for index in schema.indexes.iter_mut() {
+                    index.rename_column(from.as_str(), to);
+                }
+                schema.rename_in_constraints(from.as_str(), to);
+
+                format!("ALTER TABLE {} RENAME COLUMN {} TO {}", schema.name, from, to)
+            }
+            AlterOperation::DropColumn { name } => {
+                let index = find_column(schema, managed, name)?;
+                let column = &schema.columns[index];
+
+                if column.primary_key.unwrap_or(false) || column.unique.unwrap_or(false) || schema.constrains(name) {
+                    return Err(Error::Validation(format!("column {} is a key and cannot be dropped", name)));
+                }
+
+                let column = schema.columns.remove(index);
+                plan.sources.remove(index);
+                format!("ALTER TABLE {} DROP COLUMN {}", schema.name, column.name)
+            }
+            AlterOperation::ChangeColumn { name, column } => {
+                let index = find_column(schema, managed, name)?;
+                column.validate()?;
+
+                if column.primary_key.unwrap_or(false) || column.auto_increment.unwrap_or(false) {
+                    return Err(Error::Validation(format!("column {} cannot become a primary key or auto_increment column", name)));
+                }
+
+                schema.columns[index] = column.clone();
+                for index in schema.indexes.iter_mut() {
+                    index.rename_column(name, &column.name);
+                }
+                schema.rename_in_constraints(name, &column.name);
+
+                plan.rebuild = true;
+                schema.validate()?;
+                check_indexes(schema, managed)?;
+                return Ok(());
+            }
+            AlterOperation::CreateIndex { index } => {
+                schema.indexes.push(index.clone());
+                index.sql(&with_managed(schema, managed))?
+            }
+            AlterOperation::DropIndex { name } => {
+                let position = schema
+                    .indexes
+                    .iter()
+                    .position(|index| index.name.as_str().eq_ignore_ascii_case(name))
+                    .ok_or_else(|| Error::NotFound(format!("table {} has no index {}", schema.name.as_str(), name)))?;
+
+                let index = schema.indexes.remove(position);
+                format!("DROP INDEX {}", index.name)
+            }
+        };
+
+        schema.validate()?;
+        check_indexes(schema, managed)?;
+        plan.statements.push(sql);
+        Ok(())
+    }
+}
+
+/// `schema` with the managed columns, which indexes may use, added.
+fn with_managed(schema: &TableSchema, managed: &[&ColumnSchema]) -> TableSchema {
+    let mut columns: Vec<ColumnSchema> = managed.iter().map(|column| (*column).clone()).collect();
+    columns.extend(schema.columns.iter().cloned());
+
+    TableSchema {
+        name: schema.name.clone(),
+        columns,
+        indexes: Vec::new(),
+        primary_key: None,
+        unique: Vec::new(),
+        options: schema.options.clone(),
+    }
+}
+
+/// Checks that every index still fits the table once a column has been
+/// renamed, dropped or changed.
+fn check_indexes(schema: &TableSchema, managed: &[&ColumnSchema]) -> Result<(), Error> {
+    let table = with_managed(schema, managed);
+
+    for index in &schema.indexes {
+        index.sql(&table)?;
+    }
+
+    Ok(())
+}
+
+/// SQLite only adds columns that existing rows can take without a rebuild.
+fn check_addable(column: &ColumnSchema) -> Result<(), Error> {
+    let name = column.name.as_str();
+
+    if column.primary_key.unwrap_or(false) || column.unique.unwrap_or(false) || column.auto_increment.unwrap_or(false) {
+        return Err(Error::Validation(format!("column {} cannot be added as a primary key, unique or auto_increment column", name)));
+    }
+
+    match column.default {
+        Some(ColumnDefault::Expression { .. }) => {
+            Err(Error::Validation(format!("column {} cannot be added with an expression default", name)))
+        }
+        Some(ColumnDefault::Literal(Value::Null)) | None if column.not_null.unwrap_or(false) => {
+            Err(Error::Validation(format!("not_null column {} needs a default to be added", name)))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn find_column(schema: &TableSchema, managed: &[&ColumnSchema], name: &str) -> Result<usize, Error> {
+    if managed.iter().any(|managed| managed.name.as_str().eq_ignore_ascii_case(name)) {
+        return Err(Error::Forbidden(format!("column {} is managed by fastfood and cannot be altered", name)));
+    }
+
+    schema
+        .columns
+        .iter()
+        .position(|column| column.name == name)
+        .ok_or_else(|| Error::Validation(format!("table {} has no column {}", schema.name.as_str(), name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schema() -> TableSchema {
+        serde_json::from_value(json!({
+            "name": "people",
+            "columns": [
+                {"name": "name", "type": "text", "unique": true},
+                {"name": "age", "type": "integer"},
+            ]
+        })).unwrap()
+    }
+
+    fn apply(operation: serde_json::Value) -> Result<(String, TableSchema), Error> {
+        let plan = plan(operation)?;
+        assert!(!plan.rebuild);
+        Ok((plan.statements.join("; "), plan.schema))
+    }
+
+    fn id() -> ColumnSchema {
+        let mut id: ColumnSchema = serde_json::from_value(json!({"name": "x", "type": "integer", "primary_key": true})).unwrap();
+        id.name = Identifier::managed("id");
+        id
+    }
+
+    fn plan(operation: serde_json::Value) -> Result<AlterPlan, Error> {
+        let alter: AlterTable = serde_json::from_value(json!({"operations": [operation]})).unwrap();
+        alter.plan(schema(), &[&id()])
+    }
+
+    #[test]
+    fn test_operations() {
+        let (sql, schema) = apply(json!({"op": "add_column", "column": {"name": "email", "type": "text", "default": ""}})).ok().unwrap();
+        assert_eq!(sql, "ALTER TABLE \"people\" ADD COLUMN \"email\" TEXT DEFAULT ''");
+        assert_eq!(schema.columns.len(), 3);
+
+        let (sql, schema) = apply(json!({"op": "rename_column", "from": "age", "to": "years"})).ok().unwrap();
+        assert_eq!(sql, "ALTER TABLE \"people\" RENAME COLUMN \"age\" TO \"years\"");
+        assert!(schema.column("years").is_some());
+
+        let (sql, schema) = apply(json!({"op": "drop_column", "name": "age"})).ok().unwrap();
+        assert_eq!(sql, "ALTER TABLE \"people\" DROP COLUMN \"age\"");
+        assert_eq!(schema.columns.len(), 1);
+    }
+
+    #[test]
+    fn test_change_column_needs_rebuild() {
+        let alter: AlterTable = serde_json::from_value(json!({"operations": [
+            {"op": "rename_column", "from": "age", "to": "years"},
+            {"op": "change_column", "name": "years", "column": {"name": "years", "type": "text", "not_null": true}},
+            {"op": "add_column", "column": {"name": "email", "type": "text"}},
+        ]})).unwrap();
+        let plan = alter.plan(schema(), &[]).ok().unwrap();
+        assert!(plan.rebuild);
+
+        let sources: Vec<Option<&str>> = plan.sources.iter().map(|source| source.as_ref().map(|s| s.name.as_str())).collect();
+        assert_eq!(sources, vec![Some("name"), Some("age"), None]);
+
+        let conversions = plan.conversions();
+        assert_eq!(conversions.len(), 1);
+        assert_eq!(conversions[0].0.name.as_str(), "age");
+        assert_eq!(conversions[0].1.name.as_str(), "years");
+    }
+
+    #[test]
+    fn test_index_operations() {
+        let (sql, people) = apply(json!({"op": "create_index", "index": {"name": "people_age", "columns": ["age", "id"]}})).ok().unwrap();
+        assert_eq!(sql, "CREATE INDEX \"people_age\" ON \"people\" (\"age\", \"id\")");
+        assert_eq!(people.indexes.len(), 1);
+
+        let alter: AlterTable = serde_json::from_value(json!({"operations": [
+            {"op": "create_index", "index": {"name": "people_age", "columns": ["age"]}},
+            {"op": "rename_column", "from": "age", "to": "years"},
+            {"op": "drop_index", "name": "PEOPLE_AGE"},
+            {"op": "create_index", "index": {"name": "people_years", "columns": ["years"], "where": "years=gt.17"}},
+        ]})).unwrap();
+        let plan = alter.plan(schema(), &[&id()]).ok().unwrap();
+        assert_eq!(plan.statements[2], "DROP INDEX \"people_age\"");
+        assert_eq!(plan.schema.indexes[0].name.as_str(), "people_years");
+
+        // an index keeps a column from being dropped, and filters are not renamed
+        let alter: AlterTable = serde_json::from_value(json!({"operations": [
+            {"op": "create_index", "index": {"name": "people_age", "columns": ["name"], "where": "age=gt.17"}},
+            {"op": "rename_column", "from": "age", "to": "years"},
+        ]})).unwrap();
+        assert!(matches!(alter.plan(schema(), &[&id()]), Err(Error::Validation(_))));
+        let alter: AlterTable = serde_json::from_value(json!({"operations": [
+            {"op": "create_index", "index": {"name": "people_age", "columns": ["age"]}},
+            {"op": "drop_column", "name": "age"},
+        ]})).unwrap();
+        assert!(matches!(alter.plan(schema(), &[&id()]), Err(Error::Validation(_))));
+    }
+
+    #[test]
+    fn test_rejected_operations() {
+        assert!(matches!(apply(json!({"op": "drop_column", "name": "id"})), Err(Error::Forbidden(_))));
+        assert!(matches!(apply(json!({"op": "rename_column", "from": "ID", "to": "key"})), Err(Error::Forbidden(_))));
+        assert!(matches!(apply(json!({"op": "drop_column", "name": "email"})), Err(Error::Validation(_))));
+        assert!(matches!(apply(json!({"op": "drop_column", "name": "name"})), Err(Error::Validation(_))));
+        assert!(matches!(apply(json!({"op": "rename_column", "from": "age", "to": "name"})), Err(Error::Validation(_))));
+        assert!(matches!(apply(json!({"op": "add_column", "column": {"name": "x", "type": "text", "not_null": true}})),
+                         Err(Error::Validation(_))));
+        assert!(matches!(apply(json!({"op": "add_column", "column": {"name": "x", "type": "timestamp", "default": {"expr": "now"}}})),
+                         Err(Error::Validation(_))));
+
+        assert!(matches!(plan(json!({"op": "change_column", "name": "id", "column": {"name": "x", "type": "text"}})),
+                         Err(Error::Forbidden(_))));
+        assert!(matches!(plan(json!({"op": "change_column", "name": "age", "column": {"name": "age", "type": "integer", "primary_key": true}})),
+                         Err(Error::Validation(_))));
+
+        assert!(matches!(apply(json!({"op": "drop_index", "name": "people_age"})), Err(Error::NotFound(_))));
+        assert!(matches!(apply(json!({"op": "create_index", "index": {"name": "people_age", "columns": ["years"]}})),
+                         Err(Error::Validation(_))));
+
+        let managed: Result<AlterOperation, _> = serde_json::from_value(json!({"op": "rename_column", "from": "age", "to": "created_at"}));
+        assert!(managed.is_err());
+    }
+}
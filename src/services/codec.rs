@@ -0,0 +1,116 @@
+//! Converts row values between JSON and the way SQLite stores them, driven
+//! by each column's `DataType`: booleans are stored as 0/1, timestamps as
+//! `YYYY-MM-DD HH:MM:SS` text in UTC, and floats as REAL.
+
+use serde_json::{Number, Value};
+use time::format_description::well_known::Rfc3339;
+use crate::services::crud::{ColumnSchema, DataType, Error, Row, TableSchema};
+use crate::services::value::{parse_timestamp, sqlite_timestamp, SqlValue};
+
+/// Turns a row read from SQLite into its JSON form. Values that do not fit
+/// their column's type, which SQLite allows, are passed through unchanged.
+pub fn decode_row(schema: &TableSchema, mut row: Row) -> Row {
+    for (name, value) in row.iter_mut() {
+        if let Some(column) = schema.column(name) {
+            *value = decode_value(&column.data_type, value.take());
+        }
+    }
+
+    row
+}
+
+pub fn decode_value(data_type: &DataType, value: Value) -> Value {
+    match (data_type, value) {
+        (DataType::Boolean, Value::Number(n)) if n.as_i64() == Some(0) => Value::Bool(false),
+        (DataType::Boolean, Value::Number(n)) if n.as_i64() == Some(1) => Value::Bool(true),
+        (DataType::TimeStamp, Value::String(s)) => match parse_timestamp(&s).and_then(|t| t.format(&Rfc3339).ok()) {
+            Some(timestamp) => Value::String(timestamp),
+            None => Value::String(s),
+        },
+        (DataType::Float, Value::Number(n)) => match n.as_f64().and_then(Number::from_f64) {
+            Some(n) => Value::Number(n),
+            None => Value::Number(n),
+        },
+        (_, value) => value,
+    }
+}
+
+/// Turns a JSON value from a request body into the value stored for
+/// `column`, rejecting values of the wrong type.
+pub fn encode_value(column: &ColumnSchema, value: &Value) -> Result<SqlValue, Error> {
+    let encoded = match (&column.data_type, value) {
+        (_, Value::Null) => Some(SqlValue::Null),
+        (DataType::Text, Value::String(_)) => SqlValue::from_json(value),
+        (DataType::Integer, Value::Number(n)) if n.is_i64() => SqlValue::from_json(value),
+        (DataType::Float, Value::Number(n)) => n.as_f64().map(SqlValue::Float),
+        (DataType::Boolean, Value::Bool(_)) => SqlValue::from_json(value),
+        (DataType::TimeStamp, Value::String(s)) => parse_timestamp(s).map(|t| SqlValue::Text(sqlite_timestamp(t))),
+        _ => None,
+    };
+
+    encoded.ok_or_else(|| Error::Validation(format!("column {} expects a {} value, got {}",
+                                                    column.name.as_str(), column.data_type, value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schema() -> TableSchema {
+        serde_json::from_value(json!({
+            "name": "events",
+            "columns": [
+                {"name": "title", "type": "text"},
+                {"name": "seats", "type": "integer"},
+                {"name": "price", "type": "float"},
+                {"name": "public", "type": "boolean"},
+                {"name": "starts", "type": "timestamp"},
+            ]
+        })).unwrap()
+    }
+
+    #[test]
+    fn test_decode_row() {
+        let row: Row = serde_json::from_value(json!({
+            "title": "launch",
+            "seats": 10,
+            "price": 12,
+            "public": 1,
+            "starts": "2024-05-01 18:30:00",
+        })).unwrap();
+
+        assert_eq!(Value::Object(decode_row(&schema(), row)), json!({
+            "title": "launch",
+            "seats": 10,
+            "price": 12.0,
+            "public": true,
+            "starts": "2024-05-01T18:30:00Z",
+        }));
+    }
+
+    #[test]
+    fn test_decode_passes_through_unexpected_values() {
+        assert_eq!(decode_value(&DataType::Boolean, json!(2)), json!(2));
+        assert_eq!(decode_value(&DataType::TimeStamp, json!("soon")), json!("soon"));
+        assert_eq!(decode_value(&DataType::Boolean, json!(null)), json!(null));
+    }
+
+    #[test]
+    fn test_encode_value() {
+        let schema = schema();
+        let encode = |name: &str, value: Value| encode_value(schema.column(name).unwrap(), &value);
+
+        assert_eq!(encode("public", json!(true)).ok(), Some(SqlValue::Integer(1)));
+        assert_eq!(encode("price", json!(12)).ok(), Some(SqlValue::Float(12.0)));
+        assert_eq!(encode("starts", json!("2024-05-01T20:30:00+02:00")).ok(),
+                   Some(SqlValue::Text("2024-05-01 18:30:00".to_string())));
+        assert_eq!(encode("seats", json!(null)).ok(), Some(SqlValue::Null));
+
+        assert!(matches!(encode("public", json!(1)), Err(Error::Validation(_))));
+        assert!(matches!(encode("seats", json!(1.5)), Err(Error::Validation(_))));
+        assert!(matches!(encode("title", json!(3)), Err(Error::Validation(_))));
+        assert!(matches!(encode("starts", json!("tomorrow")), Err(Error::Validation(_))));
+        assert!(matches!(encode("title", json!(["a"])), Err(Error::Validation(_))));
+    }
+}
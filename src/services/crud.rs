@@ -1,9 +1,30 @@
-use diesel::{Connection, r2d2, RunQueryDsl};
+use std::sync::Arc;
+use actix_web::web;
+use diesel::{Connection, OptionalExtension, QueryableByName, r2d2, RunQueryDsl, SqliteConnection};
+use diesel::connection::SimpleConnection;
+use diesel::r2d2::{ConnectionManager, PooledConnection};
+use diesel::sql_types::{BigInt, Text};
 use serde::{Deserialize, Serialize};
-use log::log;
-use crate::DbPool;
+use serde_json::Value;
+use tokio::sync::Semaphore;
+use crate::db::DbPool;
+use crate::services::{codec, embed, filter, introspect, migrations, rebuild, registry, sequences};
+use crate::services::migrations::{Change, Migration, MigrationKind, Rollback};
+use crate::services::rebuild::ConversionFailure;
+use crate::services::alter::{AlterOperation, AlterPlan, AlterTable};
+use crate::services::declarative::{self, ApplyOptions, PlanStep, SchemaDocument, SchemaPlan};
+use crate::services::defaults::ColumnDefault;
+use crate::services::identifier::{Identifier, InvalidIdentifier};
+use crate::services::indexes::IndexSchema;
+use crate::services::listing::{CountMode, ESTIMATE_SCAN_LIMIT, ListQuery};
+use crate::services::options::{self, TableOptions, ID_COLUMN};
+use crate::services::references::References;
+use crate::services::rules::ColumnRules;
+use crate::services::validation::{self, FieldError, Write};
+use crate::services::value::{query_with_binds, quote_literal, SqlValue};
+use crate::services::versioning::{self, IfMatch, VersionedRow};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub enum DataType {
     #[serde(rename = "text")]
     Text,
@@ -29,27 +50,226 @@ impl std::fmt::Display for DataType {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl DataType {
+    /// Maps a declared SQLite column type back to a `DataType`, following
+    /// SQLite's type affinity rules for anything fastfood did not create.
+    pub fn from_declared(declared: &str) -> DataType {
+        let declared = declared.to_uppercase();
+
+        if declared.contains("BOOL") {
+            DataType::Boolean
+        } else if declared.contains("TIME") || declared.contains("DATE") {
+            DataType::TimeStamp
+        } else if declared.contains("INT") {
+            DataType::Integer
+        } else if declared.contains("REAL") || declared.contains("FLOA") || declared.contains("DOUB") {
+            DataType::Float
+        } else {
+            DataType::Text
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TableSchema {
-    pub name: String,
+    pub name: Identifier,
     pub columns: Vec<ColumnSchema>,
+    #[serde(default)]
+    pub indexes: Vec<IndexSchema>,
+    /// A natural key, possibly spanning several columns, made the table's
+    /// primary key in place of `id`, which stays as a unique row handle.
+    #[serde(default)]
+    pub primary_key: Option<Vec<Identifier>>,
+    /// Sets of columns whose values must be unique taken together.
+    #[serde(default)]
+    pub unique: Vec<Vec<Identifier>>,
+    /// `None` for tables fastfood did not create, whose columns it does not
+    /// manage; managed tables always have options, defaulted if not given.
+    #[serde(default = "options::default_options", deserialize_with = "options::deserialize_options",
+            skip_serializing_if = "Option::is_none")]
+    pub options: Option<TableOptions>,
+}
+
+impl TableSchema {
+    /// The table's options, the defaults for tables fastfood did not create.
+    pub fn options(&self) -> &TableOptions {
+        self.options.as_ref().unwrap_or(&options::DEFAULT_OPTIONS)
+    }
+
+    /// Checks what deserializing the identifiers cannot: column and index
+    /// names must be unique, compared the way SQLite does (case-insensitively).
+    pub fn validate(&self) -> Result<(), Error> {
+        self.options()
+            .check()
+            .map_err(|e| Error::Validation(format!("invalid options for table {}: {}", self.name.as_str(), e)))?;
+
+        let mut seen: std::collections::HashSet<String> = self.options()
+            .managed_columns(false)
+            .iter()
+            .map(|column| column.name.as_str().to_lowercase())
+            .collect();
+
+        for column in &self.columns {
+            column.validate()?;
+
+            if filter::is_reserved_param(column.name.as_str()) {
+                return Err(Error::Validation(format!("column {} of table {} is named like a listing parameter",
+                                                     column.name.as_str(), self.name.as_str())));
+            }
+
+            if !seen.insert(column.name.as_str().to_lowercase()) {
+                return Err(Error::Validation(format!("duplicate or managed column {} in table {}",
+                                                     column.name.as_str(), self.name.as_str())));
+            }
+        }
+
+        let mut seen = std::collections::HashSet::new();
+
+        for index in &self.indexes {
+            if !seen.insert(index.name.as_str().to_lowercase()) {
+                return Err(Error::Validation(format!("duplicate index {} in table {}",
+                                                     index.name.as_str(), self.name.as_str())));
+            }
+        }
+
+        if let Some(ref primary_key) = self.primary_key {
+            self.check_constraint("primary_key", primary_key)?;
+
+            for name in primary_key {
+                let column = self.constrained_column(name)?;
+                // SQLite lets NULL into the primary key of most tables
+                if !column.not_null.unwrap_or(false) {
+                    return Err(Error::Validation(format!("primary key column {} must be not_null", name.as_str())));
+                }
+            }
+            if let Some(column) = self.columns.iter().find(|column| column.primary_key.unwrap_or(false)) {
+                return Err(Error::Validation(format!("column {} cannot be a primary key as well as the table's primary_key",
+                                                     column.name.as_str())));
+            }
+        }
+
+        for unique in &self.unique {
+            self.check_constraint("unique", unique)?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks that a table constraint names distinct columns of the table.
+    fn check_constraint(&self, kind: &str, columns: &[Identifier]) -> Result<(), Error> {
+        if columns.is_empty() {
+            return Err(Error::Validation(format!("{} of table {} needs at least one column", kind, self.name.as_str())));
+        }
+
+        let mut seen = std::collections::HashSet::new();
+
+        for name in columns {
+            self.constrained_column(name)?;
+
+            if !seen.insert(name.as_str().to_lowercase()) {
+                return Err(Error::Validation(format!("{} of table {} lists column {} twice", kind, self.name.as_str(), name.as_str())));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn constrained_column(&self, name: &Identifier) -> Result<&ColumnSchema, Error> {
+        self.columns
+            .iter()
+            .find(|column| column.name.as_str().eq_ignore_ascii_case(name.as_str()))
+            .ok_or_else(|| Error::Validation(format!("table {} has no column {}", self.name.as_str(), name.as_str())))
+    }
+
+    /// The columns fastfood maintains in this table, `id` first.
+    pub fn managed_columns(&self) -> Vec<ColumnSchema> {
+        self.options().managed_columns(self.primary_key.is_some())
+    }
+
+    /// Whether a table constraint includes column `name`.
+    pub fn constrains(&self, name: &str) -> bool {
+        self.primary_key
+            .iter()
+            .chain(&self.unique)
+            .flatten()
+            .any(|column| column.as_str().eq_ignore_ascii_case(name))
+    }
+
+    /// Follows a column rename in the table constraints, as SQLite does.
+    pub fn rename_in_constraints(&mut self, from: &str, to: &Identifier) {
+        for column in self.primary_key.iter_mut().chain(self.unique.iter_mut()).flatten() {
+            if column.as_str().eq_ignore_ascii_case(from) {
+                *column = to.clone();
+            }
+        }
+    }
+
+    /// The `PRIMARY KEY` and `UNIQUE` table constraints.
+    pub fn constraints_sql(&self) -> Vec<String> {
+        let list = |columns: &[Identifier]| columns.iter().map(Identifier::to_string).collect::<Vec<_>>().join(", ");
+
+        self.primary_key
+            .iter()
+            .map(|columns| format!("PRIMARY KEY ({})", list(columns)))
+            .chain(self.unique.iter().map(|columns| format!("UNIQUE ({})", list(columns))))
+            .collect()
+    }
+
+    pub fn column(&self, name: &str) -> Option<&ColumnSchema> {
+        self.columns.iter().find(|column| column.name == name)
+    }
+}
+
+/// A table as reported by introspection. Tables found in the database that
+/// were not created through fastfood are flagged as `unmanaged`.
+#[derive(Debug, Serialize)]
+pub struct TableInfo {
+    #[serde(flatten)]
+    pub schema: TableSchema,
+    pub unmanaged: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ColumnSchema {
-    pub name: String,
+    pub name: Identifier,
     #[serde(rename = "type")]
     pub data_type: DataType,
     pub primary_key: Option<bool>,
     pub auto_increment: Option<bool>,
     pub unique: Option<bool>,
     pub not_null: Option<bool>,
-    pub default: Option<String>,
+    pub default: Option<ColumnDefault>,
+    /// Makes the column a foreign key to another table.
+    pub references: Option<References>,
+    #[serde(flatten)]
+    pub rules: ColumnRules,
+}
+
+impl ColumnSchema {
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(ref default) = self.default {
+            default
+                .check(&self.data_type, self.not_null.unwrap_or(false))
+                .map_err(|e| Error::Validation(format!("invalid default for column {}: {}", self.name.as_str(), e)))?;
+        }
+
+        if let Some(ref references) = self.references {
+            references
+                .check(self)
+                .map_err(|e| Error::Validation(format!("invalid references for column {}: {}", self.name.as_str(), e)))?;
+        }
+
+        self.rules
+            .check(self)
+            .map_err(|e| Error::Validation(format!("invalid rules for column {}: {}", self.name.as_str(), e)))?;
+
+        Ok(())
+    }
 }
 
 impl std::fmt::Display for ColumnSchema {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{} {}{}{}{}{}{}",
+        write!(f, "{} {}{}{}{}{}{}{}{}",
                self.name,
                self.data_type.to_string().to_uppercase(),
                if self.primary_key.unwrap_or(false) { " PRIMARY KEY" } else { "" },
@@ -57,7 +277,17 @@ impl std::fmt::Display for ColumnSchema {
                if self.unique.unwrap_or(false) { " UNIQUE" } else { "" },
                if self.not_null.unwrap_or(false) { " NOT NULL" } else { "" },
                if let Some(ref default) = self.default {
-                   format!(" DEFAULT {}", default)
+                   format!(" DEFAULT {}", default.sql(&self.data_type))
+               } else {
+                   "".to_string()
+               },
+               if let Some(check) = self.rules.sql(self, &self.name.to_string()) {
+                   format!(" CHECK ({})", check)
+               } else {
+                   "".to_string()
+               },
+               if let Some(ref references) = self.references {
+                   format!(" {}", references)
                } else {
                    "".to_string()
                }
@@ -65,177 +295,2058 @@ impl std::fmt::Display for ColumnSchema {
     }
 }
 
+/// Default cap on database operations queued or running at once.
+pub const DEFAULT_MAX_PENDING: usize = 64;
+
+#[derive(Clone)]
 pub struct CrudService {
     pool: DbPool,
-    id_col: ColumnSchema,
-    created_at_col: ColumnSchema,
-    updated_at_col: ColumnSchema,
+    pending: Arc<Semaphore>,
+    max_pending: usize,
+    default_page_size: i64,
+    max_page_size: i64,
+}
+
+/// Default number of rows per page when `?limit=` is not given.
+pub const DEFAULT_PAGE_SIZE: i64 = 100;
+/// Default cap on `?limit=`.
+pub const MAX_PAGE_SIZE: i64 = 1000;
+
+/// A row of a dynamic table, keyed by column name.
+pub type Row = serde_json::Map<String, Value>;
+
+/// What an alteration would do, as reported by a dry run.
+#[derive(Debug, Serialize)]
+pub struct AlterReport {
+    pub schema: TableSchema,
+    /// Whether the table has to be rebuilt rather than altered in place.
+    pub rebuild: bool,
+    /// The `ALTER TABLE` statements run when no rebuild is needed.
+    pub statements: Vec<String>,
+    pub failures: Vec<ConversionFailure>,
+}
+
+/// One page of a row listing. `next_cursor` is `None` on the last page.
+#[derive(Debug, Serialize)]
+pub struct RowPage {
+    pub rows: Vec<Row>,
+    pub next_cursor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_count: Option<i64>,
 }
 
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug)]
 pub enum Error {
     DieselError(diesel::result::Error),
-    PoolError(r2d2::Error),
+    PoolError(r2d2::PoolError),
+    NotFound(String),
+    AlreadyExists(String),
+    Validation(String),
+    /// A row payload failed validation; every offending field is listed.
+    InvalidRow(Vec<FieldError>),
+    Forbidden(String),
+    UniqueViolation(String),
+    ForeignKeyViolation(String),
+    Busy(String),
+    /// An `If-Match` precondition failed: the row changed since the client
+    /// read it.
+    PreconditionFailed(String),
+    Internal(String),
+}
+
+impl Error {
+    /// Machine-readable code reported to clients alongside the message.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::DieselError(_) | Error::Internal(_) => "internal_error",
+            Error::PoolError(_) => "pool_unavailable",
+            Error::NotFound(_) => "not_found",
+            Error::AlreadyExists(_) => "already_exists",
+            Error::Validation(_) => "validation_failed",
+            Error::InvalidRow(_) => "invalid_row",
+            Error::Forbidden(_) => "forbidden",
+            Error::UniqueViolation(_) => "unique_violation",
+            Error::ForeignKeyViolation(_) => "foreign_key_violation",
+            Error::Busy(_) => "busy",
+            Error::PreconditionFailed(_) => "precondition_failed",
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::DieselError(e) => write!(f, "Diesel error: {}", e),
+            Error::PoolError(e) => write!(f, "Pool error: {}", e),
+            Error::InvalidRow(errors) => {
+                let messages: Vec<&str> = errors.iter().map(|error| error.message.as_str()).collect();
+                write!(f, "invalid row: {}", messages.join("; "))
+            }
+            Error::NotFound(message)
+            | Error::AlreadyExists(message)
+            | Error::Validation(message)
+            | Error::Forbidden(message)
+            | Error::UniqueViolation(message)
+            | Error::ForeignKeyViolation(message)
+            | Error::Busy(message)
+            | Error::PreconditionFailed(message)
+            | Error::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// Maps SQLite failures clients can act on to dedicated variants; anything
+/// else stays a `DieselError`.
+impl From<diesel::result::Error> for Error {
+    fn from(e: diesel::result::Error) -> Self {
+        use diesel::result::DatabaseErrorKind;
+        use diesel::result::Error as DieselError;
+
+        match e {
+            DieselError::NotFound => Error::NotFound("record not found".to_string()),
+            DieselError::DatabaseError(kind, info) => {
+                let message = info.message().to_string();
+                match kind {
+                    DatabaseErrorKind::UniqueViolation => Error::UniqueViolation(message),
+                    DatabaseErrorKind::ForeignKeyViolation => Error::ForeignKeyViolation(message),
+                    DatabaseErrorKind::NotNullViolation | DatabaseErrorKind::CheckViolation => Error::Validation(message),
+                    _ if message.contains("already exists") => Error::AlreadyExists(message),
+                    _ if message.contains("database is locked") || message.contains("database table is locked") => Error::Busy(message),
+                    _ => Error::DieselError(DieselError::DatabaseError(kind, info)),
+                }
+            }
+            e => Error::DieselError(e),
+        }
+    }
+}
+
+impl From<InvalidIdentifier> for Error {
+    fn from(e: InvalidIdentifier) -> Self {
+        Error::Validation(e.0)
+    }
+}
+
+#[derive(QueryableByName)]
+pub struct JsonRow {
+    #[diesel(sql_type = Text)]
+    data: String,
+}
+
+/// Builds a `json_object(...)` expression selecting every column of a row.
+pub fn json_object(columns: &[ColumnSchema]) -> String {
+    let pairs: Vec<String> = columns
+        .iter()
+        .map(|column| format!("{}, {}", quote_literal(column.name.as_str()), column.name))
+        .collect();
+
+    format!("json_object({})", pairs.join(", "))
+}
+
+/// Parses a row selected with `json_object` and decodes it using `schema`.
+pub fn parse_row(schema: &TableSchema, row: JsonRow) -> Result<Row, Error> {
+    serde_json::from_str(&row.data)
+        .map(|row| codec::decode_row(schema, row))
+        .map_err(|e| Error::DieselError(diesel::result::Error::DeserializationError(Box::new(e))))
 }
 
 impl CrudService {
     pub fn new(pool: DbPool) -> Self {
         Self {
             pool,
-            id_col: ColumnSchema {
-                name: "id".to_string(),
-                data_type: DataType::Integer,
-                primary_key: Some(true),
-                auto_increment: Some(true),
-                unique: Some(true),
-                not_null: Some(true),
-                default: None,
-            },
-            created_at_col: ColumnSchema {
-                name: "created_at".to_string(),
-                data_type: DataType::TimeStamp,
-                primary_key: Some(false),
-                auto_increment: Some(false),
-                unique: Some(false),
-                not_null: Some(true),
-                default: Some("CURRENT_TIMESTAMP".to_string()),
-            },
-            updated_at_col: ColumnSchema {
-                name: "updated_at".to_string(),
-                data_type: DataType::TimeStamp,
-                primary_key: Some(false),
-                auto_increment: Some(false),
-                unique: Some(false),
-                not_null: Some(true),
-                default: Some("CURRENT_TIMESTAMP".to_string()),
-            },
+            pending: Arc::new(Semaphore::new(DEFAULT_MAX_PENDING)),
+            max_pending: DEFAULT_MAX_PENDING,
+            default_page_size: DEFAULT_PAGE_SIZE,
+            max_page_size: MAX_PAGE_SIZE,
         }
     }
 
+    /// Sets how many database operations may be queued or running at once.
+    pub fn with_max_pending(mut self, max_pending: usize) -> Self {
+        self.pending = Arc::new(Semaphore::new(max_pending));
+        self.max_pending = max_pending;
+        self
+    }
+
+    /// Sets the page size used when `?limit=` is not given and the largest
+    /// one clients may ask for.
+    pub fn with_page_size(mut self, default_page_size: i64, max_page_size: i64) -> Self {
+        self.default_page_size = default_page_size;
+        self.max_page_size = max_page_size;
+        self
+    }
+
+    /// Prepares the database for use, creating the schema registry on first run.
+    pub async fn init(&self) -> Result<(), Error> {
+        self.run(|_, conn| {
+            registry::ensure(conn)?;
+            migrations::ensure(conn)?;
+            sequences::ensure(conn)?;
+
+            for schema in registry::list(conn)? {
+                log::info!("Loaded schema for table {} ({} columns)", schema.name, schema.columns.len());
+            }
+
+            Ok(())
+        }).await
+    }
+
     pub async fn create_table(&self, schema: TableSchema) -> Result<TableSchema, Error> {
-        let table_name = &schema.name;
-        let columns = &schema.columns;
+        schema.validate()?;
+
+        self.run(move |service, conn| {
+            match conn.transaction(|conn| service.create_table_in(conn, &schema)) {
+                Ok(_) => Ok(service.enrich(schema)),
+                Err(e) => {
+                    log::error!("Error creating table: {}", e);
+                    Err(e)
+                }
+            }
+        }).await
+    }
 
-        let mut conn = self.pool.get().expect("couldn't get db connection from pool");
+    /// Drops a managed table together with its trigger and registry entry.
+    /// System tables and tables fastfood did not create are refused.
+    pub async fn drop_table(&self, table_name: &str) -> Result<(), Error> {
+        let table_name = table_name.to_string();
 
-        match conn.transaction(|conn| {
-            let mut query_columns = format!("{}", self.id_col);
+        if introspect::is_system_table(&table_name) {
+            return Err(Error::Forbidden(format!("table {} is a system table", table_name)));
+        }
 
-            for column in columns {
-                query_columns.push_str(&format!(", {}", column));
+        self.run(move |service, conn| {
+            match conn.transaction(|conn| service.drop_table_in(conn, &table_name)) {
+                Ok(_) => Ok(()),
+                Err(e) => {
+                    log::error!("Error dropping table: {}", e);
+                    Err(e)
+                }
             }
+        }).await
+    }
 
-            query_columns.push_str(&format!(", {}", self.created_at_col));
-            query_columns.push_str(&format!(", {}", self.updated_at_col));
+    /// Applies `alter.operations` in order, in one transaction that also
+    /// updates the stored schema. The managed columns cannot be altered.
+    /// Changing a column rebuilds the table, which is refused if any stored
+    /// value would not survive the conversion.
+    pub async fn alter_table(&self, table_name: &str, alter: AlterTable) -> Result<TableSchema, Error> {
+        let table_name = table_name.to_string();
 
-            let create_query = format!("CREATE TABLE {} ({})", table_name, query_columns);
+        if introspect::is_system_table(&table_name) {
+            return Err(Error::Forbidden(format!("table {} is a system table", table_name)));
+        }
 
-            log::info!("Executing query: {}", create_query);
+        self.run(move |service, conn| {
+            let result = rebuild::without_foreign_keys(conn, |conn, foreign_keys| {
+                conn.transaction(|conn| service.alter_table_in(conn, &table_name, &alter, foreign_keys))
+            });
 
-            diesel::sql_query(create_query).execute(conn)?;
+            match result {
+                Ok(schema) => Ok(service.enrich(schema)),
+                Err(e) => {
+                    log::error!("Error altering table: {}", e);
+                    Err(e)
+                }
+            }
+        }).await
+    }
 
-            // create trigger for updated_at
-            let trigger_query = format!("CREATE TRIGGER update_{table_name}_updated_at
-                AFTER UPDATE ON {table_name}
-                FOR EACH ROW
-                BEGIN
-                    UPDATE {table_name} SET updated_at = CURRENT_TIMESTAMP WHERE id = OLD.id;
-                END;", table_name = table_name);
+    /// Reports what `alter_table` would do, without changing anything.
+    pub async fn preview_alter(&self, table_name: &str, alter: AlterTable) -> Result<AlterReport, Error> {
+        let table_name = table_name.to_string();
 
-            log::info!("Executing query: {}", trigger_query);
-            diesel::sql_query(trigger_query).execute(conn)?;
+        self.run(move |service, conn| {
+            let current = registered_schema(conn, &table_name)?;
+            let managed = current.managed_columns();
+            let plan = alter.plan(current, &managed.iter().collect::<Vec<_>>())?;
 
-            Ok(())
-        }) {
-            Ok(_) => {
-                let mut cols = vec![self.id_col.clone()];
-                cols.extend(columns.iter().cloned());
-                cols.push(self.created_at_col.clone());
-                cols.push(self.updated_at_col.clone());
-
-                Ok(TableSchema {
-                    name: table_name.to_string(),
-                    columns: cols,
+            let failures = if plan.rebuild {
+                service.conversion_failures(conn, &plan)?
+            } else {
+                Vec::new()
+            };
+
+            Ok(AlterReport {
+                schema: service.enrich(plan.schema),
+                rebuild: plan.rebuild,
+                statements: plan.statements,
+                failures,
+            })
+        }).await
+    }
+
+    /// Lists every recorded schema change, oldest first.
+    pub async fn list_migrations(&self) -> Result<Vec<Migration>, Error> {
+        self.run(|_, conn| Ok(migrations::list(conn)?)).await
+    }
+
+    /// Undoes the most recent migration that has not been rolled back yet,
+    /// restoring the table's previous shape and stored schema. Undoing a
+    /// drop recreates the table without its rows, which the result warns
+    /// about. Like `alter_table`, it
+    /// refuses when stored values would not survive the conversion back.
+    /// Undoing a create or an added column drops data, so it is refused
+    /// unless `allow_destructive` is set.
+    pub async fn rollback_migration(&self, allow_destructive: bool) -> Result<Rollback, Error> {
+        self.run(move |service, conn| {
+            rebuild::without_foreign_keys(conn, |conn, foreign_keys| {
+                conn.transaction::<_, Error, _>(|conn| {
+                    let migration = migrations::last_applied(conn)?
+                        .ok_or_else(|| Error::NotFound("there is no migration to roll back".to_string()))?;
+                    let change = &migration.change;
+                    let refuse = |dropped: String| Error::Validation(format!(
+                        "rolling back migration {} drops {}; pass allow_destructive to roll it back", migration.version, dropped));
+
+                    if change.kind == MigrationKind::Create && !allow_destructive {
+                        return Err(refuse(format!("table {}", change.table_name)));
+                    }
+
+                    if let (Some(previous), Some(current)) = (&change.previous_schema, &change.schema) {
+                        let plan = AlterPlan::reverting(current, previous.clone(), change.reverse_sources.as_deref());
+
+                        let dropped: Vec<&str> = current.columns
+                            .iter()
+                            .filter(|column| !plan.sources.iter().flatten().any(|source| source.name == column.name))
+                            .map(|column| column.name.as_str())
+                            .collect();
+                        if !dropped.is_empty() && !allow_destructive {
+                            return Err(refuse(format!("column {} of table {}", dropped.join(", "), change.table_name)));
+                        }
+
+                        let failures = service.conversion_failures(conn, &plan)?;
+                        if !failures.is_empty() {
+                            return Err(Error::Validation(format!(
+                                "{} stored values cannot be converted back to the schema before migration {}",
+                                failures.len(), migration.version)));
+                        }
+                    }
+
+                    execute_all(conn, &change.reverse)?;
+
+                    registry::remove(conn, &change.table_name)?;
+                    if let Some(ref previous) = change.previous_schema {
+                        registry::insert(conn, previous)?;
+
+                        if foreign_keys {
+                            rebuild::check_foreign_keys(conn, &previous.name)?;
+                        }
+                    }
+
+                    migrations::mark_rolled_back(conn, migration.version)?;
+                    log::info!("Rolled back migration {} on table {}", migration.version, change.table_name);
+
+                    let warning = (change.kind == MigrationKind::Drop).then(|| {
+                        format!("table {} was recreated empty; rows are not kept when a table is dropped", change.table_name)
+                    });
+                    let migration = migrations::get(conn, migration.version)?
+                        .ok_or_else(|| Error::Internal(format!("migration {} disappeared", migration.version)))?;
+
+                    Ok(Rollback { migration, warning })
                 })
+            })
+        }).await
+    }
+
+    /// The schema a table had once migration `version` was applied.
+    pub async fn schema_at(&self, table_name: &str, version: i64) -> Result<TableSchema, Error> {
+        let table_name = table_name.to_string();
+
+        self.run(move |service, conn| {
+            match migrations::schema_at(conn, &table_name, version)? {
+                Some(Some(schema)) => Ok(service.enrich(schema)),
+                _ => Err(Error::NotFound(format!("table {} did not exist at version {}", table_name, version))),
             }
-            Err(e) => {
-                log::error!("Error creating table: {}", e);
-                Err(Error::DieselError(e))
+        }).await
+    }
+
+    /// Adds an index to a managed table, recorded as an alteration.
+    pub async fn create_index(&self, table_name: &str, index: IndexSchema) -> Result<IndexSchema, Error> {
+        let operations = vec![AlterOperation::CreateIndex { index: index.clone() }];
+        self.alter_table(table_name, AlterTable { operations, dry_run: false }).await?;
+        Ok(index)
+    }
+
+    pub async fn list_indexes(&self, table_name: &str) -> Result<Vec<IndexSchema>, Error> {
+        let table_name = table_name.to_string();
+        self.run(move |_, conn| Ok(registered_schema(conn, &table_name)?.indexes)).await
+    }
+
+    pub async fn drop_index(&self, table_name: &str, index_name: &str) -> Result<(), Error> {
+        let operations = vec![AlterOperation::DropIndex { name: index_name.to_string() }];
+        self.alter_table(table_name, AlterTable { operations, dry_run: false }).await?;
+        Ok(())
+    }
+
+    /// Brings the managed tables in line with `document`: tables it adds
+    /// are created, tables whose columns differ are altered and managed
+    /// tables it no longer lists are dropped, all in one transaction.
+    /// Tables fastfood does not manage are left alone. Plans dropping tables
+    /// or columns are refused unless `options.allow_destructive` is set.
+    pub async fn apply_schema(&self, document: SchemaDocument, options: ApplyOptions) -> Result<SchemaPlan, Error> {
+        document.validate()?;
+
+        self.run(move |service, conn| {
+            let result = rebuild::without_foreign_keys(conn, |conn, foreign_keys| {
+                conn.transaction(|conn| {
+                    let live = registry::list(conn)?;
+                    declarative::check_fixed_settings(&document.tables, &live)?;
+                    let mut plan = SchemaPlan::new(declarative::plan(&document.tables, &live));
+
+                    for step in &plan.steps {
+                        match step {
+                            PlanStep::Create { schema } if introspect::table_exists(conn, schema.name.as_str())? => {
+                                return Err(Error::Forbidden(format!("table {} is not managed by fastfood", schema.name.as_str())));
+                            }
+                            PlanStep::Alter { table, operations } => {
+                                let current = registered_schema(conn, table.as_str())?;
+                                let alter = AlterTable { operations: operations.clone(), dry_run: false };
+                                let managed = current.managed_columns();
+                                alter.plan(current, &managed.iter().collect::<Vec<_>>())?;
+                            }
+                            _ => {}
+                        }
+                    }
+
+                    if options.dry_run {
+                        return Ok(plan);
+                    }
+
+                    if plan.destructive && !options.allow_destructive {
+                        let destructive: Vec<String> = plan.steps
+                            .iter()
+                            .filter(|step| step.is_destructive())
+                            .map(|step| step.to_string())
+                            .collect();
+                        return Err(Error::Forbidden(format!("the plan drops data ({}); pass allow_destructive to apply it",
+                                                            destructive.join("; "))));
+                    }
+
+                    for step in &plan.steps {
+                        log::info!("Applying schema step: {}", step);
+
+                        match step {
+                            PlanStep::Create { schema } => service.create_table_in(conn, schema)?,
+                            PlanStep::Alter { table, operations } => {
+                                let alter = AlterTable { operations: operations.clone(), dry_run: false };
+                                service.alter_table_in(conn, table.as_str(), &alter, foreign_keys)?;
+                            }
+                            PlanStep::Drop { table } => service.drop_table_in(conn, table.as_str())?,
+                        }
+                    }
+
+                    plan.applied = true;
+                    Ok(plan)
+                })
+            });
+
+            if let Err(ref e) = result {
+                log::error!("Error applying schema: {}", e);
             }
-        }
+            result
+        }).await
     }
 
-    pub async fn drop_table(&self, table_name: &str) -> Result<(), Error> {
-        let mut conn = self.pool.get().expect("couldn't get db connection from pool");
+    pub async fn list_tables(&self) -> Result<Vec<TableInfo>, Error> {
+        self.run(|service, conn| {
+            let managed = registry::list(conn)?;
+            let mut tables = Vec::new();
+
+            for name in introspect::table_names(conn)? {
+                match managed.iter().find(|schema| schema.name == name.as_str()) {
+                    Some(schema) => tables.push(TableInfo {
+                        schema: service.enrich(schema.clone()),
+                        unmanaged: false,
+                    }),
+                    None => tables.push(TableInfo {
+                        schema: introspect::describe(conn, &name)?,
+                        unmanaged: true,
+                    }),
+                }
+            }
+
+            Ok(tables)
+        }).await
+    }
+
+    pub async fn describe_table(&self, table_name: &str) -> Result<TableInfo, Error> {
+        let table_name = table_name.to_string();
+
+        self.run(move |service, conn| {
+            if let Some(schema) = registry::get(conn, &table_name)? {
+                return Ok(TableInfo {
+                    schema: service.enrich(schema),
+                    unmanaged: false,
+                });
+            }
+
+            if introspect::is_system_table(&table_name) || !introspect::table_exists(conn, &table_name)? {
+                return Err(Error::NotFound(format!("table {} not found", table_name)));
+            }
+
+            Ok(TableInfo {
+                schema: introspect::describe(conn, &table_name)?,
+                unmanaged: true,
+            })
+        }).await
+    }
+
+    pub async fn insert_row(&self, table_name: &str, row: Row) -> Result<VersionedRow, Error> {
+        let table_name = table_name.to_string();
+
+        self.run(move |service, conn| conn.transaction(|conn| {
+            let schema = service.table_schema(conn, &table_name)?;
+            let (names, mut values) = validation::validate_row(&schema, &managed_names(&schema), row, Write::Insert)?;
+
+            let mut columns: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+            let mut placeholders = vec!["?".to_string(); names.len()];
+
+            let id = match schema.options().id.generate() {
+                Some(generated) => Some(SqlValue::Text(generated)),
+                // an id that is not the rowid comes from the table's sequence
+                None if schema.primary_key.is_some() => Some(SqlValue::Integer(sequences::next(conn, &schema.name)?)),
+                None => None,
+            };
+            if let Some(id) = id {
+                columns.insert(0, Identifier::managed(ID_COLUMN).to_string());
+                placeholders.insert(0, "?".to_string());
+                values.insert(0, id);
+            }
+
+            let insert_query = if columns.is_empty() {
+                format!("INSERT INTO {} DEFAULT VALUES RETURNING {} AS data",
+                        schema.name, json_object(&schema.columns))
+            } else {
+                format!("INSERT INTO {} ({}) VALUES ({}) RETURNING {} AS data",
+                        schema.name,
+                        columns.join(", "),
+                        placeholders.join(", "),
+                        json_object(&schema.columns))
+            };
+
+            log::info!("Executing query: {}", insert_query);
+
+            let row = query_with_binds(insert_query, values).get_result::<JsonRow>(conn)?;
+            Ok(VersionedRow::new(&schema, parse_row(&schema, row)?))
+        })).await
+    }
+
+    /// Lists a page of rows shaped by the query string: filters as
+    /// described in `services::filter`, plus `select`, `order`, `limit`,
+    /// `cursor` and `count` as described in `services::listing`.
+    pub async fn list_rows(&self, table_name: &str, params: Vec<(String, String)>) -> Result<RowPage, Error> {
+        let table_name = table_name.to_string();
+
+        self.run(move |service, conn| {
+            let schema = service.table_schema(conn, &table_name)?;
+            let query = ListQuery::parse(&schema, &params, service.default_page_size, service.max_page_size)?;
+
+            let mut binds = Vec::new();
+            let select_query = format!("SELECT {} AS data FROM {}{}{} LIMIT ?",
+                                       json_object(&query.columns(&schema)),
+                                       schema.name,
+                                       query.where_sql(&mut binds),
+                                       query.order_sql());
+
+            log::info!("Executing query: {}", select_query);
+
+            // one extra row tells whether there is a next page
+            binds.push(SqlValue::Integer(query.limit + 1));
+            let mut rows = query_with_binds(select_query, binds)
+                .load::<JsonRow>(conn)?
+                .into_iter()
+                .map(|row| parse_row(&schema, row))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let next_cursor = if rows.len() as i64 > query.limit {
+                rows.truncate(query.limit as usize);
+                rows.last().map(|row| query.cursor_after(row))
+            } else {
+                None
+            };
+
+            if let Some(ref embed) = query.embed {
+                let tables: Vec<TableSchema> = registry::list(conn)?.into_iter().map(|table| service.enrich(table)).collect();
+                embed::embed_rows(conn, &schema, &mut rows, embed, &tables, service.max_page_size)?;
+            }
+
+            let total_count = match query.count {
+                Some(mode) => Some(count_rows(conn, &schema, &query, mode)?),
+                None => None,
+            };
+
+            Ok(RowPage {
+                rows: rows.into_iter().map(|row| query.project(row)).collect(),
+                next_cursor,
+                total_count,
+            })
+        }).await
+    }
 
-        let drop_query = format!("DROP TABLE {}", table_name);
+    pub async fn get_row(&self, table_name: &str, id: &str) -> Result<VersionedRow, Error> {
+        let (table_name, id) = (table_name.to_string(), id.to_string());
 
-        log::info!("Executing query: {}", drop_query);
+        self.run(move |service, conn| {
+            let schema = service.table_schema(conn, &table_name)?;
+            let row = select_row(conn, &schema, &id)?;
 
-        match diesel::sql_query(drop_query).execute(&mut conn) {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                log::error!("Error dropping table: {}", e);
-                return Err(Error::DieselError(e));
+            Ok(VersionedRow::new(&schema, row))
+        }).await
+    }
+
+    /// Updates a row, provided it meets `if_match`. The precondition is
+    /// part of the `UPDATE` itself, so no concurrent write can slip in
+    /// between the check and the change.
+    pub async fn update_row(&self, table_name: &str, id: &str, row: Row, if_match: Option<IfMatch>) -> Result<VersionedRow, Error> {
+        let (table_name, id) = (table_name.to_string(), id.to_string());
+
+        self.run(move |service, conn| {
+            conn.transaction(|conn| {
+                let schema = service.table_schema(conn, &table_name)?;
+                let (names, mut values) = validation::validate_row(&schema, &managed_names(&schema), row, Write::Update)?;
+
+                if names.is_empty() {
+                    let current = select_row(conn, &schema, &id)?;
+                    if if_match.as_ref().is_some_and(|if_match| !if_match.accepts(versioning::version(&schema, &current))) {
+                        return Err(precondition_failed(&schema, &id));
+                    }
+                    return Ok(VersionedRow::new(&schema, current));
+                }
+
+                values.push(parse_id(&schema, &id)?);
+                let precondition = precondition_sql(&schema, if_match.as_ref(), &mut values);
+                let update_query = format!("UPDATE {} SET {} WHERE id = ?{}",
+                                           schema.name,
+                                           names.iter().map(|name| format!("{} = ?", name)).collect::<Vec<_>>().join(", "),
+                                           precondition);
+
+                log::info!("Executing query: {}", update_query);
+
+                if query_with_binds(update_query, values).execute(conn)? == 0 {
+                    return Err(missed_write(conn, &schema, &id));
+                }
+
+                // read back after the update trigger has run
+                let row = select_row(conn, &schema, &id)?;
+                Ok(VersionedRow::new(&schema, row))
+            })
+        }).await
+    }
+
+    /// Deletes a row, provided it meets `if_match`.
+    pub async fn delete_row(&self, table_name: &str, id: &str, if_match: Option<IfMatch>) -> Result<(), Error> {
+        let (table_name, id) = (table_name.to_string(), id.to_string());
+
+        self.run(move |service, conn| {
+            let schema = service.table_schema(conn, &table_name)?;
+
+            let mut binds = vec![parse_id(&schema, &id)?];
+            let delete_query = format!("DELETE FROM {} WHERE id = ?{}",
+                                       schema.name, precondition_sql(&schema, if_match.as_ref(), &mut binds));
+
+            log::info!("Executing query: {}", delete_query);
+
+            match query_with_binds(delete_query, binds).execute(conn)? {
+                0 => Err(missed_write(conn, &schema, &id)),
+                _ => Ok(()),
             }
+        }).await
+    }
+
+    /// Runs blocking database work with a pooled connection on actix's
+    /// blocking thread pool, keeping it off the async workers. At most
+    /// `max_pending` operations may be queued or running at once; beyond
+    /// that callers get `Error::Busy` instead of queuing without limit.
+    async fn run<F, T>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&CrudService, &mut SqliteConnection) -> Result<T, Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let permit = self.pending.clone().try_acquire_owned().map_err(|_| {
+            log::warn!("Rejecting database operation: {} already pending", self.max_pending);
+            Error::Busy("too many pending database operations".to_string())
+        })?;
+
+        let service = self.clone();
+
+        web::block(move || {
+            let _permit = permit;
+            let mut conn = service.connection()?;
+            f(&service, &mut conn)
+        })
+        .await
+        .map_err(|e| Error::Internal(format!("database operation failed: {}", e)))?
+    }
+
+    /// Returns the schema of a managed table, including the managed columns.
+    fn table_schema(&self, conn: &mut SqliteConnection, table_name: &str) -> Result<TableSchema, Error> {
+        match registry::get(conn, table_name)? {
+            Some(schema) => Ok(self.enrich(schema)),
+            None => Err(Error::NotFound(format!("table {} not found", table_name))),
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use diesel::sqlite::SqliteConnection;
-    use diesel::r2d2::ConnectionManager;
-    use diesel::prelude::*;
+    /// Adds the columns `CrudService` manages around the user-defined ones:
+    /// `id` first and the timestamps and version last.
+    fn enrich(&self, mut schema: TableSchema) -> TableSchema {
+        let mut columns = schema.managed_columns();
+        let timestamps = columns.split_off(1);
+        columns.append(&mut schema.columns);
+        columns.extend(timestamps);
 
-    fn get_pool() -> DbPool {
-        dotenv::dotenv().ok();
-        let database_url = "test.sqlite";
-        let manager = ConnectionManager::<SqliteConnection>::new(database_url);
-        r2d2::Pool::builder()
-            .build(manager)
-            .expect("Failed to create pool.")
+        schema.columns = columns;
+        schema
     }
 
-    #[actix_web::test]
-    async fn test_create_table() {
-        let pool = get_pool();
-        let service = CrudService::new(pool);
+    /// Creates a managed table and records the migration, within the
+    /// caller's transaction.
+    fn create_table_in(&self, conn: &mut SqliteConnection, schema: &TableSchema) -> Result<(), Error> {
+        self.check_references(conn, schema)?;
 
-        let schema = TableSchema {
-            name: "test_table".to_string(),
-            columns: vec![
-                ColumnSchema {
-                    name: "name".to_string(),
-                    data_type: DataType::Text,
-                    primary_key: Some(false),
-                    auto_increment: Some(false),
-                    unique: Some(false),
-                    not_null: Some(true),
-                    default: None,
-                },
-                ColumnSchema {
-                    name: "age".to_string(),
-                    data_type: DataType::Integer,
-                    primary_key: Some(false),
-                    auto_increment: Some(false),
-                    unique: Some(false),
-                    not_null: Some(true),
-                    default: None,
-                },
-            ],
-        };
+        let table_name = &schema.name;
+        let mut forward = vec![self.create_table_sql(table_name, schema)];
+        forward.extend(self.updated_at_trigger_sql(table_name, schema));
+        forward.extend(self.index_sql(schema)?);
 
-        let result = service.create_table(schema).await;
-        assert!(result.is_ok());
+        execute_all(conn, &forward)?;
+        registry::insert(conn, schema)?;
+
+        migrations::record(conn, &Change {
+            table_name: table_name.as_str().to_string(),
+            kind: MigrationKind::Create,
+            forward,
+            reverse: vec![format!("DROP TABLE {}", table_name)],
+            previous_schema: None,
+            schema: Some(schema.clone()),
+            reverse_sources: None,
+        })?;
+
+        Ok(())
     }
 
-    #[actix_web::test]
-    async fn test_drop_table() {
-        let pool = get_pool();
-        let service = CrudService::new(pool);
+    /// Drops a managed table and records the migration, within the
+    /// caller's transaction.
+    fn drop_table_in(&self, conn: &mut SqliteConnection, table_name: &str) -> Result<(), Error> {
+        let schema = registered_schema(conn, table_name)?;
 
-        let result = service.drop_table("test_table").await;
-        assert!(result.is_ok());
+        for other in registry::list(conn)? {
+            let referencing = other.columns.iter().find(|column| {
+                column.references.as_ref().is_some_and(|references| same_table(&references.table, &schema.name))
+            });
+
+            match referencing {
+                Some(column) if !same_table(&other.name, &schema.name) => {
+                    return Err(Error::ForeignKeyViolation(format!("table {} is referenced by column {} of table {}",
+                                                                  schema.name.as_str(), column.name.as_str(), other.name.as_str())));
+                }
+                _ => {}
+            }
+        }
+        let trigger = updated_at_trigger(&schema.name);
+
+        let mut reverse = vec![self.create_table_sql(&schema.name, &schema)];
+        reverse.extend(self.updated_at_trigger_sql(&schema.name, &schema));
+        reverse.extend(rebuild::dependent_objects(conn, &schema.name, &[&trigger])?);
+        if schema.primary_key.is_some() {
+            // ids handed out before the drop must stay taken once it is rolled back
+            reverse.push(sequences::restore_sql(&schema.name, sequences::high_water(conn, &schema.name)?));
+        }
+
+        let forward = vec![
+            format!("DROP TRIGGER IF EXISTS {}", trigger),
+            format!("DROP TABLE {}", schema.name),
+        ];
+
+        execute_all(conn, &forward)?;
+        registry::remove(conn, table_name)?;
+        sequences::remove(conn, table_name)?;
+
+        migrations::record(conn, &Change {
+            table_name: schema.name.as_str().to_string(),
+            kind: MigrationKind::Drop,
+            forward,
+            reverse,
+            previous_schema: Some(schema),
+            schema: None,
+            reverse_sources: None,
+        })?;
+
+        Ok(())
     }
-}
\ No newline at end of file
+
+    /// Alters a managed table and records the migration, within the
+    /// caller's transaction. Foreign keys must have been switched off by
+    /// the caller; `foreign_keys` tells whether they were on.
+    fn alter_table_in(&self, conn: &mut SqliteConnection, table_name: &str, alter: &AlterTable, foreign_keys: bool) -> Result<TableSchema, Error> {
+        let previous = registered_schema(conn, table_name)?;
+        let managed = previous.managed_columns();
+        let plan = alter.plan(previous.clone(), &managed.iter().collect::<Vec<_>>())?;
+        self.check_references(conn, &plan.schema)?;
+        self.check_referencing(conn, &plan.schema)?;
+
+        if plan.rebuild {
+            let failures = self.conversion_failures(conn, &plan)?;
+            if !failures.is_empty() {
+                return Err(Error::Validation(format!(
+                    "{} stored values cannot be converted; send dry_run to list them", failures.len())));
+            }
+        }
+
+        // stored indexes are recreated from the schema rather than copied
+        let trigger = updated_at_trigger(&previous.name);
+        let mut skipped = vec![&trigger];
+        skipped.extend(previous.indexes.iter().map(|index| &index.name));
+        let objects = rebuild::dependent_objects(conn, &previous.name, &skipped)?;
+
+        let forward = if plan.rebuild {
+            self.rebuild_sql(&plan.schema, &plan.sources, &objects)?
+        } else {
+            plan.statements.clone()
+        };
+        let reverse_sources = plan.reverse_sources(&previous);
+        let reverse = self.rebuild_sql(&previous, &reverse_sources, &objects)?;
+
+        execute_all(conn, &forward)?;
+
+        if foreign_keys {
+            rebuild::check_foreign_keys(conn, &plan.schema.name)?;
+        }
+
+        registry::update(conn, &plan.schema)?;
+
+        migrations::record(conn, &Change {
+            table_name: previous.name.as_str().to_string(),
+            kind: MigrationKind::Alter,
+            forward,
+            reverse,
+            previous_schema: Some(previous),
+            schema: Some(plan.schema.clone()),
+            reverse_sources: Some(reverse_sources.into_iter().map(|source| source.map(|column| column.name)).collect()),
+        })?;
+
+        Ok(plan.schema)
+    }
+
+    /// Checks that the tables and columns referenced by `schema`'s foreign
+    /// keys exist, looking them up in `schema` itself for self-references.
+    fn check_references(&self, conn: &mut SqliteConnection, schema: &TableSchema) -> Result<(), Error> {
+        for column in &schema.columns {
+            if let Some(ref references) = column.references {
+                let target = if same_table(&references.table, &schema.name) {
+                    self.enrich(schema.clone())
+                } else if let Some(target) = registry::get(conn, references.table.as_str())? {
+                    self.enrich(target)
+                } else if introspect::table_exists(conn, references.table.as_str())? {
+                    introspect::describe(conn, references.table.as_str())?
+                } else {
+                    return Err(Error::Validation(format!("column {} references table {}, which does not exist",
+                                                         column.name.as_str(), references.table.as_str())));
+                };
+
+                references.check_target(column, &target)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the foreign keys other managed tables hold on `schema`
+    /// still point at a key of the right type, so altering a table cannot
+    /// rename, drop or loosen a column they reference. Their stored schemas
+    /// and `REFERENCES` clauses would otherwise go stale.
+    fn check_referencing(&self, conn: &mut SqliteConnection, schema: &TableSchema) -> Result<(), Error> {
+        let target = self.enrich(schema.clone());
+
+        for other in registry::list(conn)? {
+            if same_table(&other.name, &schema.name) {
+                continue;
+            }
+
+            for column in &other.columns {
+                let references = match column.references {
+                    Some(ref references) if same_table(&references.table, &schema.name) => references,
+                    _ => continue,
+                };
+
+                references.check_target(column, &target).map_err(|e| {
+                    Error::ForeignKeyViolation(format!("table {} depends on {}.{}: {}",
+                                                       other.name.as_str(), schema.name.as_str(), references.column.as_str(), e))
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Lists the stored values `plan` could not carry over to the rebuilt table.
+    fn conversion_failures(&self, conn: &mut SqliteConnection, plan: &AlterPlan) -> Result<Vec<ConversionFailure>, Error> {
+        let mut failures = Vec::new();
+
+        for (source, target) in plan.conversions() {
+            failures.extend(rebuild::find_failures(conn, &plan.schema.name, &Identifier::managed(ID_COLUMN), source, target)?);
+        }
+
+        Ok(failures)
+    }
+
+    /// Statements rebuilding a table as `table`, each user-defined column
+    /// filled from the matching column of `sources`, and recreating its
+    /// trigger, its indexes and the indexes and triggers in `objects`.
+    fn rebuild_sql(&self, table: &TableSchema, sources: &[Option<ColumnSchema>], objects: &[String]) -> Result<Vec<String>, Error> {
+        let table_name = &table.name;
+        let columns = &table.columns;
+        let new_table = Identifier::managed(format!("_fastfood_rebuild_{}", table_name.as_str()));
+
+        let managed = table.managed_columns();
+        let mut copied: Vec<(&ColumnSchema, &ColumnSchema)> = managed.iter().map(|column| (column, column)).collect();
+        for (source, target) in sources.iter().zip(columns) {
+            if let Some(source) = source {
+                copied.push((source, target));
+            }
+        }
+
+        let mut statements = vec![
+            self.create_table_sql(&new_table, table),
+            rebuild::copy_sql(table_name, &new_table, &copied),
+            format!("DROP TABLE {}", table_name),
+            format!("ALTER TABLE {} RENAME TO {}", new_table, table_name),
+        ];
+        statements.extend(self.updated_at_trigger_sql(table_name, table));
+        statements.extend(self.index_sql(table)?);
+        statements.extend(objects.iter().cloned());
+        Ok(statements)
+    }
+
+    /// `CREATE INDEX` for every index stored with `schema`.
+    fn index_sql(&self, schema: &TableSchema) -> Result<Vec<String>, Error> {
+        let table = self.enrich(schema.clone());
+        schema.indexes.iter().map(|index| index.sql(&table)).collect()
+    }
+
+    /// `CREATE TABLE` named `table_name` for a managed table with the
+    /// user-defined columns and table constraints of `schema`.
+    fn create_table_sql(&self, table_name: &Identifier, schema: &TableSchema) -> String {
+        let mut definitions: Vec<String> = self.enrich(schema.clone()).columns.iter().map(ColumnSchema::to_string).collect();
+        definitions.extend(schema.constraints_sql());
+
+        format!("CREATE TABLE {} ({})", table_name, definitions.join(", "))
+    }
+
+    /// The trigger keeping `updated_at` current and counting `version` up
+    /// on `table_name`, when `schema` keeps either column. A single trigger
+    /// does both, so each update bumps the version exactly once.
+    fn updated_at_trigger_sql(&self, table_name: &Identifier, schema: &TableSchema) -> Option<String> {
+        let mut assignments = Vec::new();
+        if let Some(ref updated_at) = schema.options().updated_at {
+            assignments.push(format!("{} = CURRENT_TIMESTAMP", updated_at));
+        }
+        if let Some(ref version) = schema.options().version {
+            assignments.push(format!("{version} = OLD.{version} + 1", version = version));
+        }
+        if assignments.is_empty() {
+            return None;
+        }
+
+        Some(format!("CREATE TRIGGER {trigger_name}
+            AFTER UPDATE ON {table_name}
+            FOR EACH ROW
+            BEGIN
+                UPDATE {table_name} SET {assignments} WHERE {id} = OLD.{id};
+            END;",
+            trigger_name = updated_at_trigger(table_name),
+            table_name = table_name,
+            assignments = assignments.join(", "),
+            id = Identifier::managed(ID_COLUMN)))
+    }
+
+    /// Checks a connection out of the pool, waiting at most the pool's
+    /// configured connection timeout. Foreign keys are switched on again,
+    /// as the pool only does so for new connections and a rebuild may have
+    /// failed to restore them.
+    fn connection(&self) -> Result<PooledConnection<ConnectionManager<SqliteConnection>>, Error> {
+        let mut conn = self.pool.get().map_err(|e| {
+            log::warn!("Could not get a database connection: {}", e);
+            Error::PoolError(e)
+        })?;
+
+        conn.batch_execute("PRAGMA foreign_keys = ON")?;
+        Ok(conn)
+    }
+}
+
+fn execute_all(conn: &mut SqliteConnection, queries: &[String]) -> Result<(), Error> {
+    for query in queries {
+        log::info!("Executing query: {}", query);
+        diesel::sql_query(query).execute(conn)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the stored schema of a table fastfood manages, refusing tables
+/// that exist but were not created through fastfood.
+fn registered_schema(conn: &mut SqliteConnection, table_name: &str) -> Result<TableSchema, Error> {
+    match registry::get(conn, table_name)? {
+        Some(schema) => Ok(schema),
+        None if introspect::table_exists(conn, table_name)? => {
+            Err(Error::Forbidden(format!("table {} is not managed by fastfood", table_name)))
+        }
+        None => Err(Error::NotFound(format!("table {} not found", table_name))),
+    }
+}
+
+/// Table names are compared the way SQLite does, case-insensitively.
+fn same_table(a: &Identifier, b: &Identifier) -> bool {
+    a.as_str().eq_ignore_ascii_case(b.as_str())
+}
+
+/// Name of the trigger keeping `updated_at` and `version` current on
+/// `table_name`; it predates `version`, hence the name.
+fn updated_at_trigger(table_name: &Identifier) -> Identifier {
+    Identifier::managed(format!("update_{}_updated_at", table_name.as_str()))
+}
+
+fn select_row(conn: &mut SqliteConnection, schema: &TableSchema, id: &str) -> Result<Row, Error> {
+    let table_name = schema.name.as_str();
+    let select_query = format!("SELECT {} AS data FROM {} WHERE id = ?",
+                               json_object(&schema.columns), schema.name);
+
+    let row = query_with_binds(select_query, vec![parse_id(schema, id)?])
+        .get_result::<JsonRow>(conn)
+        .optional()?;
+
+    match row {
+        Some(row) => parse_row(schema, row),
+        None => Err(row_not_found(table_name, id)),
+    }
+}
+
+#[derive(QueryableByName)]
+struct Count {
+    #[diesel(sql_type = BigInt)]
+    count: i64,
+}
+
+fn count_rows(conn: &mut SqliteConnection, schema: &TableSchema, query: &ListQuery, mode: CountMode) -> Result<i64, Error> {
+    let mut binds = Vec::new();
+    let filter = query.filter_sql(&mut binds);
+
+    let count_query = match mode {
+        CountMode::Exact => format!("SELECT COUNT(*) AS count FROM {}{}", schema.name, filter),
+        CountMode::Estimated if filter.is_empty() => format!("SELECT COALESCE(MAX(rowid), 0) AS count FROM {}", schema.name),
+        CountMode::Estimated => {
+            binds.push(SqlValue::Integer(ESTIMATE_SCAN_LIMIT));
+            format!("SELECT COUNT(*) AS count FROM (SELECT 1 FROM {}{} LIMIT ?)", schema.name, filter)
+        }
+    };
+
+    log::info!("Executing query: {}", count_query);
+
+    Ok(query_with_binds(count_query, binds).get_result::<Count>(conn)?.count)
+}
+
+/// The stored form of an id taken from a URL. Ids that do not fit the
+/// table's id strategy cannot belong to any row.
+fn parse_id(schema: &TableSchema, id: &str) -> Result<SqlValue, Error> {
+    schema.options().id.parse(id).ok_or_else(|| row_not_found(schema.name.as_str(), id))
+}
+
+fn row_not_found(table_name: &str, id: &str) -> Error {
+    Error::NotFound(format!("row {} not found in table {}", id, table_name))
+}
+
+/// The condition a write adds to its `WHERE` clause for `if_match`.
+fn precondition_sql(schema: &TableSchema, if_match: Option<&IfMatch>, binds: &mut Vec<SqlValue>) -> String {
+    if_match.map_or_else(String::new, |if_match| if_match.where_sql(schema.options().version.as_ref(), binds))
+}
+
+/// Explains a write that matched no row: either the row is gone or it
+/// failed the precondition.
+fn missed_write(conn: &mut SqliteConnection, schema: &TableSchema, id: &str) -> Error {
+    match select_row(conn, schema, id) {
+        Ok(_) => precondition_failed(schema, id),
+        Err(e) => e,
+    }
+}
+
+fn precondition_failed(schema: &TableSchema, id: &str) -> Error {
+    match schema.options().version {
+        Some(_) => Error::PreconditionFailed(format!("row {} of table {} has changed; read it again", id, schema.name.as_str())),
+        None => Error::PreconditionFailed(format!("table {} keeps no row versions to match", schema.name.as_str())),
+    }
+}
+
+/// The columns of `schema` clients may not write.
+fn managed_names(schema: &TableSchema) -> Vec<&Identifier> {
+    schema
+        .columns
+        .iter()
+        .filter(|column| schema.options().is_managed(column.name.as_str()))
+        .map(|column| &column.name)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{build_pool, PoolConfig};
+    use serde_json::json;
+    use std::time::Duration;
+
+    /// Every test gets its own database file so tests can run in parallel.
+    fn get_pool_with(name: &str, config: &PoolConfig) -> DbPool {
+        dotenv::dotenv().ok();
+        let database_url = std::env::temp_dir().join(format!("fastfood-{}-{}.sqlite", name, std::process::id()));
+        let _ = std::fs::remove_file(&database_url);
+        build_pool(&database_url.to_string_lossy(), config).expect("Failed to create pool.")
+    }
+
+    fn get_pool(name: &str) -> DbPool {
+        get_pool_with(name, &PoolConfig::default())
+    }
+
+    async fn get_service(name: &str) -> CrudService {
+        let service = CrudService::new(get_pool(name));
+        assert!(service.init().await.is_ok());
+        service
+    }
+
+    fn test_schema() -> TableSchema {
+        TableSchema {
+            name: "test_table".parse().unwrap(),
+            columns: vec![
+                ColumnSchema {
+                    name: "name".parse().unwrap(),
+                    data_type: DataType::Text,
+                    primary_key: Some(false),
+                    auto_increment: Some(false),
+                    unique: Some(false),
+                    not_null: Some(true),
+                    default: None,
+                    references: None,
+                    rules: ColumnRules::default(),
+                },
+                ColumnSchema {
+                    name: "age".parse().unwrap(),
+                    data_type: DataType::Integer,
+                    primary_key: Some(false),
+                    auto_increment: Some(false),
+                    unique: Some(false),
+                    not_null: Some(true),
+                    default: None,
+                    references: None,
+                    rules: ColumnRules::default(),
+                },
+            ],
+            indexes: Vec::new(),
+            primary_key: None,
+            unique: Vec::new(),
+            options: Some(TableOptions::default()),
+        }
+    }
+
+    fn row(value: Value) -> Row {
+        value.as_object().cloned().unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_create_table() {
+        let service = get_service("create_table").await;
+
+        let result = service.create_table(test_schema()).await;
+        assert!(result.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn test_drop_table() {
+        let service = get_service("drop_table").await;
+        assert!(service.create_table(test_schema()).await.is_ok());
+
+        let result = service.drop_table("test_table").await;
+        assert!(result.is_ok());
+
+        let mut conn = service.connection().ok().unwrap();
+        assert!(registry::get(&mut conn, "test_table").ok().unwrap().is_none());
+        assert!(!introspect::table_exists(&mut conn, "test_table").unwrap());
+
+        let triggers: i64 = diesel::select(diesel::dsl::sql::<BigInt>(
+            "(SELECT count(*) FROM sqlite_master WHERE type = 'trigger')"))
+            .get_result(&mut conn)
+            .unwrap();
+        assert_eq!(triggers, 0);
+    }
+
+    #[actix_web::test]
+    async fn test_create_table_registers_schema() {
+        let service = get_service("registers_schema").await;
+        assert!(service.create_table(test_schema()).await.is_ok());
+
+        let mut conn = service.connection().ok().unwrap();
+        let schema = registry::get(&mut conn, "test_table").ok().unwrap().unwrap();
+        assert_eq!(schema.columns.len(), 2);
+        assert_eq!(schema.columns[0].name, "name");
+
+        // a failed CREATE TABLE must not leave a registry entry behind
+        let mut duplicate = test_schema();
+        duplicate.name = "other_table".parse().unwrap();
+        duplicate.columns.push(duplicate.columns[0].clone());
+        assert!(matches!(service.create_table(duplicate).await, Err(Error::Validation(_))));
+        assert!(registry::get(&mut conn, "other_table").ok().unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_create_table_with_defaults() {
+        let service = get_service("defaults").await;
+
+        let mut schema = test_schema();
+        schema.columns[0].default = Some(ColumnDefault::Literal(json!("it's me")));
+        schema.columns[1].default = Some(ColumnDefault::Literal(json!(18)));
+        assert!(service.create_table(schema).await.is_ok());
+
+        let inserted = service.insert_row("test_table", Row::new()).await.ok().unwrap().row;
+        assert_eq!(inserted["name"], json!("it's me"));
+        assert_eq!(inserted["age"], json!(18));
+
+        let mut schema = test_schema();
+        schema.name = "other_table".parse().unwrap();
+        schema.columns[1].default = Some(ColumnDefault::Literal(json!("hello")));
+        match service.create_table(schema).await {
+            Err(Error::Validation(message)) => assert!(message.contains("age")),
+            _ => panic!("expected a validation error"),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_constraint_errors() {
+        let service = get_service("constraint_errors").await;
+
+        let mut schema = test_schema();
+        schema.columns[0].unique = Some(true);
+        assert!(service.create_table(schema.clone()).await.is_ok());
+        assert!(matches!(service.create_table(schema).await, Err(Error::AlreadyExists(_))));
+
+        assert!(service.insert_row("test_table", row(json!({"name": "bob", "age": 30}))).await.is_ok());
+        let duplicate = service.insert_row("test_table", row(json!({"name": "bob", "age": 31}))).await;
+        assert!(matches!(duplicate, Err(Error::UniqueViolation(_))));
+
+        let missing = service.insert_row("test_table", row(json!({"name": "al"}))).await;
+        assert!(matches!(missing, Err(Error::InvalidRow(_))));
+    }
+
+    #[actix_web::test]
+    async fn test_pool_exhaustion_is_an_error() {
+        let config = PoolConfig {
+            max_size: 1,
+            min_idle: None,
+            connection_timeout: Duration::from_millis(50),
+            ..PoolConfig::default()
+        };
+        let service = CrudService::new(get_pool_with("pool_exhaustion", &config));
+        assert!(service.init().await.is_ok());
+
+        let _held = service.connection().ok().unwrap();
+        assert!(matches!(service.list_tables().await, Err(Error::PoolError(_))));
+    }
+
+    #[test]
+    fn test_checkout_enables_foreign_keys() {
+        let config = PoolConfig { max_size: 1, ..PoolConfig::default() };
+        let service = CrudService::new(get_pool_with("checkout_foreign_keys", &config));
+
+        service.connection().ok().unwrap().batch_execute("PRAGMA foreign_keys = OFF").unwrap();
+        let enabled: i32 = diesel::select(diesel::dsl::sql::<diesel::sql_types::Integer>("(SELECT foreign_keys FROM pragma_foreign_keys)"))
+            .get_result(&mut service.connection().ok().unwrap())
+            .unwrap();
+        assert_eq!(enabled, 1);
+    }
+
+    #[actix_web::test]
+    async fn test_pending_limit_applies_backpressure() {
+        let service = get_service("backpressure").await.with_max_pending(1);
+
+        let _held = service.pending.clone().try_acquire_owned().unwrap();
+        assert!(matches!(service.list_tables().await, Err(Error::Busy(_))));
+    }
+
+    #[actix_web::test]
+    async fn test_drop_table_safeguards() {
+        let service = get_service("drop_safeguards").await;
+
+        let mut conn = service.connection().ok().unwrap();
+        diesel::sql_query("CREATE TABLE legacy (code TEXT)").execute(&mut conn).unwrap();
+
+        assert!(matches!(service.drop_table("legacy").await, Err(Error::Forbidden(_))));
+        assert!(matches!(service.drop_table("_fastfood_tables").await, Err(Error::Forbidden(_))));
+        assert!(matches!(service.drop_table("sqlite_master").await, Err(Error::Forbidden(_))));
+        assert!(matches!(service.drop_table("missing").await, Err(Error::NotFound(_))));
+        assert!(introspect::table_exists(&mut conn, "legacy").unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_list_and_describe_tables() {
+        let service = get_service("describe_tables").await;
+        assert!(service.create_table(test_schema()).await.is_ok());
+
+        let mut conn = service.connection().ok().unwrap();
+        diesel::sql_query("CREATE TABLE legacy (code VARCHAR(10) PRIMARY KEY, price DOUBLE NOT NULL)")
+            .execute(&mut conn)
+            .unwrap();
+
+        let tables = service.list_tables().await.ok().unwrap();
+        let names: Vec<&str> = tables.iter().map(|table| table.schema.name.as_str()).collect();
+        assert_eq!(names, vec!["legacy", "test_table"]);
+
+        let managed = service.describe_table("test_table").await.ok().unwrap();
+        assert!(!managed.unmanaged);
+        let columns: Vec<&str> = managed.schema.columns.iter().map(|column| column.name.as_str()).collect();
+        assert_eq!(columns, vec!["id", "name", "age", "created_at", "updated_at"]);
+        assert_eq!(serde_json::to_value(&managed).unwrap()["options"]["id"], json!("autoincrement"));
+
+        // fastfood manages none of legacy's columns, so it claims no options
+        let legacy = service.describe_table("legacy").await.ok().unwrap();
+        assert!(legacy.unmanaged);
+        assert!(serde_json::to_value(&legacy).unwrap().get("options").is_none());
+        assert!(matches!(legacy.schema.columns[1].data_type, DataType::Float));
+        assert_eq!(legacy.schema.columns[1].not_null, Some(true));
+
+        assert!(service.describe_table("LEGACY").await.ok().unwrap().unmanaged);
+        for system in ["_fastfood_tables", "_FASTFOOD_TABLES", "SQLITE_SEQUENCE"] {
+            assert!(matches!(service.describe_table(system).await, Err(Error::NotFound(_))));
+        }
+        assert!(matches!(service.describe_table("missing").await, Err(Error::NotFound(_))));
+    }
+
+    #[actix_web::test]
+    async fn test_table_constraints() {
+        let service = get_service("table_constraints").await;
+        let table = |value: serde_json::Value| -> TableSchema { serde_json::from_value(value).unwrap() };
+        let columns = json!([
+            {"name": "tenant_id", "type": "integer", "not_null": true},
+            {"name": "code", "type": "text", "not_null": true},
+            {"name": "email", "type": "text"},
+        ]);
+
+        let nullable_key = table(json!({"name": "accounts", "columns": [{"name": "code", "type": "text"}], "primary_key": ["code"]}));
+        assert!(matches!(service.create_table(nullable_key).await, Err(Error::Validation(_))));
+        let missing = table(json!({"name": "accounts", "columns": columns, "unique": [["tenant_id", "phone"]]}));
+        assert!(matches!(service.create_table(missing).await, Err(Error::Validation(_))));
+
+        let accounts = table(json!({"name": "accounts", "columns": columns,
+                                    "primary_key": ["tenant_id", "code"], "unique": [["tenant_id", "email"]]}));
+        let created = service.create_table(accounts.clone()).await.ok().unwrap();
+        assert_eq!(created.columns[0].primary_key, Some(false));
+
+        let first = service.insert_row("accounts", row(json!({"tenant_id": 1, "code": "a", "email": "x"}))).await.ok().unwrap().row;
+        let second = service.insert_row("accounts", row(json!({"tenant_id": 2, "code": "a", "email": "x"}))).await.ok().unwrap().row;
+        assert_eq!((first["id"].clone(), second["id"].clone()), (json!(1), json!(2)));
+        assert!(matches!(service.insert_row("accounts", row(json!({"tenant_id": 1, "code": "a"}))).await,
+                         Err(Error::UniqueViolation(_))));
+        assert!(matches!(service.insert_row("accounts", row(json!({"tenant_id": 1, "code": "b", "email": "x"}))).await,
+                         Err(Error::UniqueViolation(_))));
+        assert!(service.update_row("accounts", "2", row(json!({"email": "y"})), None).await.is_ok());
+
+        // ids are never handed out twice, even once the last row is deleted
+        assert!(service.delete_row("accounts", "2", None).await.is_ok());
+        let third = service.insert_row("accounts", row(json!({"tenant_id": 3, "code": "a"}))).await.unwrap().row;
+        assert_eq!(third["id"], json!(3));
+
+        // renames follow into the constraints, which survive a rebuild
+        let alter: AlterTable = serde_json::from_value(json!({"operations": [
+            {"op": "change_column", "name": "code", "column": {"name": "handle", "type": "text", "not_null": true}},
+        ]})).unwrap();
+        let altered = service.alter_table("accounts", alter).await.ok().unwrap();
+        assert_eq!(altered.primary_key.unwrap()[1].as_str(), "handle");
+        assert!(matches!(service.insert_row("accounts", row(json!({"tenant_id": 1, "handle": "a"}))).await,
+                         Err(Error::UniqueViolation(_))));
+        let alter: AlterTable = serde_json::from_value(json!({"operations": [{"op": "drop_column", "name": "email"}]})).unwrap();
+        assert!(matches!(service.alter_table("accounts", alter).await, Err(Error::Validation(_))));
+
+        let document: SchemaDocument = serde_json::from_value(json!({"tables": [accounts]})).unwrap();
+        assert!(matches!(service.apply_schema(document, ApplyOptions::default()).await, Err(Error::Validation(_))));
+
+        // nor once the table is dropped and the drop rolled back
+        assert!(service.delete_row("accounts", "3", None).await.is_ok());
+        assert!(service.drop_table("accounts").await.is_ok());
+        assert!(service.rollback_migration(false).await.is_ok());
+        let fourth = service.insert_row("accounts", row(json!({"tenant_id": 4, "handle": "a"}))).await.unwrap().row;
+        assert_eq!(fourth["id"], json!(4));
+
+        // introspection reports the keys of tables fastfood did not create
+        let mut conn = service.connection().ok().unwrap();
+        diesel::sql_query("CREATE TABLE legacy (a TEXT, b TEXT, c TEXT UNIQUE, PRIMARY KEY (b, a), UNIQUE (c, a))")
+            .execute(&mut conn)
+            .unwrap();
+        let legacy = service.describe_table("legacy").await.ok().unwrap().schema;
+        let names = |columns: &[Identifier]| columns.iter().map(|name| name.as_str().to_string()).collect::<Vec<_>>();
+        assert_eq!(names(legacy.primary_key.as_ref().unwrap()), vec!["b", "a"]);
+        assert_eq!(legacy.unique.iter().map(|unique| names(unique)).collect::<Vec<_>>(), vec![vec!["c", "a"]]);
+        assert_eq!(legacy.columns[2].unique, Some(true));
+        assert_eq!(legacy.columns[0].primary_key, Some(false));
+    }
+
+    #[actix_web::test]
+    async fn test_table_options() {
+        let pool = get_pool("table_options");
+        let service = CrudService::new(pool.clone());
+        assert!(service.init().await.is_ok());
+        let table = |value: serde_json::Value| -> TableSchema { serde_json::from_value(value).unwrap() };
+
+        let clash = table(json!({"name": "tokens", "columns": [{"name": "modified", "type": "text"}],
+                                 "options": {"updated_at": "modified"}}));
+        assert!(matches!(service.create_table(clash).await, Err(Error::Validation(_))));
+
+        let tokens = table(json!({"name": "tokens", "columns": [{"name": "name", "type": "text"}],
+                                  "options": {"id": "ulid", "created_at": null, "updated_at": "modified"}}));
+        let created = service.create_table(tokens).await.ok().unwrap();
+        let columns: Vec<&str> = created.columns.iter().map(|column| column.name.as_str()).collect();
+        assert_eq!(columns, vec!["id", "name", "modified"]);
+
+        let inserted = service.insert_row("tokens", row(json!({"name": "a"}))).await.ok().unwrap().row;
+        let id = inserted["id"].as_str().unwrap().to_string();
+        assert_eq!(id.len(), 26);
+        assert!(!inserted.contains_key("created_at"));
+        assert!(service.get_row("tokens", &id.to_lowercase()).await.is_ok());
+        assert!(matches!(service.get_row("tokens", "1").await, Err(Error::NotFound(_))));
+        assert!(matches!(service.update_row("tokens", &id, row(json!({"modified": "2024-01-01T00:00:00Z"})), None).await,
+                         Err(Error::InvalidRow(_))));
+
+        // text ids can be referenced, and survive a rebuild
+        assert!(service.create_table(table(json!({"name": "uses", "columns": [
+            {"name": "token_id", "type": "text", "references": {"table": "tokens"}},
+        ], "options": {"id": "uuid_v4", "created_at": null, "updated_at": null}}))).await.is_ok());
+        let used = service.insert_row("uses", row(json!({"token_id": id}))).await.ok().unwrap().row;
+        assert_eq!(used.len(), 2);
+
+        let alter: AlterTable = serde_json::from_value(json!({"operations": [
+            {"op": "change_column", "name": "name", "column": {"name": "name", "type": "text", "not_null": true, "default": ""}},
+        ]})).unwrap();
+        assert!(service.alter_table("tokens", alter).await.is_ok());
+        assert_eq!(service.get_row("tokens", &id).await.ok().unwrap().row["name"], json!("a"));
+        assert!(service.delete_row("uses", used["id"].as_str().unwrap(), None).await.is_ok());
+
+        // only tables keeping updated_at get the trigger
+        let mut conn = pool.get().unwrap();
+        let triggers: i64 = diesel::select(diesel::dsl::sql::<BigInt>(
+            "(SELECT count(*) FROM sqlite_master WHERE type = 'trigger')"))
+            .get_result(&mut conn)
+            .unwrap();
+        assert_eq!(triggers, 1);
+    }
+
+    #[actix_web::test]
+    async fn test_row_versions() {
+        let service = get_service("row_versions").await;
+        let mut schema = test_schema();
+        schema.options = Some(TableOptions { version: Some(Identifier::managed("version")), ..TableOptions::default() });
+        assert!(service.create_table(schema).await.is_ok());
+
+        let inserted = service.insert_row("test_table", row(json!({"name": "bob", "age": 30}))).await.ok().unwrap();
+        assert_eq!((inserted.row["version"].clone(), inserted.etag.as_deref()), (json!(1), Some("\"1\"")));
+        let id = inserted.row["id"].to_string();
+        assert_eq!(service.get_row("test_table", &id).await.ok().unwrap().etag.as_deref(), Some("\"1\""));
+        assert!(matches!(service.update_row("test_table", &id, row(json!({"version": 7})), None).await, Err(Error::InvalidRow(_))));
+
+        // both admins read version 1; the second write must not go through
+        let updated = service.update_row("test_table", &id, row(json!({"age": 31})), Some(IfMatch::Versions(vec![1]))).await.ok().unwrap();
+        assert_eq!((updated.row["version"].clone(), updated.etag.as_deref()), (json!(2), Some("\"2\"")));
+        assert!(matches!(service.update_row("test_table", &id, row(json!({"age": 32})), Some(IfMatch::Versions(vec![1]))).await,
+                         Err(Error::PreconditionFailed(_))));
+        assert!(matches!(service.update_row("test_table", &id, row(json!({})), Some(IfMatch::Versions(vec![1]))).await,
+                         Err(Error::PreconditionFailed(_))));
+        assert!(matches!(service.delete_row("test_table", &id, Some(IfMatch::Versions(vec![1]))).await,
+                         Err(Error::PreconditionFailed(_))));
+        assert_eq!(service.get_row("test_table", &id).await.ok().unwrap().row["age"], json!(31));
+
+        let updated = service.update_row("test_table", &id, row(json!({"age": 32})), Some(IfMatch::Any)).await.ok().unwrap();
+        assert_eq!(updated.row["version"], json!(3));
+        assert!(matches!(service.update_row("test_table", "99", row(json!({"age": 1})), Some(IfMatch::Any)).await,
+                         Err(Error::NotFound(_))));
+        assert!(service.delete_row("test_table", &id, Some(IfMatch::Versions(vec![2, 3]))).await.is_ok());
+        assert!(matches!(service.delete_row("test_table", &id, Some(IfMatch::Any)).await, Err(Error::NotFound(_))));
+
+        // rows of tables without versions match no entity tag
+        let mut plain = test_schema();
+        plain.name = Identifier::managed("plain");
+        assert!(service.create_table(plain).await.is_ok());
+        let id = service.insert_row("plain", row(json!({"name": "ann", "age": 40}))).await.ok().unwrap().row["id"].to_string();
+        assert_eq!(service.get_row("plain", &id).await.ok().unwrap().etag, None);
+        assert!(matches!(service.delete_row("plain", &id, Some(IfMatch::Versions(vec![1]))).await,
+                         Err(Error::PreconditionFailed(_))));
+        assert!(service.delete_row("plain", &id, Some(IfMatch::Any)).await.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn test_row_crud() {
+        let service = get_service("row_crud").await;
+        assert!(service.create_table(test_schema()).await.is_ok());
+
+        let inserted = service.insert_row("test_table", row(json!({"name": "bob", "age": 30}))).await.ok().unwrap().row;
+        let id = inserted["id"].as_i64().unwrap();
+        assert_eq!(inserted["name"], json!("bob"));
+        assert!(inserted.contains_key("created_at"));
+
+        let updated = service.update_row("test_table", &id.to_string(), row(json!({"age": 31})), None).await.ok().unwrap().row;
+        assert_eq!(updated["name"], json!("bob"));
+        assert_eq!(updated["age"], json!(31));
+
+        let fetched = service.get_row("test_table", &id.to_string()).await.ok().unwrap().row;
+        assert_eq!(fetched, updated);
+
+        assert!(service.delete_row("test_table", &id.to_string(), None).await.is_ok());
+        assert!(matches!(service.get_row("test_table", &id.to_string()).await, Err(Error::NotFound(_))));
+        assert!(matches!(service.delete_row("test_table", &id.to_string(), None).await, Err(Error::NotFound(_))));
+    }
+
+    #[actix_web::test]
+    async fn test_row_payloads_are_validated() {
+        let service = get_service("validate_rows").await;
+        assert!(service.create_table(test_schema()).await.is_ok());
+
+        let result = service.insert_row("test_table", row(json!({"id": 9, "age": "thirty"}))).await;
+        match result {
+            Err(Error::InvalidRow(errors)) => {
+                let fields: Vec<&str> = errors.iter().map(|error| error.field.as_str()).collect();
+                assert_eq!(fields, vec!["age", "id", "name"]);
+            }
+            _ => panic!("expected field errors"),
+        }
+
+        let id = service.insert_row("test_table", row(json!({"name": "bob", "age": 30}))).await.ok().unwrap().row["id"].as_i64().unwrap();
+        let result = service.update_row("test_table", &id.to_string(), row(json!({"updated_at": "2024-01-01T00:00:00Z", "name": null})), None).await;
+        assert!(matches!(result, Err(Error::InvalidRow(errors)) if errors.len() == 2));
+    }
+
+    #[actix_web::test]
+    async fn test_column_rules() {
+        let pool = get_pool("column_rules");
+        let service = CrudService::new(pool.clone());
+        assert!(service.init().await.is_ok());
+
+        let schema: TableSchema = serde_json::from_value(json!({"name": "test_table", "columns": [
+            {"name": "name", "type": "text", "min_length": 2, "pattern": "[a-z]+"},
+            {"name": "age", "type": "integer", "min": 0, "max": 150},
+            {"name": "status", "type": "text", "one_of": ["new", "done"]},
+        ]})).unwrap();
+        assert!(service.create_table(schema).await.is_ok());
+        assert!(service.insert_row("test_table", row(json!({"name": "bob", "age": 30, "status": "new"}))).await.is_ok());
+
+        let result = service.insert_row("test_table", row(json!({"name": "Bo", "age": -1, "status": "lost"}))).await;
+        match result {
+            Err(Error::InvalidRow(errors)) => {
+                let messages: Vec<&str> = errors.iter().map(|error| error.message.as_str()).collect();
+                assert_eq!(messages, vec!["column age must be at least 0",
+                                          "column name must match the pattern [a-z]+",
+                                          "column status must be one of \"new\", \"done\""]);
+            }
+            _ => panic!("expected field errors"),
+        }
+
+        // SQLite holds other writers to the rules it can check
+        let mut conn = pool.get().unwrap();
+        assert!(diesel::sql_query("INSERT INTO test_table (name, age) VALUES ('al', 200)").execute(&mut conn).is_err());
+        assert!(diesel::sql_query("INSERT INTO test_table (name, age) VALUES ('AL', 20)").execute(&mut conn).is_ok());
+
+        // tightening a rule reports the rows breaking it
+        let alter: AlterTable = serde_json::from_value(json!({"dry_run": true, "operations": [
+            {"op": "change_column", "name": "age", "column": {"name": "age", "type": "integer", "max": 25}},
+        ]})).unwrap();
+        let report = service.preview_alter("test_table", alter).await.ok().unwrap();
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].value, json!(30));
+    }
+
+    #[actix_web::test]
+    async fn test_insert_row_rejects_unknown_column() {
+        let service = get_service("unknown_column").await;
+        assert!(service.create_table(test_schema()).await.is_ok());
+
+        let result = service.insert_row("test_table", row(json!({"name": "bob", "age": 30, "email": "x"}))).await;
+        assert!(matches!(result, Err(Error::InvalidRow(_))));
+
+        let result = service.insert_row("missing_table", row(json!({"name": "bob"}))).await;
+        assert!(matches!(result, Err(Error::NotFound(_))));
+    }
+
+    #[actix_web::test]
+    async fn test_list_rows_with_filters() {
+        let service = get_service("list_rows").await;
+        assert!(service.create_table(test_schema()).await.is_ok());
+
+        for (name, age) in [("alice", 25), ("bob", 35), ("bobby", 45), ("carol", 55)] {
+            assert!(service.insert_row("test_table", row(json!({"name": name, "age": age}))).await.is_ok());
+        }
+
+        let params = |query: &[(&str, &str)]| -> Vec<(String, String)> {
+            query.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+        };
+        let names = |page: RowPage| -> Vec<String> {
+            page.rows.iter().map(|row| row["name"].as_str().unwrap().to_string()).collect()
+        };
+
+        let page = service.list_rows("test_table", params(&[])).await.ok().unwrap();
+        assert_eq!(page.rows.len(), 4);
+
+        let page = service.list_rows("test_table", params(&[("age", "gt.30"), ("name", "like.*bob*")])).await.ok().unwrap();
+        assert_eq!(names(page), vec!["bob", "bobby"]);
+
+        let page = service.list_rows("test_table", params(&[("or", "(age.lt.30,name.eq.carol)")])).await.ok().unwrap();
+        assert_eq!(names(page), vec!["alice", "carol"]);
+
+        let page = service.list_rows("test_table", params(&[("name", "ilike.BOB*"), ("limit", "1")])).await.ok().unwrap();
+        assert_eq!(names(page), vec!["bob"]);
+
+        let result = service.list_rows("test_table", params(&[("age", "like.3*")])).await;
+        assert!(matches!(result, Err(Error::Validation(_))));
+        let result = service.list_rows("test_table", params(&[("email", "eq.x")])).await;
+        assert!(matches!(result, Err(Error::Validation(_))));
+        let result = service.list_rows("test_table", params(&[("limit", "0")])).await;
+        assert!(matches!(result, Err(Error::Validation(_))));
+
+        // columns named like listing parameters could never be filtered by
+        let mut schema = test_schema();
+        schema.name = Identifier::new("orders").unwrap();
+        schema.columns[0].name = Identifier::new("Order").unwrap();
+        assert!(matches!(service.create_table(schema).await, Err(Error::Validation(_))));
+        let alter: AlterTable = serde_json::from_value(json!({"operations": [{"op": "rename_column", "from": "name", "to": "or"}]})).unwrap();
+        assert!(matches!(service.alter_table("test_table", alter).await, Err(Error::Validation(_))));
+    }
+
+    #[actix_web::test]
+    async fn test_list_rows_paginates() {
+        let service = get_service("paginate").await.with_page_size(2, 3);
+        let schema: TableSchema = serde_json::from_value(json!({
+            "name": "test_table",
+            "columns": [{"name": "name", "type": "text"}, {"name": "age", "type": "integer"}]
+        })).unwrap();
+        assert!(service.create_table(schema).await.is_ok());
+
+        for (name, age) in [("a", Some(30)), ("b", None), ("c", Some(20)), ("d", Some(30)), ("e", None)] {
+            assert!(service.insert_row("test_table", row(json!({"name": name, "age": age}))).await.is_ok());
+        }
+
+        // walk every page ordered by a column with ties and NULLs
+        let mut names = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let mut params = vec![
+                ("order".to_string(), "age.desc".to_string()),
+                ("select".to_string(), "name".to_string()),
+                ("count".to_string(), "exact".to_string()),
+            ];
+            if let Some(cursor) = cursor {
+                params.push(("cursor".to_string(), cursor));
+            }
+
+            let page = service.list_rows("test_table", params).await.ok().unwrap();
+            assert_eq!(page.total_count, Some(5));
+            assert!(page.rows.len() <= 2);
+            for row in &page.rows {
+                assert_eq!(row.len(), 1);
+                names.push(row["name"].as_str().unwrap().to_string());
+            }
+
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        assert_eq!(names, vec!["a", "d", "c", "b", "e"]);
+
+        let page = service.list_rows("test_table", vec![("count".to_string(), "estimated".to_string())]).await.ok().unwrap();
+        assert_eq!(page.total_count, Some(5));
+
+        let result = service.list_rows("test_table", vec![("limit".to_string(), "4".to_string())]).await;
+        assert!(matches!(result, Err(Error::Validation(_))));
+    }
+
+    #[actix_web::test]
+    async fn test_rows_are_encoded_by_data_type() {
+        let service = get_service("row_codec").await;
+        let schema: TableSchema = serde_json::from_value(json!({
+            "name": "events",
+            "columns": [
+                {"name": "public", "type": "boolean"},
+                {"name": "starts", "type": "timestamp"},
+                {"name": "price", "type": "float"},
+            ]
+        })).unwrap();
+        assert!(service.create_table(schema).await.is_ok());
+
+        let body = row(json!({"public": true, "starts": "2024-05-01T20:30:00+02:00", "price": 12}));
+        let inserted = service.insert_row("events", body).await.ok().unwrap().row;
+        assert_eq!(inserted["public"], json!(true));
+        assert_eq!(inserted["starts"], json!("2024-05-01T18:30:00Z"));
+        assert_eq!(inserted["price"], json!(12.0));
+        assert!(inserted["created_at"].as_str().unwrap().ends_with('Z'));
+
+        let page = service.list_rows("events", vec![("starts".to_string(), "eq.2024-05-01T18:30:00Z".to_string())]).await.ok().unwrap();
+        assert_eq!(page.rows.len(), 1);
+
+        let result = service.insert_row("events", row(json!({"public": "yes"}))).await;
+        assert!(matches!(result, Err(Error::InvalidRow(_))));
+    }
+
+    #[actix_web::test]
+    async fn test_alter_table() {
+        let service = get_service("alter_table").await;
+        assert!(service.create_table(test_schema()).await.is_ok());
+        let id = service.insert_row("test_table", row(json!({"name": "bob", "age": 30}))).await.ok().unwrap().row["id"].as_i64().unwrap();
+
+        let alter: AlterTable = serde_json::from_value(json!({"operations": [
+            {"op": "add_column", "column": {"name": "email", "type": "text", "not_null": true, "default": "none"}},
+            {"op": "rename_column", "from": "age", "to": "years"},
+            {"op": "drop_column", "name": "name"},
+        ]})).unwrap();
+        let schema = service.alter_table("test_table", alter).await.ok().unwrap();
+        let columns: Vec<&str> = schema.columns.iter().map(|column| column.name.as_str()).collect();
+        assert_eq!(columns, vec!["id", "years", "email", "created_at", "updated_at"]);
+
+        let fetched = service.get_row("test_table", &id.to_string()).await.ok().unwrap().row;
+        assert_eq!(fetched["years"], json!(30));
+        assert_eq!(fetched["email"], json!("none"));
+        assert!(!fetched.contains_key("name"));
+
+        // the stored schema follows, and updated_at is still maintained
+        let described = service.describe_table("test_table").await.ok().unwrap();
+        assert_eq!(described.schema.columns.len(), 5);
+        assert!(service.update_row("test_table", &id.to_string(), row(json!({"years": 31})), None).await.is_ok());
+
+        // a failing operation rolls back the ones before it
+        let alter: AlterTable = serde_json::from_value(json!({"operations": [
+            {"op": "drop_column", "name": "email"},
+            {"op": "drop_column", "name": "updated_at"},
+        ]})).unwrap();
+        assert!(matches!(service.alter_table("test_table", alter).await, Err(Error::Forbidden(_))));
+        assert!(service.get_row("test_table", &id.to_string()).await.ok().unwrap().row.contains_key("email"));
+
+        let alter: AlterTable = serde_json::from_value(json!({"operations": [{"op": "drop_column", "name": "email"}]})).unwrap();
+        assert!(matches!(service.alter_table("missing", alter).await, Err(Error::NotFound(_))));
+    }
+
+    #[actix_web::test]
+    async fn test_change_column_rebuilds_table() {
+        let pool = get_pool("rebuild_table");
+        let service = CrudService::new(pool.clone());
+        assert!(service.init().await.is_ok());
+
+        let schema: TableSchema = serde_json::from_value(json!({
+            "name": "test_table",
+            "columns": [{"name": "name", "type": "text"}, {"name": "code", "type": "text"}]
+        })).unwrap();
+        assert!(service.create_table(schema).await.is_ok());
+        for (name, code) in [("bob", "12"), ("al", "x1")] {
+            assert!(service.insert_row("test_table", row(json!({"name": name, "code": code}))).await.is_ok());
+        }
+
+        let mut conn = pool.get().unwrap();
+        diesel::sql_query("CREATE INDEX test_table_name ON test_table (name)").execute(&mut conn).unwrap();
+
+        let change = |column: Value, dry_run: bool| -> AlterTable {
+            serde_json::from_value(json!({
+                "operations": [{"op": "change_column", "name": "code", "column": column}],
+                "dry_run": dry_run,
+            })).unwrap()
+        };
+        let to_integer = json!({"name": "code", "type": "integer", "not_null": true});
+
+        // the dry run lists the failing row and changes nothing
+        let report = service.preview_alter("test_table", change(to_integer.clone(), true)).await.ok().unwrap();
+        assert!(report.rebuild);
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].id, json!(2));
+        assert_eq!(report.failures[0].value, json!("x1"));
+
+        assert!(matches!(service.alter_table("test_table", change(to_integer.clone(), false)).await, Err(Error::Validation(_))));
+        assert_eq!(service.get_row("test_table", "2").await.ok().unwrap().row["code"], json!("x1"));
+
+        assert!(service.update_row("test_table", "2", row(json!({"code": "7"})), None).await.is_ok());
+        let schema = service.alter_table("test_table", change(to_integer, false)).await.ok().unwrap();
+        assert!(matches!(schema.column("code").unwrap().data_type, DataType::Integer));
+
+        let fetched = service.get_row("test_table", "1").await.ok().unwrap().row;
+        assert_eq!(fetched["code"], json!(12));
+        assert_eq!(fetched["name"], json!("bob"));
+
+        // the trigger and index were recreated and the new constraint holds
+        let objects: i64 = diesel::select(diesel::dsl::sql::<BigInt>(
+            "(SELECT count(*) FROM sqlite_master WHERE name IN ('update_test_table_updated_at', 'test_table_name'))"))
+            .get_result(&mut conn)
+            .unwrap();
+        assert_eq!(objects, 2);
+        assert!(matches!(service.update_row("test_table", "1", row(json!({"code": null})), None).await, Err(Error::InvalidRow(_))));
+        assert!(matches!(service.insert_row("test_table", row(json!({"name": "cy"}))).await, Err(Error::InvalidRow(_))));
+    }
+
+    #[actix_web::test]
+    async fn test_migrations_and_rollback() {
+        let service = get_service("migrations").await;
+        assert!(service.create_table(test_schema()).await.is_ok());
+        let id = service.insert_row("test_table", row(json!({"name": "bob", "age": 30}))).await.ok().unwrap().row["id"].as_i64().unwrap();
+
+        let alter: AlterTable = serde_json::from_value(json!({"operations": [
+            {"op": "rename_column", "from": "age", "to": "years"},
+            {"op": "add_column", "column": {"name": "email", "type": "text"}},
+        ]})).unwrap();
+        assert!(service.alter_table("test_table", alter).await.is_ok());
+
+        let history = service.list_migrations().await.ok().unwrap();
+        let kinds: Vec<MigrationKind> = history.iter().map(|migration| migration.change.kind).collect();
+        assert_eq!(kinds, vec![MigrationKind::Create, MigrationKind::Alter]);
+        assert_eq!(history[1].change.forward.len(), 2);
+
+        let first = service.schema_at("test_table", history[0].version).await.ok().unwrap();
+        assert!(first.column("age").is_some());
+        assert!(first.column("years").is_none());
+
+        // undoing the alter restores the column and keeps the data, once
+        // dropping the added column is allowed
+        assert!(matches!(service.rollback_migration(false).await, Err(Error::Validation(_))));
+        let undone = service.rollback_migration(true).await.ok().unwrap();
+        assert_eq!(undone.migration.version, history[1].version);
+        assert!(undone.migration.rolled_back_at.is_some());
+        assert!(undone.warning.is_none());
+        let fetched = service.get_row("test_table", &id.to_string()).await.ok().unwrap().row;
+        assert_eq!(fetched["age"], json!(30));
+        assert!(!fetched.contains_key("email"));
+        assert!(service.describe_table("test_table").await.ok().unwrap().schema.column("age").is_some());
+        assert!(service.update_row("test_table", &id.to_string(), row(json!({"age": 31})), None).await.is_ok());
+
+        // undoing a drop brings the table back, empty, and says so
+        assert!(service.drop_table("test_table").await.is_ok());
+        assert!(matches!(service.schema_at("test_table", 100).await, Err(Error::NotFound(_))));
+        assert!(service.rollback_migration(false).await.ok().unwrap().warning.is_some());
+        let page = service.list_rows("test_table", vec![]).await.ok().unwrap();
+        assert!(page.rows.is_empty());
+
+        // and undoing the create removes it, if allowed to
+        assert!(matches!(service.rollback_migration(false).await, Err(Error::Validation(_))));
+        assert!(service.describe_table("test_table").await.is_ok());
+        assert!(service.rollback_migration(true).await.is_ok());
+        assert!(matches!(service.describe_table("test_table").await, Err(Error::NotFound(_))));
+        assert!(matches!(service.rollback_migration(true).await, Err(Error::NotFound(_))));
+        assert_eq!(service.list_migrations().await.ok().unwrap().len(), 3);
+    }
+
+    #[actix_web::test]
+    async fn test_rollback_checks_conversions() {
+        let service = get_service("rollback_conversions").await;
+        assert!(service.create_table(test_schema()).await.is_ok());
+
+        let alter: AlterTable = serde_json::from_value(json!({"operations": [
+            {"op": "change_column", "name": "age", "column": {"name": "years", "type": "text"}},
+        ]})).unwrap();
+        assert!(service.alter_table("test_table", alter).await.is_ok());
+        let id = service.insert_row("test_table", row(json!({"name": "bob", "years": "x1"}))).await.unwrap().row["id"].to_string();
+
+        // "x1" is no integer, so the renamed column cannot go back to age
+        assert!(matches!(service.rollback_migration(false).await, Err(Error::Validation(_))));
+        assert_eq!(service.get_row("test_table", &id).await.unwrap().row["years"], json!("x1"));
+
+        assert!(service.update_row("test_table", &id, row(json!({"years": "41"})), None).await.is_ok());
+        assert!(service.rollback_migration(false).await.is_ok());
+        assert_eq!(service.get_row("test_table", &id).await.unwrap().row["age"], json!(41));
+    }
+
+    #[actix_web::test]
+    async fn test_indexes() {
+        let pool = get_pool("indexes");
+        let service = CrudService::new(pool.clone());
+        assert!(service.init().await.is_ok());
+        assert!(service.create_table(test_schema()).await.is_ok());
+
+        let index: IndexSchema = serde_json::from_value(json!({
+            "name": "test_table_adult_name", "columns": [{"expr": "lower", "column": "name"}], "unique": true, "where": "age=gte.18"
+        })).unwrap();
+        assert!(service.create_index("test_table", index.clone()).await.is_ok());
+        assert!(matches!(service.create_index("test_table", index).await, Err(Error::Validation(_))));
+
+        // the index only covers adults, compared case-insensitively
+        assert!(service.insert_row("test_table", row(json!({"name": "Bob", "age": 30}))).await.is_ok());
+        assert!(service.insert_row("test_table", row(json!({"name": "bob", "age": 12}))).await.is_ok());
+        assert!(matches!(service.insert_row("test_table", row(json!({"name": "BOB", "age": 40}))).await,
+                         Err(Error::UniqueViolation(_))));
+
+        // it survives a rebuild
+        let alter: AlterTable = serde_json::from_value(json!({"operations": [
+            {"op": "change_column", "name": "name", "column": {"name": "name", "type": "text"}},
+        ]})).unwrap();
+        assert_eq!(service.alter_table("test_table", alter).await.ok().unwrap().indexes.len(), 1);
+        assert!(matches!(service.insert_row("test_table", row(json!({"name": "BOB", "age": 40}))).await,
+                         Err(Error::UniqueViolation(_))));
+
+        let count_indexes = || -> i64 {
+            let mut conn = pool.get().unwrap();
+            diesel::select(diesel::dsl::sql::<BigInt>(
+                "(SELECT count(*) FROM sqlite_master WHERE type = 'index' AND name = 'test_table_adult_name')"))
+                .get_result(&mut conn)
+                .unwrap()
+        };
+
+        assert!(service.drop_index("test_table", "test_table_adult_name").await.is_ok());
+        assert!(service.list_indexes("test_table").await.ok().unwrap().is_empty());
+        assert_eq!(count_indexes(), 0);
+        assert!(matches!(service.drop_index("test_table", "test_table_adult_name").await, Err(Error::NotFound(_))));
+
+        // rolling back the drop recreates it
+        assert!(service.rollback_migration(false).await.is_ok());
+        assert_eq!(service.list_indexes("test_table").await.ok().unwrap().len(), 1);
+        assert_eq!(count_indexes(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_apply_schema() {
+        let service = get_service("apply_schema").await;
+        assert!(service.create_table(test_schema()).await.is_ok());
+        let id = service.insert_row("test_table", row(json!({"name": "bob", "age": 30}))).await.ok().unwrap().row["id"].as_i64().unwrap();
+
+        let document = |value: serde_json::Value| -> SchemaDocument { serde_json::from_value(value).unwrap() };
+        let desired = json!({"tables": [
+            {"name": "test_table", "columns": [
+                {"name": "name", "type": "text", "not_null": true},
+                {"name": "email", "type": "text"},
+            ]},
+            {"name": "orders", "columns": [{"name": "total", "type": "float"}]},
+        ]});
+
+        // dropping age needs the explicit flag, and nothing is applied without it
+        let preview = service.apply_schema(document(desired.clone()), ApplyOptions { dry_run: true, allow_destructive: false }).await.ok().unwrap();
+        assert_eq!(preview.steps.len(), 2);
+        assert!(preview.destructive && !preview.applied);
+        assert!(matches!(service.apply_schema(document(desired.clone()), ApplyOptions::default()).await, Err(Error::Forbidden(_))));
+        assert!(matches!(service.describe_table("orders").await, Err(Error::NotFound(_))));
+
+        let options = ApplyOptions { dry_run: false, allow_destructive: true };
+        let applied = service.apply_schema(document(desired.clone()), options).await.ok().unwrap();
+        assert!(applied.applied);
+        assert!(service.describe_table("orders").await.is_ok());
+        let fetched = service.get_row("test_table", &id.to_string()).await.ok().unwrap().row;
+        assert_eq!(fetched["name"], json!("bob"));
+        assert!(!fetched.contains_key("age"));
+
+        // applying the same document again is a no-op
+        assert!(service.apply_schema(document(desired), options).await.ok().unwrap().steps.is_empty());
+
+        // a failing step rolls back the whole plan
+        let invalid = json!({"tables": [
+            {"name": "test_table", "columns": [{"name": "name", "type": "integer", "not_null": true}]},
+            {"name": "customers", "columns": []},
+        ]});
+        assert!(matches!(service.apply_schema(document(invalid), options).await, Err(Error::Validation(_))));
+        assert!(matches!(service.describe_table("customers").await, Err(Error::NotFound(_))));
+        assert!(service.describe_table("orders").await.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn test_foreign_keys() {
+        let service = get_service("foreign_keys").await;
+        let table = |value: serde_json::Value| -> TableSchema { serde_json::from_value(value).unwrap() };
+
+        assert!(service.create_table(table(json!({"name": "customers", "columns": [{"name": "name", "type": "text"}]}))).await.is_ok());
+
+        let missing = table(json!({"name": "orders", "columns": [
+            {"name": "customer_id", "type": "integer", "references": {"table": "clients"}},
+        ]}));
+        assert!(matches!(service.create_table(missing).await, Err(Error::Validation(_))));
+        let wrong_column = table(json!({"name": "orders", "columns": [
+            {"name": "customer_id", "type": "integer", "references": {"table": "customers", "column": "number"}},
+        ]}));
+        assert!(matches!(service.create_table(wrong_column).await, Err(Error::Validation(_))));
+
+        let orders = table(json!({"name": "orders", "columns": [
+            {"name": "customer_id", "type": "integer", "references": {"table": "customers", "on_delete": "cascade"}},
+            {"name": "parent_id", "type": "integer", "references": {"table": "orders", "on_delete": "set_null"}},
+        ]}));
+        assert!(service.create_table(orders).await.is_ok());
+
+        let customer = service.insert_row("customers", row(json!({"name": "ann"}))).await.ok().unwrap().row["id"].as_i64().unwrap();
+        let order = service.insert_row("orders", row(json!({"customer_id": customer}))).await.ok().unwrap().row["id"].as_i64().unwrap();
+        assert!(service.insert_row("orders", row(json!({"customer_id": customer, "parent_id": order}))).await.is_ok());
+        assert!(matches!(service.insert_row("orders", row(json!({"customer_id": customer + 100}))).await,
+                         Err(Error::ForeignKeyViolation(_))));
+
+        // referenced columns cannot be renamed or made non-unique
+        assert!(service.alter_table("customers", serde_json::from_value(json!({"operations": [
+            {"op": "change_column", "name": "name", "column": {"name": "code", "type": "text", "unique": true}},
+        ]})).unwrap()).await.is_ok());
+        assert!(service.create_table(table(json!({"name": "invoices", "columns": [
+            {"name": "customer_code", "type": "text", "references": {"table": "customers", "column": "code"}},
+        ]}))).await.is_ok());
+        for operation in [
+            json!({"op": "rename_column", "from": "code", "to": "label"}),
+            json!({"op": "change_column", "name": "code", "column": {"name": "code", "type": "text"}}),
+            json!({"op": "change_column", "name": "code", "column": {"name": "label", "type": "text", "unique": true}}),
+        ] {
+            let alter: AlterTable = serde_json::from_value(json!({"operations": [operation]})).unwrap();
+            assert!(matches!(service.alter_table("customers", alter).await, Err(Error::ForeignKeyViolation(_))));
+        }
+        let code = service.insert_row("customers", row(json!({"code": "c1"}))).await.unwrap().row["code"].clone();
+        assert!(service.insert_row("invoices", row(json!({"customer_code": code}))).await.is_ok());
+        assert!(service.drop_table("invoices").await.is_ok());
+
+        // so can single-column keys declared at table level
+        assert!(service.create_table(table(json!({"name": "regions", "columns": [
+            {"name": "code", "type": "text", "not_null": true}, {"name": "label", "type": "text", "not_null": true},
+        ], "primary_key": ["code"], "unique": [["label"]]}))).await.is_ok());
+        assert!(service.create_table(table(json!({"name": "stores", "columns": [
+            {"name": "region_code", "type": "text", "references": {"table": "regions", "column": "code"}},
+            {"name": "region_label", "type": "text", "references": {"table": "regions", "column": "label"}},
+        ]}))).await.is_ok());
+        assert!(service.insert_row("regions", row(json!({"code": "eu", "label": "Europe"}))).await.is_ok());
+        assert!(service.insert_row("stores", row(json!({"region_code": "eu", "region_label": "Europe"}))).await.is_ok());
+        assert!(matches!(service.insert_row("stores", row(json!({"region_code": "us"}))).await,
+                         Err(Error::ForeignKeyViolation(_))));
+
+        // a referenced table cannot be dropped, and deletes cascade
+        assert!(matches!(service.drop_table("customers").await, Err(Error::ForeignKeyViolation(_))));
+        assert!(service.delete_row("customers", &customer.to_string(), None).await.is_ok());
+        assert!(service.list_rows("orders", vec![]).await.ok().unwrap().rows.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_list_rows_with_embeds() {
+        let service = get_service("embeds").await;
+        let table = |value: serde_json::Value| -> TableSchema { serde_json::from_value(value).unwrap() };
+
+        assert!(service.create_table(table(json!({"name": "customers", "columns": [{"name": "name", "type": "text"}]}))).await.is_ok());
+        assert!(service.create_table(table(json!({"name": "orders", "columns": [
+            {"name": "customer_id", "type": "integer", "references": {"table": "customers"}},
+        ]}))).await.is_ok());
+        assert!(service.create_table(table(json!({"name": "items", "columns": [
+            {"name": "order_id", "type": "integer", "references": {"table": "orders"}},
+            {"name": "sku", "type": "text"},
+        ]}))).await.is_ok());
+
+        let ann = service.insert_row("customers", row(json!({"name": "ann"}))).await.ok().unwrap().row["id"].clone();
+        let first = service.insert_row("orders", row(json!({"customer_id": ann}))).await.ok().unwrap().row["id"].clone();
+        let second = service.insert_row("orders", row(json!({}))).await.ok().unwrap().row["id"].clone();
+        for sku in ["a", "b"] {
+            assert!(service.insert_row("items", row(json!({"order_id": first, "sku": sku}))).await.is_ok());
+        }
+
+        let params = vec![("embed".to_string(), "customer,items".to_string()), ("select".to_string(), "id".to_string())];
+        let page = service.list_rows("orders", params).await.ok().unwrap();
+        assert_eq!(page.rows.len(), 2);
+        assert_eq!(page.rows[0]["customer"]["name"], json!("ann"));
+        assert_eq!(page.rows[0]["items"].as_array().unwrap().len(), 2);
+        assert!(!page.rows[0].contains_key("customer_id"));
+        assert_eq!(page.rows[1]["id"], second);
+        assert_eq!(page.rows[1]["customer"], json!(null));
+        assert_eq!(page.rows[1]["items"], json!([]));
+
+        // nested, from the other side
+        let params = vec![("embed".to_string(), "orders.items".to_string())];
+        let page = service.list_rows("customers", params).await.ok().unwrap();
+        assert_eq!(page.rows[0]["orders"][0]["items"][1]["sku"], json!("b"));
+
+        let params = vec![("embed".to_string(), "vendor".to_string())];
+        assert!(matches!(service.list_rows("orders", params).await, Err(Error::Validation(_))));
+
+        // at most a page of children per row
+        let service = service.with_page_size(1, 2);
+        let params = vec![("embed".to_string(), "items".to_string())];
+        assert_eq!(service.list_rows("orders", params.clone()).await.ok().unwrap().rows[0]["items"].as_array().unwrap().len(), 2);
+        assert!(service.insert_row("items", row(json!({"order_id": first, "sku": "c"}))).await.is_ok());
+        assert!(matches!(service.list_rows("orders", params).await, Err(Error::Validation(_))));
+        let params = vec![("embed".to_string(), "customer".to_string()), ("limit".to_string(), "2".to_string())];
+        assert_eq!(service.list_rows("orders", params).await.ok().unwrap().rows.len(), 2);
+    }
+}
@@ -0,0 +1,370 @@
+//! Declarative schemas: a YAML or JSON document listing every table
+//! fastfood should manage, compared with the stored schemas to plan the
+//! creates, alters and drops that bring the database in line with it.
+
+use std::collections::HashSet;
+use std::fmt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::services::alter::AlterOperation;
+use crate::services::crud::{ColumnSchema, Error, TableSchema};
+use crate::services::defaults::ColumnDefault;
+use crate::services::identifier::Identifier;
+use crate::services::indexes::IndexSchema;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SchemaDocument {
+    pub tables: Vec<TableSchema>,
+}
+
+impl SchemaDocument {
+    /// Reads a schema file, parsed as JSON when its name ends in `.json`
+    /// and as YAML otherwise.
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| Error::Internal(format!("could not read schema file {}: {}", path, e)))?;
+
+        let document = if path.ends_with(".json") {
+            serde_json::from_str(&contents).map_err(|e| e.to_string())
+        } else {
+            serde_yaml::from_str(&contents).map_err(|e| e.to_string())
+        };
+
+        document.map_err(|e| Error::Validation(format!("could not parse schema file {}: {}", path, e)))
+    }
+
+    /// Checks every table, and that no table is listed twice.
+    pub fn validate(&self) -> Result<(), Error> {
+        let mut seen = HashSet::new();
+
+        for table in &self.tables {
+            table.validate()?;
+
+            if !seen.insert(table.name.as_str().to_lowercase()) {
+                return Err(Error::Validation(format!("table {} is listed more than once", table.name.as_str())));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// How `POST /schema/apply` and the startup schema file are applied.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApplyOptions {
+    /// Report the plan without applying it.
+    pub dry_run: bool,
+    /// Allow the plan to drop tables or columns.
+    pub allow_destructive: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum PlanStep {
+    Create { schema: TableSchema },
+    Alter { table: Identifier, operations: Vec<AlterOperation> },
+    Drop { table: Identifier },
+}
+
+impl PlanStep {
+    /// Whether applying the step loses stored data.
+    pub fn is_destructive(&self) -> bool {
+        match self {
+            PlanStep::Create { .. } => false,
+            PlanStep::Alter { operations, .. } => {
+                operations.iter().any(|operation| matches!(operation, AlterOperation::DropColumn { .. }))
+            }
+            PlanStep::Drop { .. } => true,
+        }
+    }
+}
+
+impl fmt::Display for PlanStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlanStep::Create { schema } => {
+                let columns: Vec<&str> = schema.columns.iter().map(|column| column.name.as_str()).collect();
+                write!(f, "+ create table {} ({})", schema.name.as_str(), columns.join(", "))
+            }
+            PlanStep::Alter { table, operations } => {
+                let operations: Vec<String> = operations
+                    .iter()
+                    .map(|operation| match operation {
+                        AlterOperation::AddColumn { column } => format!("add column {}", column.name.as_str()),
+                        AlterOperation::RenameColumn { from, to } => format!("rename column {} to {}", from, to.as_str()),
+                        AlterOperation::DropColumn { name } => format!("drop column {}", name),
+                        AlterOperation::ChangeColumn { name, .. } => format!("change column {}", name),
+                        AlterOperation::CreateIndex { index } => format!("create index {}", index.name.as_str()),
+                        AlterOperation::DropIndex { name } => format!("drop index {}", name),
+                    })
+                    .collect();
+                write!(f, "~ alter table {}: {}", table.as_str(), operations.join(", "))
+            }
+            PlanStep::Drop { table } => write!(f, "- drop table {}", table.as_str()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SchemaPlan {
+    pub steps: Vec<PlanStep>,
+    /// Whether any step drops a table or a column.
+    pub destructive: bool,
+    pub applied: bool,
+}
+
+impl SchemaPlan {
+    pub fn new(steps: Vec<PlanStep>) -> Self {
+        Self {
+            destructive: steps.iter().any(PlanStep::is_destructive),
+            steps,
+            applied: false,
+        }
+    }
+}
+
+/// The steps turning the stored schemas `live` into `desired`: creates
+/// first, then alters, then drops of the tables `desired` no longer lists.
+/// Created tables come after the tables they reference and dropped ones
+/// before them. Tables and columns are matched by name, case-insensitively,
+/// so a renamed column shows up as a drop and an add.
+pub fn plan(desired: &[TableSchema], live: &[TableSchema]) -> Vec<PlanStep> {
+    let mut creates = Vec::new();
+    let mut alters = Vec::new();
+
+    for table in desired {
+        match live.iter().find(|schema| same_name(&schema.name, &table.name)) {
+            None => creates.push(table),
+            Some(current) => {
+                let operations = alter_operations(current, table);
+                if !operations.is_empty() {
+                    alters.push(PlanStep::Alter { table: current.name.clone(), operations });
+                }
+            }
+        }
+    }
+
+    let drops: Vec<&TableSchema> = live
+        .iter()
+        .filter(|schema| !desired.iter().any(|table| same_name(&table.name, &schema.name)))
+        .collect();
+
+    let creates = dependency_order(creates)
+        .into_iter()
+        .map(|schema| PlanStep::Create { schema: schema.clone() });
+    let drops = dependency_order(drops)
+        .into_iter()
+        .rev()
+        .map(|schema| PlanStep::Drop { table: schema.name.clone() });
+
+    creates.chain(alters).chain(drops).collect()
+}
+
+/// Orders `tables` so each one comes after the others it references,
+/// keeping their order otherwise. Tables in a cycle keep their order.
+fn dependency_order(mut tables: Vec<&TableSchema>) -> Vec<&TableSchema> {
+    let mut ordered = Vec::with_capacity(tables.len());
+
+    while !tables.is_empty() {
+        let next = tables
+            .iter()
+            .position(|table| !references_any(table, &tables))
+            .unwrap_or(0);
+        ordered.push(tables.remove(next));
+    }
+
+    ordered
+}
+
+fn references_any(table: &TableSchema, others: &[&TableSchema]) -> bool {
+    table
+        .columns
+        .iter()
+        .filter_map(|column| column.references.as_ref())
+        .filter(|references| !same_name(&references.table, &table.name))
+        .any(|references| others.iter().any(|other| same_name(&other.name, &references.table)))
+}
+
+/// Drops the indexes that are gone or differ, then the columns `desired` no
+/// longer has, then changes those whose definition differs, adds the new
+/// ones and finally creates the new and differing indexes.
+fn alter_operations(current: &TableSchema, desired: &TableSchema) -> Vec<AlterOperation> {
+    let kept = |index: &IndexSchema, others: &[IndexSchema]| others.iter().any(|other| other == index);
+
+    let index_drops = current
+        .indexes
+        .iter()
+        .filter(|index| !kept(index, &desired.indexes))
+        .map(|index| AlterOperation::DropIndex { name: index.name.as_str().to_string() });
+    let index_creates = desired
+        .indexes
+        .iter()
+        .filter(|index| !kept(index, &current.indexes))
+        .map(|index| AlterOperation::CreateIndex { index: index.clone() });
+
+    let mut drops: Vec<AlterOperation> = index_drops.collect();
+    let mut changes = Vec::new();
+
+    for column in &current.columns {
+        match desired.columns.iter().find(|wanted| same_name(&wanted.name, &column.name)) {
+            None => drops.push(AlterOperation::DropColumn { name: column.name.as_str().to_string() }),
+            Some(wanted) if !same_definition(column, wanted) => changes.push(AlterOperation::ChangeColumn {
+                name: column.name.as_str().to_string(),
+                column: wanted.clone(),
+            }),
+            Some(_) => {}
+        }
+    }
+
+    let adds = desired
+        .columns
+        .iter()
+        .filter(|wanted| !current.columns.iter().any(|column| same_name(&column.name, &wanted.name)))
+        .map(|wanted| AlterOperation::AddColumn { column: wanted.clone() });
+
+    drops.into_iter().chain(changes).chain(adds).chain(index_creates).collect()
+}
+
+/// Refuses to change the table constraints or options of existing tables,
+/// which no alteration can do.
+pub fn check_fixed_settings(desired: &[TableSchema], live: &[TableSchema]) -> Result<(), Error> {
+    let key = |columns: &Vec<Identifier>| columns.iter().map(|name| name.as_str().to_lowercase()).collect::<Vec<_>>();
+
+    for table in desired {
+        if let Some(current) = live.iter().find(|schema| same_name(&schema.name, &table.name)) {
+            let same_key = current.primary_key.as_ref().map(key) == table.primary_key.as_ref().map(key);
+            let same_unique = current.unique.iter().map(key).collect::<Vec<_>>() == table.unique.iter().map(key).collect::<Vec<_>>();
+
+            if !same_key || !same_unique {
+                return Err(Error::Validation(format!("the primary_key and unique constraints of table {} cannot be changed",
+                                                     table.name.as_str())));
+            }
+            if current.options != table.options {
+                return Err(Error::Validation(format!("the options of table {} cannot be changed", table.name.as_str())));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn same_name(a: &Identifier, b: &Identifier) -> bool {
+    a.as_str().eq_ignore_ascii_case(b.as_str())
+}
+
+/// Compares two definitions the way they render, so an omitted flag equals
+/// `false` and an omitted default equals a `null` one.
+fn same_definition(a: &ColumnSchema, b: &ColumnSchema) -> bool {
+    let flags = |column: &ColumnSchema| [column.primary_key, column.auto_increment, column.unique, column.not_null]
+        .map(|flag| flag.unwrap_or(false));
+    let default = |column: &ColumnSchema| match column.default {
+        None => ColumnDefault::Literal(Value::Null),
+        Some(ref default) => default.clone(),
+    };
+
+    a.name == b.name && a.data_type == b.data_type && flags(a) == flags(b)
+        && default(a) == default(b) && a.references == b.references && a.rules == b.rules
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn table(value: Value) -> TableSchema {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_plan() {
+        let live = vec![
+            table(json!({"name": "people", "columns": [
+                {"name": "name", "type": "text"},
+                {"name": "age", "type": "integer"},
+                {"name": "nickname", "type": "text", "unique": false},
+            ]})),
+            table(json!({"name": "legacy", "columns": []})),
+        ];
+        let desired = vec![
+            table(json!({"name": "People", "columns": [
+                {"name": "name", "type": "text", "not_null": true, "default": ""},
+                {"name": "age", "type": "integer"},
+                {"name": "email", "type": "text"},
+                {"name": "nickname", "type": "text", "default": null},
+            ]})),
+            table(json!({"name": "orders", "columns": [{"name": "total", "type": "float"}]})),
+        ];
+
+        let steps = plan(&desired, &live);
+        let lines: Vec<String> = steps.iter().map(|step| step.to_string()).collect();
+        assert_eq!(lines, vec![
+            "+ create table orders (total)",
+            "~ alter table people: change column name, add column email",
+            "- drop table legacy",
+        ]);
+
+        assert!(!steps[0].is_destructive());
+        assert!(!steps[1].is_destructive());
+        assert!(SchemaPlan::new(steps).destructive);
+
+        assert!(plan(&live, &live).is_empty());
+    }
+
+    #[test]
+    fn test_referenced_tables_come_first() {
+        let orders = table(json!({"name": "orders", "columns": [
+            {"name": "customer_id", "type": "integer", "references": {"table": "customers"}},
+        ]}));
+        let customers = table(json!({"name": "customers", "columns": []}));
+
+        let lines: Vec<String> = plan(&[orders.clone(), customers.clone()], &[]).iter().map(|step| step.to_string()).collect();
+        assert_eq!(lines, vec!["+ create table customers ()", "+ create table orders (customer_id)"]);
+
+        let lines: Vec<String> = plan(&[], &[customers, orders]).iter().map(|step| step.to_string()).collect();
+        assert_eq!(lines, vec!["- drop table orders", "- drop table customers"]);
+    }
+
+    #[test]
+    fn test_changed_indexes_are_recreated() {
+        let live = vec![table(json!({"name": "people", "columns": [{"name": "name", "type": "text"}],
+                                     "indexes": [{"name": "people_name", "columns": ["name"]}]}))];
+        let desired = vec![table(json!({"name": "people", "columns": [{"name": "name", "type": "text"}],
+                                        "indexes": [{"name": "people_name", "columns": ["name"], "unique": true}]}))];
+
+        let steps = plan(&desired, &live);
+        assert_eq!(steps[0].to_string(), "~ alter table people: drop index people_name, create index people_name");
+        assert!(!steps[0].is_destructive());
+    }
+
+    #[test]
+    fn test_dropped_columns_are_destructive() {
+        let live = vec![table(json!({"name": "people", "columns": [{"name": "name", "type": "text"}, {"name": "age", "type": "integer"}]}))];
+        let desired = vec![table(json!({"name": "people", "columns": [{"name": "name", "type": "text"}]}))];
+
+        let steps = plan(&desired, &live);
+        assert_eq!(steps.len(), 1);
+        assert_eq!(steps[0].to_string(), "~ alter table people: drop column age");
+        assert!(steps[0].is_destructive());
+    }
+
+    #[test]
+    fn test_document_formats() {
+        let document: SchemaDocument = serde_yaml::from_str("
+tables:
+  - name: people
+    columns:
+      - {name: name, type: text, not_null: true}
+").unwrap();
+        assert_eq!(document.tables[0].columns[0].not_null, Some(true));
+
+        let duplicated: SchemaDocument = serde_json::from_value(json!({"tables": [
+            {"name": "people", "columns": []},
+            {"name": "PEOPLE", "columns": []},
+        ]})).unwrap();
+        assert!(matches!(duplicated.validate(), Err(Error::Validation(_))));
+
+        assert!(serde_json::from_value::<SchemaDocument>(json!({"tables": [], "extra": 1})).is_err());
+    }
+}
@@ -0,0 +1,173 @@
+//! Column defaults. Clients send either a JSON literal, which is checked
+//! against the column's `DataType` and rendered as an escaped SQL literal, or
+//! one of a few allow-listed expressions such as `{"expr": "now"}`.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::services::crud::DataType;
+use crate::services::value::{parse_timestamp, quote_literal, sqlite_timestamp};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ColumnDefault {
+    Expression { expr: DefaultExpression },
+    Literal(Value),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DefaultExpression {
+    /// The current UTC date and time, `CURRENT_TIMESTAMP`.
+    Now,
+    /// The current UTC date, `CURRENT_DATE`.
+    Today,
+    /// A random version 4 UUID in its canonical text form.
+    Uuid,
+}
+
+const UUID_V4_SQL: &str = "(lower(hex(randomblob(4))) || '-' || lower(hex(randomblob(2))) || '-4' || \
+    substr(lower(hex(randomblob(2))), 2) || '-' || substr('89ab', 1 + (abs(random()) % 4), 1) || \
+    substr(lower(hex(randomblob(2))), 2) || '-' || lower(hex(randomblob(6))))";
+
+impl DefaultExpression {
+    fn sql(&self) -> &'static str {
+        match self {
+            DefaultExpression::Now => "CURRENT_TIMESTAMP",
+            DefaultExpression::Today => "CURRENT_DATE",
+            DefaultExpression::Uuid => UUID_V4_SQL,
+        }
+    }
+
+    fn supports(&self, data_type: &DataType) -> bool {
+        match self {
+            DefaultExpression::Now | DefaultExpression::Today => {
+                matches!(data_type, DataType::TimeStamp | DataType::Text)
+            }
+            DefaultExpression::Uuid => matches!(data_type, DataType::Text),
+        }
+    }
+}
+
+impl ColumnDefault {
+    /// Checks the default against the column it belongs to, returning a
+    /// message describing the mismatch.
+    pub fn check(&self, data_type: &DataType, not_null: bool) -> Result<(), String> {
+        match self {
+            ColumnDefault::Expression { expr } if expr.supports(data_type) => Ok(()),
+            ColumnDefault::Expression { expr } => Err(format!(
+                "expression {:?} cannot be used as a default for a {} column", expr, data_type)),
+            ColumnDefault::Literal(Value::Null) if not_null => Err(
+                "default null is not allowed on a not_null column".to_string()),
+            ColumnDefault::Literal(Value::Null) => Ok(()),
+            ColumnDefault::Literal(value) => {
+                let valid = match data_type {
+                    DataType::Text => value.is_string(),
+                    DataType::Integer => value.is_i64(),
+                    DataType::Float => value.is_number(),
+                    DataType::Boolean => value.is_boolean(),
+                    DataType::TimeStamp => value.as_str().and_then(parse_timestamp).is_some(),
+                };
+
+                if valid {
+                    Ok(())
+                } else {
+                    Err(format!("default {} is not a valid {} value", value, data_type))
+                }
+            }
+        }
+    }
+
+    /// Renders the default as SQL. Strings are always escaped, so this is
+    /// safe even for a default that did not pass `check`.
+    pub fn sql(&self, data_type: &DataType) -> String {
+        match self {
+            ColumnDefault::Expression { expr } => expr.sql().to_string(),
+            ColumnDefault::Literal(Value::Null) => "NULL".to_string(),
+            ColumnDefault::Literal(Value::Bool(b)) => (*b as i64).to_string(),
+            ColumnDefault::Literal(Value::Number(n)) => n.to_string(),
+            ColumnDefault::Literal(Value::String(s)) => match (data_type, parse_timestamp(s)) {
+                (DataType::TimeStamp, Some(timestamp)) => quote_literal(&sqlite_timestamp(timestamp)),
+                _ => quote_literal(s),
+            },
+            ColumnDefault::Literal(value) => quote_literal(&value.to_string()),
+        }
+    }
+
+    /// Recovers a default from the raw `dflt_value` SQLite reports for a
+    /// column. Expressions outside the allow-list yield `None`.
+    pub fn from_sql(raw: &str) -> Option<ColumnDefault> {
+        let raw = raw.trim();
+
+        match raw.to_uppercase().as_str() {
+            "NULL" => return Some(ColumnDefault::Literal(Value::Null)),
+            "CURRENT_TIMESTAMP" => return Some(ColumnDefault::Expression { expr: DefaultExpression::Now }),
+            "CURRENT_DATE" => return Some(ColumnDefault::Expression { expr: DefaultExpression::Today }),
+            _ => {}
+        }
+
+        if raw.len() >= 2 && raw.starts_with('\'') && raw.ends_with('\'') {
+            let text = raw[1..raw.len() - 1].replace("''", "'");
+            return Some(ColumnDefault::Literal(Value::String(text)));
+        }
+
+        serde_json::from_str::<serde_json::Number>(raw)
+            .ok()
+            .map(|n| ColumnDefault::Literal(Value::Number(n)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn literal(value: Value) -> ColumnDefault {
+        ColumnDefault::Literal(value)
+    }
+
+    #[test]
+    fn test_deserialize() {
+        assert_eq!(serde_json::from_value::<ColumnDefault>(json!({"expr": "now"})).unwrap(),
+                   ColumnDefault::Expression { expr: DefaultExpression::Now });
+        assert_eq!(serde_json::from_value::<ColumnDefault>(json!("hello")).unwrap(), literal(json!("hello")));
+    }
+
+    #[test]
+    fn test_check_against_data_type() {
+        assert!(literal(json!("hello")).check(&DataType::Text, true).is_ok());
+        assert!(literal(json!(3)).check(&DataType::Integer, true).is_ok());
+        assert!(literal(json!(3)).check(&DataType::Float, true).is_ok());
+        assert!(literal(json!(true)).check(&DataType::Boolean, true).is_ok());
+        assert!(literal(json!("2024-01-01T00:00:00Z")).check(&DataType::TimeStamp, true).is_ok());
+        assert!(literal(json!(null)).check(&DataType::Text, false).is_ok());
+
+        assert!(literal(json!("3")).check(&DataType::Integer, true).is_err());
+        assert!(literal(json!(1.5)).check(&DataType::Integer, true).is_err());
+        assert!(literal(json!(1)).check(&DataType::Boolean, true).is_err());
+        assert!(literal(json!("soon")).check(&DataType::TimeStamp, true).is_err());
+        assert!(literal(json!(null)).check(&DataType::Text, true).is_err());
+        assert!(literal(json!({"a": 1})).check(&DataType::Text, true).is_err());
+
+        let uuid = ColumnDefault::Expression { expr: DefaultExpression::Uuid };
+        assert!(uuid.check(&DataType::Text, true).is_ok());
+        assert!(uuid.check(&DataType::Integer, true).is_err());
+    }
+
+    #[test]
+    fn test_sql_is_escaped() {
+        assert_eq!(literal(json!("hello")).sql(&DataType::Text), "'hello'");
+        assert_eq!(literal(json!("it's'); DROP TABLE x; --")).sql(&DataType::Text), "'it''s''); DROP TABLE x; --'");
+        assert_eq!(literal(json!(true)).sql(&DataType::Boolean), "1");
+        assert_eq!(literal(json!(2.5)).sql(&DataType::Float), "2.5");
+        assert_eq!(literal(json!("2024-01-01T02:00:00+02:00")).sql(&DataType::TimeStamp), "'2024-01-01 00:00:00'");
+    }
+
+    #[test]
+    fn test_from_sql() {
+        assert_eq!(ColumnDefault::from_sql("'it''s'"), Some(literal(json!("it's"))));
+        assert_eq!(ColumnDefault::from_sql("42"), Some(literal(json!(42))));
+        assert_eq!(ColumnDefault::from_sql("CURRENT_TIMESTAMP"),
+                   Some(ColumnDefault::Expression { expr: DefaultExpression::Now }));
+        assert_eq!(ColumnDefault::from_sql("(random())"), None);
+    }
+}
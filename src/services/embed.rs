@@ -0,0 +1,319 @@
+//! `?embed=customer,items.product` nests related rows into a listing,
+//! following the foreign keys stored with the tables' schemas:
+//!
+//! - a many-to-one relation is named after its foreign key column without
+//!   the `_id` suffix (`customer_id` embeds `customer`), or after the
+//!   referenced table when the column has no such suffix, and embeds the
+//!   referenced row or `null`;
+//! - a one-to-many relation is named after the referencing table (`items`)
+//!   and embeds an array of its rows.
+//!
+//! Each relation is loaded with one query per level whatever the number of
+//! rows, and paths may be at most `MAX_EMBED_DEPTH` relations deep. A row may
+//! embed at most a page of children per one-to-many relation; beyond that
+//! the listing is refused, and the children should be listed on their own.
+
+use std::collections::{HashMap, HashSet};
+use diesel::{RunQueryDsl, SqliteConnection};
+use serde_json::Value;
+use crate::services::codec;
+use crate::services::crud::{json_object, parse_row, ColumnSchema, Error, JsonRow, Row, TableSchema};
+use crate::services::identifier::Identifier;
+use crate::services::options::ID_COLUMN;
+use crate::services::value::{query_with_binds, SqlValue};
+
+/// How many relations deep `?embed=` may go.
+pub const MAX_EMBED_DEPTH: usize = 3;
+
+/// Keys bound per query, well below SQLite's limit on host parameters.
+const MAX_BATCH: usize = 500;
+
+/// The relations to embed, each with the relations to embed in turn.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Embed {
+    pub relations: Vec<(String, Embed)>,
+}
+
+impl Embed {
+    /// Parses a comma-separated list of dot-separated relation paths.
+    pub fn parse(param: &str) -> Result<Embed, Error> {
+        let mut embed = Embed::default();
+
+        for path in param.split(',') {
+            let names: Vec<&str> = path.trim().split('.').collect();
+
+            if names.iter().any(|name| name.is_empty()) {
+                return Err(Error::Validation(format!("invalid embed {:?}", path)));
+            }
+            if names.len() > MAX_EMBED_DEPTH {
+                return Err(Error::Validation(format!("embed {} is more than {} relations deep", path.trim(), MAX_EMBED_DEPTH)));
+            }
+
+            let mut node = &mut embed;
+            for name in names {
+                let index = match node.relations.iter().position(|(existing, _)| existing == name) {
+                    Some(index) => index,
+                    None => {
+                        node.relations.push((name.to_string(), Embed::default()));
+                        node.relations.len() - 1
+                    }
+                };
+                node = &mut node.relations[index].1;
+            }
+        }
+
+        Ok(embed)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.relations.iter().any(|(relation, _)| relation == name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cardinality {
+    ManyToOne,
+    OneToMany,
+}
+
+/// A relation of `TableSchema` resolved from the stored foreign keys.
+#[derive(Debug)]
+pub struct Relation<'a> {
+    pub cardinality: Cardinality,
+    /// The column of the embedding table holding the key.
+    pub local: &'a ColumnSchema,
+    pub target: &'a TableSchema,
+    /// The column of `target` matched against `local`.
+    pub remote: &'a ColumnSchema,
+}
+
+/// Finds the relation `name` of `schema` among `tables`, the managed tables
+/// including their managed columns.
+pub fn resolve<'a>(schema: &'a TableSchema, name: &str, tables: &'a [TableSchema]) -> Result<Relation<'a>, Error> {
+    if find_column(schema, name).is_some() {
+        return Err(Error::Validation(format!("embed {} would replace column {} of table {}", name, name, schema.name.as_str())));
+    }
+
+    let mut relations = Vec::new();
+
+    for column in &schema.columns {
+        if let Some(ref references) = column.references {
+            let relation_name = match column.name.as_str().strip_suffix("_id") {
+                Some(stripped) => stripped,
+                None => references.table.as_str(),
+            };
+
+            if relation_name.eq_ignore_ascii_case(name) {
+                let target = find_table(tables, references.table.as_str())?;
+                relations.push(Relation {
+                    cardinality: Cardinality::ManyToOne,
+                    local: column,
+                    remote: referenced_column(target, references.column.as_str())?,
+                    target,
+                });
+            }
+        }
+    }
+
+    if relations.is_empty() {
+        if let Some(target) = tables.iter().find(|table| table.name.as_str().eq_ignore_ascii_case(name)) {
+            for column in &target.columns {
+                if let Some(ref references) = column.references {
+                    if references.table.as_str().eq_ignore_ascii_case(schema.name.as_str()) {
+                        relations.push(Relation {
+                            cardinality: Cardinality::OneToMany,
+                            local: referenced_column(schema, references.column.as_str())?,
+                            target,
+                            remote: column,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    match relations.len() {
+        1 => Ok(relations.remove(0)),
+        0 => Err(Error::Validation(format!("table {} has no relation {}", schema.name.as_str(), name))),
+        _ => Err(Error::Validation(format!("relation {} of table {} is ambiguous", name, schema.name.as_str()))),
+    }
+}
+
+/// Embeds the relations of `embed` into `rows` of `schema`, one query per
+/// relation and level, refusing rows with more than `max_children` children
+/// in a one-to-many relation.
+pub fn embed_rows(conn: &mut SqliteConnection, schema: &TableSchema, rows: &mut [Row], embed: &Embed,
+                  tables: &[TableSchema], max_children: i64) -> Result<(), Error> {
+    for (name, nested) in &embed.relations {
+        let relation = resolve(schema, name, tables)?;
+        let local = relation.local.name.as_str();
+        let remote = relation.remote.name.as_str();
+
+        let mut related = load(conn, &relation, rows, max_children)?;
+        embed_rows(conn, relation.target, &mut related, nested, tables, max_children)?;
+
+        match relation.cardinality {
+            Cardinality::ManyToOne => {
+                let by_key: HashMap<String, Row> = related
+                    .into_iter()
+                    .filter_map(|row| row.get(remote).map(|key| (key.to_string(), row.clone())))
+                    .collect();
+
+                for row in rows.iter_mut() {
+                    let parent = row.get(local).and_then(|key| by_key.get(&key.to_string())).cloned();
+                    row.insert(name.clone(), parent.map(Value::Object).unwrap_or(Value::Null));
+                }
+            }
+            Cardinality::OneToMany => {
+                let mut by_key: HashMap<String, Vec<Value>> = HashMap::new();
+                for child in related {
+                    if let Some(key) = child.get(remote).map(Value::to_string) {
+                        by_key.entry(key).or_default().push(Value::Object(child));
+                    }
+                }
+
+                if let Some((key, _)) = by_key.iter().find(|(_, children)| children.len() as i64 > max_children) {
+                    return Err(Error::Validation(format!("row with {} {} of table {} has more than {} {} to embed",
+                                                         local, key, schema.name.as_str(), max_children, name)));
+                }
+
+                for row in rows.iter_mut() {
+                    let children = row.get(local).and_then(|key| by_key.get(&key.to_string())).cloned();
+                    row.insert(name.clone(), Value::Array(children.unwrap_or_default()));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the rows of the relation's target matching the keys of `rows`, of
+/// a one-to-many relation at most one more than `max_children` per key, so
+/// an oversized relation shows without reading all of it.
+fn load(conn: &mut SqliteConnection, relation: &Relation, rows: &[Row], max_children: i64) -> Result<Vec<Row>, Error> {
+    let mut seen = HashSet::new();
+    let mut keys = Vec::new();
+
+    for key in rows.iter().filter_map(|row| row.get(relation.local.name.as_str())) {
+        if !key.is_null() && seen.insert(key.to_string()) {
+            // values SQLite stored in a form the column does not expect cannot match
+            if let Ok(key) = codec::encode_value(relation.remote, key) {
+                keys.push(key);
+            }
+        }
+    }
+
+    let id = Identifier::managed(ID_COLUMN);
+    let mut related = Vec::new();
+
+    for batch in keys.chunks(MAX_BATCH) {
+        let placeholders = vec!["?"; batch.len()].join(", ");
+        let mut binds = batch.to_vec();
+        let select_query = match relation.cardinality {
+            Cardinality::ManyToOne => format!("SELECT {} AS data FROM {} WHERE {} IN ({}) ORDER BY {}",
+                                              json_object(&relation.target.columns),
+                                              relation.target.name,
+                                              relation.remote.name,
+                                              placeholders,
+                                              id),
+            Cardinality::OneToMany => {
+                binds.push(SqlValue::Integer(max_children + 1));
+                format!("SELECT data FROM (SELECT {} AS data, {id} AS position, \
+                         ROW_NUMBER() OVER (PARTITION BY {remote} ORDER BY {id}) AS ordinal \
+                         FROM {} WHERE {remote} IN ({})) WHERE ordinal <= ? ORDER BY position",
+                        json_object(&relation.target.columns),
+                        relation.target.name,
+                        placeholders,
+                        id = id,
+                        remote = relation.remote.name)
+            }
+        };
+
+        log::info!("Executing query: {}", select_query);
+
+        for row in query_with_binds(select_query, binds).load::<JsonRow>(conn)? {
+            related.push(parse_row(relation.target, row)?);
+        }
+    }
+
+    Ok(related)
+}
+
+fn find_table<'a>(tables: &'a [TableSchema], name: &str) -> Result<&'a TableSchema, Error> {
+    tables
+        .iter()
+        .find(|table| table.name.as_str().eq_ignore_ascii_case(name))
+        .ok_or_else(|| Error::Validation(format!("table {} is not managed by fastfood and cannot be embedded", name)))
+}
+
+fn find_column<'a>(schema: &'a TableSchema, name: &str) -> Option<&'a ColumnSchema> {
+    schema.columns.iter().find(|column| column.name.as_str().eq_ignore_ascii_case(name))
+}
+
+fn referenced_column<'a>(schema: &'a TableSchema, name: &str) -> Result<&'a ColumnSchema, Error> {
+    find_column(schema, name)
+        .ok_or_else(|| Error::Validation(format!("table {} has no column {}", schema.name.as_str(), name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn tables() -> Vec<TableSchema> {
+        ["customers", "orders", "items"]
+            .iter()
+            .zip([
+                json!([{"name": "name", "type": "text"}]),
+                json!([{"name": "customer_id", "type": "integer", "references": {"table": "customers"}},
+                       {"name": "billing", "type": "integer", "references": {"table": "customers"}}]),
+                json!([{"name": "order_id", "type": "integer", "references": {"table": "orders"}}]),
+            ])
+            .map(|(name, columns)| {
+                let mut schema: TableSchema = serde_json::from_value(json!({"name": name, "columns": columns})).unwrap();
+                let mut id: ColumnSchema = serde_json::from_value(json!({"name": "x", "type": "integer", "primary_key": true})).unwrap();
+                id.name = crate::services::identifier::Identifier::managed("id");
+                schema.columns.insert(0, id);
+                schema
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let embed = Embed::parse("customer, items.product,items.order").ok().unwrap();
+        assert_eq!(embed.relations.len(), 2);
+        assert!(embed.contains("items"));
+        assert_eq!(embed.relations[1].1.relations.len(), 2);
+
+        assert!(Embed::parse("a.b.c").is_ok());
+        assert!(matches!(Embed::parse("a.b.c.d"), Err(Error::Validation(_))));
+        assert!(matches!(Embed::parse("a,,b"), Err(Error::Validation(_))));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let tables = tables();
+        let (customers, orders) = (&tables[0], &tables[1]);
+
+        let customer = resolve(orders, "customer", &tables).ok().unwrap();
+        assert_eq!(customer.cardinality, Cardinality::ManyToOne);
+        assert_eq!(customer.local.name.as_str(), "customer_id");
+        assert_eq!(customer.target.name.as_str(), "customers");
+        assert_eq!(customer.remote.name.as_str(), "id");
+
+        let items = resolve(orders, "items", &tables).ok().unwrap();
+        assert_eq!(items.cardinality, Cardinality::OneToMany);
+        assert_eq!(items.local.name.as_str(), "id");
+        assert_eq!(items.remote.name.as_str(), "order_id");
+
+        // billing has no _id suffix, so it is named after the table it references
+        assert_eq!(resolve(orders, "customers", &tables).ok().unwrap().local.name.as_str(), "billing");
+
+        // orders references customers twice
+        assert!(matches!(resolve(customers, "orders", &tables), Err(Error::Validation(_))));
+        assert!(matches!(resolve(orders, "billing", &tables), Err(Error::Validation(_))));
+        assert!(matches!(resolve(orders, "nothing", &tables), Err(Error::Validation(_))));
+    }
+}
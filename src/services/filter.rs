@@ -0,0 +1,451 @@
+//! The filter language accepted by row listings, modelled on PostgREST:
+//!
+//! - `age=gt.30` compares a column with a value (`eq`, `neq`, `gt`, `gte`,
+//!   `lt`, `lte`, `like`, `ilike`, `in`, `is`), `*` being the wildcard for
+//!   `like` (case-sensitive) and `ilike` (case-insensitive);
+//! - `name=not.like.*bob*` negates a condition;
+//! - `or=(a.eq.1,b.is.null)` and `and=(...)` combine conditions and nest as
+//!   `or=(a.eq.1,and(b.gt.2,c.lt.5))`.
+//!
+//! Top-level parameters are ANDed. Columns cannot be named after a listing
+//! parameter (see `is_reserved_param`), as they could never be filtered by.
+//! Every column is checked against the
+//! table's schema and every value is parsed according to the column's
+//! `DataType` and bound as a parameter; nothing from the query string is
+//! interpolated into SQL.
+
+use crate::services::crud::{ColumnSchema, DataType, Error, TableSchema};
+use crate::services::identifier::Identifier;
+use crate::services::value::{parse_timestamp, sqlite_timestamp, SqlValue};
+
+/// Query parameters that control the listing rather than filter it.
+pub const RESERVED_PARAMS: &[&str] = &["select", "order", "limit", "cursor", "count", "embed"];
+
+/// Query parameters combining conditions.
+pub const GROUP_PARAMS: &[&str] = &["and", "or"];
+
+/// Whether a query parameter named `name` is taken by the listing itself
+/// rather than a filter on the column of that name.
+pub fn is_reserved_param(name: &str) -> bool {
+    RESERVED_PARAMS.iter().chain(GROUP_PARAMS).any(|param| param.eq_ignore_ascii_case(name))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Eq,
+    Neq,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+    Like,
+    ILike,
+    In,
+    Is,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Value(SqlValue),
+    List(Vec<SqlValue>),
+    Null,
+    True,
+    False,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    Condition {
+        column: Identifier,
+        op: Operator,
+        operand: Operand,
+    },
+    Not(Box<Filter>),
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+}
+
+impl Operator {
+    fn parse(name: &str) -> Result<Operator, Error> {
+        match name {
+            "eq" => Ok(Operator::Eq),
+            "neq" => Ok(Operator::Neq),
+            "gt" => Ok(Operator::Gt),
+            "gte" => Ok(Operator::Gte),
+            "lt" => Ok(Operator::Lt),
+            "lte" => Ok(Operator::Lte),
+            "like" => Ok(Operator::Like),
+            "ilike" => Ok(Operator::ILike),
+            "in" => Ok(Operator::In),
+            "is" => Ok(Operator::Is),
+            _ => Err(Error::Validation(format!("unknown filter operator {}", name))),
+        }
+    }
+
+    fn supports(&self, data_type: &DataType) -> bool {
+        match self {
+            Operator::Eq | Operator::Neq | Operator::In | Operator::Is => true,
+            Operator::Gt | Operator::Gte | Operator::Lt | Operator::Lte => !matches!(data_type, DataType::Boolean),
+            Operator::Like | Operator::ILike => matches!(data_type, DataType::Text),
+        }
+    }
+
+    fn sql(&self) -> &'static str {
+        match self {
+            Operator::Eq => "=",
+            Operator::Neq => "<>",
+            Operator::Gt => ">",
+            Operator::Gte => ">=",
+            Operator::Lt => "<",
+            Operator::Lte => "<=",
+            Operator::Like => "GLOB",
+            Operator::ILike => "LIKE",
+            Operator::In => "IN",
+            Operator::Is => "IS",
+        }
+    }
+}
+
+impl Filter {
+    /// Parses the filter parameters of a query string against `schema`,
+    /// ignoring `RESERVED_PARAMS`. Returns `None` when nothing is filtered.
+    pub fn from_params(schema: &TableSchema, params: &[(String, String)]) -> Result<Option<Filter>, Error> {
+        let mut filters = Vec::new();
+
+        for (key, value) in params {
+            if RESERVED_PARAMS.contains(&key.as_str()) {
+                continue;
+            }
+
+            let filter = match key.as_str() {
+                "and" => Filter::And(parse_group(schema, value)?),
+                "or" => Filter::Or(parse_group(schema, value)?),
+                "not.and" => Filter::Not(Box::new(Filter::And(parse_group(schema, value)?))),
+                "not.or" => Filter::Not(Box::new(Filter::Or(parse_group(schema, value)?))),
+                column => parse_condition(schema, column, value)?,
+            };
+            filters.push(filter);
+        }
+
+        Ok(match filters.len() {
+            0 => None,
+            1 => filters.pop(),
+            _ => Some(Filter::And(filters)),
+        })
+    }
+
+    /// Renders the filter as a SQL boolean expression, pushing its values
+    /// onto `binds` in placeholder order.
+    pub fn to_sql(&self, binds: &mut Vec<SqlValue>) -> String {
+        match self {
+            Filter::Condition { column, op, operand } => match operand {
+                Operand::Value(value) => {
+                    binds.push(value.clone());
+                    match op {
+                        Operator::ILike => format!("{} LIKE ? ESCAPE '\\'", column),
+                        _ => format!("{} {} ?", column, op.sql()),
+                    }
+                }
+                Operand::List(values) => {
+                    binds.extend(values.iter().cloned());
+                    format!("{} IN ({})", column, vec!["?"; values.len()].join(", "))
+                }
+                Operand::Null => format!("{} IS NULL", column),
+                Operand::True => format!("{} IS 1", column),
+                Operand::False => format!("{} IS 0", column),
+            },
+            Filter::Not(filter) => format!("NOT ({})", filter.to_sql(binds)),
+            Filter::And(filters) => join(filters, " AND ", binds),
+            Filter::Or(filters) => join(filters, " OR ", binds),
+        }
+    }
+
+    /// Renders the filter with its values inlined as literals, for index
+    /// definitions, which cannot take parameters.
+    pub fn to_literal_sql(&self) -> String {
+        let mut binds = Vec::new();
+        let sql = self.to_sql(&mut binds);
+
+        // identifiers are validated and operators fixed, so every `?` is a placeholder
+        let mut parts = sql.split('?');
+        let mut rendered = parts.next().unwrap_or_default().to_string();
+        for (part, value) in parts.zip(&binds) {
+            rendered.push_str(&value.literal());
+            rendered.push_str(part);
+        }
+
+        rendered
+    }
+}
+
+fn join(filters: &[Filter], separator: &str, binds: &mut Vec<SqlValue>) -> String {
+    let parts: Vec<String> = filters
+        .iter()
+        .map(|filter| format!("({})", filter.to_sql(binds)))
+        .collect();
+
+    parts.join(separator)
+}
+
+/// Parses `col=[not.]op.value`.
+fn parse_condition(schema: &TableSchema, column: &str, expression: &str) -> Result<Filter, Error> {
+    let column = schema
+        .column(column)
+        .ok_or_else(|| Error::Validation(format!("table {} has no column {}", schema.name.as_str(), column)))?;
+
+    let (op, value) = expression
+        .split_once('.')
+        .ok_or_else(|| Error::Validation(format!("filter on {} must look like operator.value", column.name.as_str())))?;
+
+    if op == "not" {
+        return Ok(Filter::Not(Box::new(parse_condition(schema, column.name.as_str(), value)?)));
+    }
+
+    let name = op;
+    let op = Operator::parse(name)?;
+    if !op.supports(&column.data_type) {
+        return Err(Error::Validation(format!("operator {} cannot be used on {} column {}",
+                                             name, column.data_type, column.name.as_str())));
+    }
+
+    let operand = match op {
+        Operator::Is => match value {
+            "null" => Operand::Null,
+            "true" | "false" if !matches!(column.data_type, DataType::Boolean) => {
+                return Err(Error::Validation(format!("is.{} can only be used on boolean columns", value)));
+            }
+            "true" => Operand::True,
+            "false" => Operand::False,
+            _ => return Err(Error::Validation(format!("is expects null, true or false, got {}", value))),
+        },
+        Operator::In => {
+            let items = value
+                .strip_prefix('(')
+                .and_then(|v| v.strip_suffix(')'))
+                .ok_or_else(|| Error::Validation(format!("in expects a list like (a,b), got {}", value)))?;
+            let items = split_list(items)?;
+            if items.is_empty() {
+                return Err(Error::Validation(format!("in on column {} needs at least one value", column.name.as_str())));
+            }
+            Operand::List(items.iter().map(|item| parse_value(column, item)).collect::<Result<_, _>>()?)
+        }
+        Operator::Like => Operand::Value(SqlValue::Text(glob_pattern(value))),
+        Operator::ILike => Operand::Value(SqlValue::Text(like_pattern(value))),
+        _ => Operand::Value(parse_value(column, value)?),
+    };
+
+    Ok(Filter::Condition {
+        column: column.name.clone(),
+        op,
+        operand,
+    })
+}
+
+/// Parses the `(item,item,...)` body of `and`/`or`, where an item is either
+/// `col.op.value` or a nested `and(...)`, `or(...)`, `not.and(...)` or
+/// `not.or(...)`.
+fn parse_group(schema: &TableSchema, value: &str) -> Result<Vec<Filter>, Error> {
+    let items = value
+        .strip_prefix('(')
+        .and_then(|v| v.strip_suffix(')'))
+        .ok_or_else(|| Error::Validation(format!("expected a parenthesised list of conditions, got {}", value)))?;
+
+    let mut filters = Vec::new();
+
+    for item in split_list(items)? {
+        let (negated, item) = match item.strip_prefix("not.") {
+            Some(rest) if rest.starts_with("and(") || rest.starts_with("or(") => (true, rest),
+            _ => (false, item.as_str()),
+        };
+
+        let filter = if let Some(group) = item.strip_prefix("and").filter(|g| g.starts_with('(')) {
+            Filter::And(parse_group(schema, group)?)
+        } else if let Some(group) = item.strip_prefix("or").filter(|g| g.starts_with('(')) {
+            Filter::Or(parse_group(schema, group)?)
+        } else {
+            let (column, expression) = item
+                .split_once('.')
+                .ok_or_else(|| Error::Validation(format!("condition {} must look like column.operator.value", item)))?;
+            parse_condition(schema, column, expression)?
+        };
+
+        filters.push(if negated { Filter::Not(Box::new(filter)) } else { filter });
+    }
+
+    if filters.is_empty() {
+        return Err(Error::Validation("and/or need at least one condition".to_string()));
+    }
+
+    Ok(filters)
+}
+
+/// Splits on commas outside parentheses and double quotes; quotes are
+/// removed so values may contain commas, as in `in.("a,b",c)`.
+fn split_list(list: &str) -> Result<Vec<String>, Error> {
+    let mut items = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0usize;
+    let mut quoted = false;
+
+    for c in list.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            '(' if !quoted => {
+                depth += 1;
+                current.push(c);
+            }
+            ')' if !quoted => {
+                depth = depth.checked_sub(1)
+                    .ok_or_else(|| Error::Validation(format!("unbalanced parentheses in {}", list)))?;
+                current.push(c);
+            }
+            ',' if !quoted && depth == 0 => items.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+
+    if quoted || depth != 0 {
+        return Err(Error::Validation(format!("unbalanced quotes or parentheses in {}", list)));
+    }
+
+    if !current.is_empty() || !items.is_empty() {
+        items.push(current);
+    }
+
+    Ok(items)
+}
+
+/// Parses a query string value according to the column's `DataType`.
+pub fn parse_value(column: &ColumnSchema, value: &str) -> Result<SqlValue, Error> {
+    let invalid = || Error::Validation(format!("{:?} is not a valid {} value for column {}",
+                                               value, column.data_type, column.name.as_str()));
+
+    match column.data_type {
+        DataType::Text => Ok(SqlValue::Text(value.to_string())),
+        DataType::Integer => value.parse().map(SqlValue::Integer).map_err(|_| invalid()),
+        DataType::Float => value
+            .parse::<f64>()
+            .ok()
+            .filter(|f| f.is_finite())
+            .map(SqlValue::Float)
+            .ok_or_else(invalid),
+        DataType::Boolean => match value {
+            "true" => Ok(SqlValue::Integer(1)),
+            "false" => Ok(SqlValue::Integer(0)),
+            _ => Err(invalid()),
+        },
+        DataType::TimeStamp => parse_timestamp(value)
+            .map(|timestamp| SqlValue::Text(sqlite_timestamp(timestamp)))
+            .ok_or_else(invalid),
+    }
+}
+
+/// Turns a `*` wildcard pattern into a GLOB pattern matching the other
+/// GLOB metacharacters literally.
+fn glob_pattern(pattern: &str) -> String {
+    pattern
+        .chars()
+        .map(|c| match c {
+            '?' => "[?]".to_string(),
+            '[' => "[[]".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+/// Turns a `*` wildcard pattern into a LIKE pattern escaped with `\`.
+fn like_pattern(pattern: &str) -> String {
+    pattern
+        .chars()
+        .map(|c| match c {
+            '*' => "%".to_string(),
+            '%' | '_' | '\\' => format!("\\{}", c),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema() -> TableSchema {
+        serde_json::from_value(serde_json::json!({
+            "name": "people",
+            "columns": [
+                {"name": "name", "type": "text"},
+                {"name": "age", "type": "integer"},
+                {"name": "score", "type": "float"},
+                {"name": "active", "type": "boolean"},
+                {"name": "born", "type": "timestamp"},
+            ]
+        })).unwrap()
+    }
+
+    fn params(query: &[(&str, &str)]) -> Vec<(String, String)> {
+        query.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    fn compile(query: &[(&str, &str)]) -> Result<(String, Vec<SqlValue>), Error> {
+        let filter = Filter::from_params(&schema(), &params(query))?.unwrap();
+        let mut binds = Vec::new();
+        let sql = filter.to_sql(&mut binds);
+        Ok((sql, binds))
+    }
+
+    #[test]
+    fn test_simple_conditions() {
+        let (sql, binds) = compile(&[("age", "gt.30"), ("name", "like.*bob*")]).ok().unwrap();
+        assert_eq!(sql, "(\"age\" > ?) AND (\"name\" GLOB ?)");
+        assert_eq!(binds, vec![SqlValue::Integer(30), SqlValue::Text("*bob*".to_string())]);
+
+        let (sql, binds) = compile(&[("name", "ilike.*50%*")]).ok().unwrap();
+        assert_eq!(sql, "\"name\" LIKE ? ESCAPE '\\'");
+        assert_eq!(binds, vec![SqlValue::Text("%50\\%%".to_string())]);
+    }
+
+    #[test]
+    fn test_logic_groups() {
+        let (sql, binds) = compile(&[("or", "(age.eq.1,name.is.null,and(score.gte.1.5,active.is.true))")]).ok().unwrap();
+        assert_eq!(sql, "(\"age\" = ?) OR (\"name\" IS NULL) OR ((\"score\" >= ?) AND (\"active\" IS 1))");
+        assert_eq!(binds, vec![SqlValue::Integer(1), SqlValue::Float(1.5)]);
+
+        let (sql, _) = compile(&[("age", "not.in.(1,2)")]).ok().unwrap();
+        assert_eq!(sql, "NOT (\"age\" IN (?, ?))");
+    }
+
+    #[test]
+    fn test_values_are_typed() {
+        let (_, binds) = compile(&[("born", "lt.2024-01-01T02:00:00+02:00"), ("active", "eq.false")]).ok().unwrap();
+        assert_eq!(binds, vec![SqlValue::Text("2024-01-01 00:00:00".to_string()), SqlValue::Integer(0)]);
+
+        let (_, binds) = compile(&[("name", "in.(\"a,b\",c)")]).ok().unwrap();
+        assert_eq!(binds, vec![SqlValue::Text("a,b".to_string()), SqlValue::Text("c".to_string())]);
+    }
+
+    #[test]
+    fn test_rejected_filters() {
+        for query in [
+            ("email", "eq.x"),
+            ("age", "like.*1*"),
+            ("age", "eq.thirty"),
+            ("active", "gt.true"),
+            ("age", "between.1"),
+            ("name", "is.true"),
+            ("or", "(age.eq.1"),
+            ("age", "in.()"),
+            ("score", "gt.inf"),
+            ("score", "eq.NaN"),
+            ("score", "in.(1,-infinity)"),
+        ] {
+            assert!(matches!(compile(&[query]), Err(Error::Validation(_))), "{:?} should be rejected", query);
+        }
+    }
+
+    #[test]
+    fn test_reserved_params_are_skipped() {
+        assert!(Filter::from_params(&schema(), &params(&[("limit", "10")])).ok().unwrap().is_none());
+        assert!(is_reserved_param("Order") && is_reserved_param("or"));
+        assert!(!is_reserved_param("ordered"));
+    }
+}
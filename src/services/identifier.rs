@@ -0,0 +1,166 @@
+//! Validated SQL identifiers. Every table and column name that ends up in a
+//! dynamically built statement goes through [`Identifier`], which is always
+//! emitted quoted.
+
+use std::fmt;
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+
+pub const MAX_LENGTH: usize = 63;
+
+/// Column names `CrudService` adds to the tables it creates by default.
+pub const RESERVED_NAMES: &[&str] = &["id", "created_at", "updated_at"];
+
+/// Name prefixes owned by SQLite and by fastfood's own bookkeeping tables.
+pub const RESERVED_PREFIXES: &[&str] = &["sqlite_", "_fastfood"];
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Identifier(String);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidIdentifier(pub String);
+
+impl fmt::Display for InvalidIdentifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Identifier {
+    /// Validates a client supplied name: ASCII letters, digits and
+    /// underscores, not starting with a digit, at most `MAX_LENGTH` bytes and
+    /// not one of the reserved names or prefixes.
+    pub fn new(name: impl Into<String>) -> Result<Self, InvalidIdentifier> {
+        let name = name.into();
+        let lower = name.to_lowercase();
+
+        if name.is_empty() || name.len() > MAX_LENGTH {
+            return Err(InvalidIdentifier(format!(
+                "identifier {:?} must be between 1 and {} characters long", name, MAX_LENGTH)));
+        }
+
+        if name.starts_with(|c: char| c.is_ascii_digit())
+            || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(InvalidIdentifier(format!(
+                "identifier {:?} may only contain letters, digits and underscores and must not start with a digit", name)));
+        }
+
+        if RESERVED_NAMES.contains(&lower.as_str()) {
+            return Err(InvalidIdentifier(format!("identifier {:?} is reserved", name)));
+        }
+
+        if RESERVED_PREFIXES.iter().any(|prefix| lower.starts_with(prefix)) {
+            return Err(InvalidIdentifier(format!("identifier {:?} uses a reserved prefix", name)));
+        }
+
+        Ok(Identifier(name))
+    }
+
+    /// Validates a name referring to an existing column, which unlike the
+    /// name of a new column may be one of the managed `RESERVED_NAMES`.
+    pub fn column(name: impl Into<String>) -> Result<Self, InvalidIdentifier> {
+        let name = name.into();
+
+        match RESERVED_NAMES.iter().find(|reserved| reserved.eq_ignore_ascii_case(&name)) {
+            Some(reserved) => Ok(Identifier::managed(*reserved)),
+            None => Identifier::new(name),
+        }
+    }
+
+    /// Wraps a name fastfood owns itself or read back from the SQLite
+    /// catalog, skipping validation. Quoting still applies when rendered.
+    pub fn managed(name: impl Into<String>) -> Self {
+        Identifier(name.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Renders the identifier double-quoted, ready to be placed in SQL.
+impl fmt::Display for Identifier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"{}\"", self.0.replace('"', "\"\""))
+    }
+}
+
+/// Deserializes a column name with [`Identifier::column`], for fields
+/// naming columns that already exist.
+pub fn deserialize_column<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Identifier, D::Error> {
+    Identifier::column(String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+impl FromStr for Identifier {
+    type Err = InvalidIdentifier;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Identifier::new(s)
+    }
+}
+
+impl TryFrom<String> for Identifier {
+    type Error = InvalidIdentifier;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Identifier::new(value)
+    }
+}
+
+impl From<Identifier> for String {
+    fn from(value: Identifier) -> Self {
+        value.0
+    }
+}
+
+impl PartialEq<str> for Identifier {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&str> for Identifier {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_identifiers() {
+        for name in ["name", "Name_2", "_private", "a"] {
+            assert!(Identifier::new(name).is_ok(), "{} should be valid", name);
+        }
+    }
+
+    #[test]
+    fn test_invalid_identifiers() {
+        let too_long = "a".repeat(MAX_LENGTH + 1);
+        for name in ["", "2fast", "drop table", "name\"; --", "naïve", too_long.as_str()] {
+            assert!(Identifier::new(name).is_err(), "{} should be invalid", name);
+        }
+    }
+
+    #[test]
+    fn test_reserved_identifiers() {
+        for name in ["id", "ID", "created_at", "updated_at", "sqlite_master", "SQLITE_x", "_fastfood_tables"] {
+            assert!(Identifier::new(name).is_err(), "{} should be reserved", name);
+        }
+    }
+
+    #[test]
+    fn test_display_is_quoted() {
+        assert_eq!(Identifier::new("name").unwrap().to_string(), "\"name\"");
+        assert_eq!(Identifier::managed("odd\"name").to_string(), "\"odd\"\"name\"");
+    }
+
+    #[test]
+    fn test_deserialize_validates() {
+        assert!(serde_json::from_str::<Identifier>("\"name\"").is_ok());
+        assert!(serde_json::from_str::<Identifier>("\"x; DROP TABLE y\"").is_err());
+    }
+}
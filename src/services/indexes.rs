@@ -0,0 +1,203 @@
+//! Secondary indexes, stored with the table's schema so they can be
+//! recreated whenever the table is rebuilt. Keys are columns or one of a few
+//! allow-listed functions of a column, and partial indexes give their
+//! `where` in the filter syntax of listings, so no client SQL reaches SQLite.
+
+use serde::{Deserialize, Serialize};
+use crate::services::crud::{DataType, Error, TableSchema};
+use crate::services::filter::{Filter, RESERVED_PARAMS};
+use crate::services::identifier::{deserialize_column, Identifier};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IndexSchema {
+    pub name: Identifier,
+    pub columns: Vec<IndexKey>,
+    #[serde(default)]
+    pub unique: bool,
+    /// Limits the index to the rows matching filters such as
+    /// `status=eq.active&deleted_at=is.null`.
+    #[serde(default, rename = "where")]
+    pub filter: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum IndexKey {
+    Column(#[serde(deserialize_with = "deserialize_column")] Identifier),
+    Expression {
+        expr: IndexFunction,
+        #[serde(deserialize_with = "deserialize_column")]
+        column: Identifier,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexFunction {
+    Lower,
+    Upper,
+    Trim,
+    Length,
+    /// The date part of a timestamp.
+    Date,
+}
+
+impl IndexFunction {
+    fn sql(&self) -> &'static str {
+        match self {
+            IndexFunction::Lower => "lower",
+            IndexFunction::Upper => "upper",
+            IndexFunction::Trim => "trim",
+            IndexFunction::Length => "length",
+            IndexFunction::Date => "date",
+        }
+    }
+
+    fn supports(&self, data_type: &DataType) -> bool {
+        match self {
+            IndexFunction::Lower | IndexFunction::Upper | IndexFunction::Trim | IndexFunction::Length => {
+                matches!(data_type, DataType::Text)
+            }
+            IndexFunction::Date => matches!(data_type, DataType::TimeStamp | DataType::Text),
+        }
+    }
+}
+
+impl IndexKey {
+    pub fn column(&self) -> &Identifier {
+        match self {
+            IndexKey::Column(column) | IndexKey::Expression { column, .. } => column,
+        }
+    }
+
+    fn column_mut(&mut self) -> &mut Identifier {
+        match self {
+            IndexKey::Column(column) | IndexKey::Expression { column, .. } => column,
+        }
+    }
+}
+
+impl IndexSchema {
+    /// `CREATE INDEX` for the index on `table`, which must include the
+    /// managed columns. Fails if a key or the filter does not fit the table.
+    pub fn sql(&self, table: &TableSchema) -> Result<String, Error> {
+        let invalid = |message: String| Error::Validation(format!("invalid index {}: {}", self.name.as_str(), message));
+
+        if self.columns.is_empty() {
+            return Err(invalid("at least one column is required".to_string()));
+        }
+
+        let mut keys = Vec::with_capacity(self.columns.len());
+        for key in &self.columns {
+            let column = table
+                .columns
+                .iter()
+                .find(|column| column.name.as_str().eq_ignore_ascii_case(key.column().as_str()))
+                .ok_or_else(|| invalid(format!("table {} has no column {}", table.name.as_str(), key.column().as_str())))?;
+
+            keys.push(match key {
+                IndexKey::Column(_) => column.name.to_string(),
+                IndexKey::Expression { expr, .. } if expr.supports(&column.data_type) => {
+                    format!("{}({})", expr.sql(), column.name)
+                }
+                IndexKey::Expression { expr, .. } => {
+                    return Err(invalid(format!("{:?} cannot be applied to {} column {}", expr, column.data_type, column.name.as_str())));
+                }
+            });
+        }
+
+        let filter = match self.filter {
+            Some(ref filter) => format!(" WHERE {}", parse_filter(table, filter).map_err(|e| invalid(e.to_string()))?.to_literal_sql()),
+            None => String::new(),
+        };
+
+        Ok(format!("CREATE {}INDEX {} ON {} ({}){}",
+                   if self.unique { "UNIQUE " } else { "" },
+                   self.name,
+                   table.name,
+                   keys.join(", "),
+                   filter))
+    }
+
+    /// Follows a column rename. Filters are not rewritten, so an index
+    /// whose filter names the column no longer fits the table afterwards.
+    pub fn rename_column(&mut self, from: &str, to: &Identifier) {
+        for key in self.columns.iter_mut() {
+            if key.column().as_str().eq_ignore_ascii_case(from) {
+                *key.column_mut() = to.clone();
+            }
+        }
+    }
+}
+
+/// Parses `col=op.value&...` with the filter syntax of listings.
+fn parse_filter(table: &TableSchema, filter: &str) -> Result<Filter, Error> {
+    let mut params = Vec::new();
+
+    for pair in filter.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair
+            .split_once('=')
+            .ok_or_else(|| Error::Validation(format!("where expects col=op.value pairs, got {}", pair)))?;
+
+        if RESERVED_PARAMS.contains(&key) {
+            return Err(Error::Validation(format!("{} cannot be used in where", key)));
+        }
+        params.push((key.to_string(), value.to_string()));
+    }
+
+    Filter::from_params(table, &params)?
+        .ok_or_else(|| Error::Validation("where must not be empty".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn table() -> TableSchema {
+        serde_json::from_value(json!({
+            "name": "people",
+            "columns": [
+                {"name": "tenant_id", "type": "integer"},
+                {"name": "email", "type": "text"},
+                {"name": "status", "type": "text"},
+                {"name": "joined", "type": "timestamp"},
+                {"name": "price", "type": "float"},
+            ]
+        })).unwrap()
+    }
+
+    fn sql(index: serde_json::Value) -> Result<String, Error> {
+        serde_json::from_value::<IndexSchema>(index).unwrap().sql(&table())
+    }
+
+    #[test]
+    fn test_sql() {
+        assert_eq!(sql(json!({"name": "people_tenant_email", "columns": ["tenant_id", {"expr": "lower", "column": "email"}], "unique": true})).ok().unwrap(),
+                   "CREATE UNIQUE INDEX \"people_tenant_email\" ON \"people\" (\"tenant_id\", lower(\"email\"))");
+
+        assert_eq!(sql(json!({"name": "active_people", "columns": [{"expr": "date", "column": "joined"}],
+                              "where": "status=in.(active,new)&email=not.is.null&tenant_id=gt.3"})).ok().unwrap(),
+                   "CREATE INDEX \"active_people\" ON \"people\" (date(\"joined\")) \
+                    WHERE (\"status\" IN ('active', 'new')) AND (NOT (\"email\" IS NULL)) AND (\"tenant_id\" > 3)");
+
+        assert_eq!(sql(json!({"name": "quoted", "columns": ["email"], "where": "status=eq.o'brien"})).ok().unwrap(),
+                   "CREATE INDEX \"quoted\" ON \"people\" (\"email\") WHERE \"status\" = 'o''brien'");
+    }
+
+    #[test]
+    fn test_invalid_indexes() {
+        assert!(sql(json!({"name": "none", "columns": []})).is_err());
+        assert!(sql(json!({"name": "missing", "columns": ["age"]})).is_err());
+        assert!(sql(json!({"name": "lowered", "columns": [{"expr": "lower", "column": "tenant_id"}]})).is_err());
+        assert!(sql(json!({"name": "filtered", "columns": ["email"], "where": "age=gt.3"})).is_err());
+        assert!(sql(json!({"name": "filtered", "columns": ["email"], "where": "limit=3"})).is_err());
+        for filter in ["price=gt.inf", "price=eq.NaN", "price=in.(1.5,-infinity)"] {
+            assert!(matches!(sql(json!({"name": "priced", "columns": ["email"], "where": filter})), Err(Error::Validation(_))));
+        }
+
+        assert!(serde_json::from_value::<IndexSchema>(json!({"name": "bad", "columns": [{"expr": "random()", "column": "email"}]})).is_err());
+        assert!(serde_json::from_value::<IndexSchema>(json!({"name": "bad", "columns": ["email); DROP TABLE people; --"]})).is_err());
+    }
+}
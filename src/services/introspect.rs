@@ -0,0 +1,115 @@
+//! Reads the shape of tables fastfood did not create from SQLite's own catalog.
+
+use diesel::{OptionalExtension, QueryableByName, QueryResult, RunQueryDsl, SqliteConnection};
+use diesel::sql_types::{Integer, Nullable, Text};
+use crate::services::crud::{ColumnSchema, DataType, TableSchema};
+use crate::services::defaults::ColumnDefault;
+use crate::services::identifier::Identifier;
+use crate::services::rules::ColumnRules;
+
+#[derive(QueryableByName)]
+struct TableName {
+    #[diesel(sql_type = Text)]
+    name: String,
+}
+
+#[derive(QueryableByName)]
+struct ColumnInfo {
+    #[diesel(sql_type = Text)]
+    name: String,
+    #[diesel(sql_type = Text)]
+    declared_type: String,
+    #[diesel(sql_type = Integer)]
+    not_null: i32,
+    #[diesel(sql_type = Nullable<Text>)]
+    default_value: Option<String>,
+    #[diesel(sql_type = Integer)]
+    pk: i32,
+}
+
+/// Whether `name` belongs to SQLite or to fastfood's own bookkeeping,
+/// compared case-insensitively as SQLite compares table names.
+pub fn is_system_table(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name.starts_with("sqlite_") || name.starts_with("_fastfood_")
+}
+
+/// Lists user tables present in the database, skipping system tables.
+pub fn table_names(conn: &mut SqliteConnection) -> QueryResult<Vec<String>> {
+    let names = diesel::sql_query("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")
+        .load::<TableName>(conn)?;
+
+    Ok(names
+        .into_iter()
+        .map(|table| table.name)
+        .filter(|name| !is_system_table(name))
+        .collect())
+}
+
+pub fn table_exists(conn: &mut SqliteConnection, name: &str) -> QueryResult<bool> {
+    let table = diesel::sql_query("SELECT name FROM sqlite_master WHERE type = 'table' AND name = ? COLLATE NOCASE")
+        .bind::<Text, _>(name)
+        .get_result::<TableName>(conn)
+        .optional()?;
+
+    Ok(table.is_some())
+}
+
+/// Rebuilds a best-effort `TableSchema` from `pragma_table_info` and, for
+/// `UNIQUE` constraints, `pragma_index_list`. Keys spanning several columns
+/// are reported as table constraints, others on their column.
+pub fn describe(conn: &mut SqliteConnection, name: &str) -> QueryResult<TableSchema> {
+    let columns = diesel::sql_query("SELECT name, type AS declared_type, \"notnull\" AS not_null, \
+                                     dflt_value AS default_value, pk FROM pragma_table_info(?) ORDER BY cid")
+        .bind::<Text, _>(name)
+        .load::<ColumnInfo>(conn)?;
+
+    let mut key: Vec<&ColumnInfo> = columns.iter().filter(|column| column.pk > 0).collect();
+    key.sort_by_key(|column| column.pk);
+    let composite_key = key.len() > 1;
+
+    let mut unique = Vec::new();
+    for index in unique_constraints(conn, name)? {
+        let indexed = diesel::sql_query("SELECT name FROM pragma_index_info(?) ORDER BY seqno")
+            .bind::<Text, _>(&index)
+            .load::<TableName>(conn)?;
+        unique.push(indexed.into_iter().map(|column| Identifier::managed(column.name)).collect::<Vec<_>>());
+    }
+
+    let unique_alone = |column: &str| unique.iter().any(|names| names.len() == 1 && names[0] == column);
+
+    Ok(TableSchema {
+        name: Identifier::managed(name),
+        primary_key: if composite_key {
+            Some(key.iter().map(|column| Identifier::managed(column.name.as_str())).collect())
+        } else {
+            None
+        },
+        columns: columns
+            .iter()
+            .map(|column| ColumnSchema {
+                name: Identifier::managed(column.name.as_str()),
+                data_type: DataType::from_declared(&column.declared_type),
+                primary_key: Some(column.pk > 0 && !composite_key),
+                auto_increment: None,
+                unique: Some(unique_alone(&column.name)),
+                not_null: Some(column.not_null != 0),
+                default: column.default_value.as_deref().and_then(ColumnDefault::from_sql),
+                references: None,
+                rules: ColumnRules::default(),
+            })
+            .collect(),
+        indexes: Vec::new(),
+        unique: unique.into_iter().filter(|names| names.len() > 1).collect(),
+        options: None,
+    })
+}
+
+/// Names of the indexes SQLite made for the `UNIQUE` constraints of `table`.
+fn unique_constraints(conn: &mut SqliteConnection, table: &str) -> QueryResult<Vec<String>> {
+    let indexes = diesel::sql_query("SELECT name FROM pragma_index_list(?) WHERE origin = 'u' ORDER BY seq DESC")
+        .bind::<Text, _>(table)
+        .load::<TableName>(conn)?;
+
+    Ok(indexes.into_iter().map(|index| index.name).collect())
+}
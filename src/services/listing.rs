@@ -0,0 +1,383 @@
+//! The query parameters that shape a row listing on top of the filters of
+//! `services::filter`:
+//!
+//! - `select=id,name` limits the columns returned;
+//! - `order=created_at.desc,id.asc` sorts, `id` always being added as the
+//!   final tie-breaker so every row has a unique position;
+//! - `limit=50` sets the page size;
+//! - `cursor=...` continues after the last row of a previous page. Cursors
+//!   are opaque to clients: base64 JSON holding the order they were issued
+//!   for and the sort key values of that row, turned into a keyset `WHERE`
+//!   clause so pages cost the same however deep they are;
+//! - `count=exact|estimated` adds a `total_count` to the response;
+//! - `embed=customer,items` nests related rows, see `services::embed`.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::services::codec;
+use crate::services::crud::{ColumnSchema, Error, Row, TableSchema};
+use crate::services::embed::Embed;
+use crate::services::filter::Filter;
+use crate::services::identifier::Identifier;
+use crate::services::value::SqlValue;
+
+/// The column every listing is finally ordered by.
+const TIE_BREAKER: &str = "id";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortKey {
+    pub column: Identifier,
+    pub direction: Direction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CountMode {
+    /// `COUNT(*)` over every row matching the filters.
+    Exact,
+    /// The highest rowid for unfiltered listings, otherwise a count that
+    /// stops at `ESTIMATE_SCAN_LIMIT` rows.
+    Estimated,
+}
+
+/// Rows an estimated count looks at before giving up.
+pub const ESTIMATE_SCAN_LIMIT: i64 = 10_000;
+
+#[derive(Debug, Clone)]
+pub struct ListQuery {
+    pub filter: Option<Filter>,
+    pub order: Vec<SortKey>,
+    pub select: Option<Vec<Identifier>>,
+    pub limit: i64,
+    /// Sort key values of the row the page starts after.
+    pub after: Option<Vec<SqlValue>>,
+    pub count: Option<CountMode>,
+    pub embed: Option<Embed>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Cursor {
+    order: String,
+    after: Vec<Value>,
+}
+
+impl ListQuery {
+    pub fn parse(schema: &TableSchema, params: &[(String, String)], default_limit: i64, max_limit: i64) -> Result<ListQuery, Error> {
+        let param = |name: &str| params.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str());
+
+        let limit = match param("limit") {
+            Some(limit) => match limit.parse::<i64>() {
+                Ok(limit) if (1..=max_limit).contains(&limit) => limit,
+                _ => return Err(Error::Validation(format!("limit must be between 1 and {}", max_limit))),
+            },
+            None => default_limit,
+        };
+
+        let order = parse_order(schema, param("order").unwrap_or(""))?;
+
+        let select = match param("select") {
+            Some(select) => Some(select
+                .split(',')
+                .map(|name| resolve(schema, name.trim()))
+                .collect::<Result<Vec<_>, _>>()?),
+            None => None,
+        };
+
+        let count = match param("count") {
+            Some("exact") => Some(CountMode::Exact),
+            Some("estimated") => Some(CountMode::Estimated),
+            Some(count) => return Err(Error::Validation(format!("count must be exact or estimated, got {}", count))),
+            None => None,
+        };
+
+        let embed = param("embed").map(Embed::parse).transpose()?;
+
+        let mut query = ListQuery {
+            filter: Filter::from_params(schema, params)?,
+            order,
+            select,
+            limit,
+            after: None,
+            count,
+            embed,
+        };
+
+        if let Some(cursor) = param("cursor") {
+            query.after = Some(query.decode_cursor(schema, cursor)?);
+        }
+
+        Ok(query)
+    }
+
+    /// The `WHERE` clause combining the filters with the cursor position.
+    pub fn where_sql(&self, binds: &mut Vec<SqlValue>) -> String {
+        let mut conditions = Vec::new();
+
+        if let Some(ref filter) = self.filter {
+            conditions.push(format!("({})", filter.to_sql(binds)));
+        }
+        if let Some(ref after) = self.after {
+            conditions.push(format!("({})", self.keyset_sql(after, binds)));
+        }
+
+        if conditions.is_empty() {
+            String::new()
+        } else {
+            format!(" WHERE {}", conditions.join(" AND "))
+        }
+    }
+
+    /// The filters alone, for counting.
+    pub fn filter_sql(&self, binds: &mut Vec<SqlValue>) -> String {
+        match self.filter {
+            Some(ref filter) => format!(" WHERE {}", filter.to_sql(binds)),
+            None => String::new(),
+        }
+    }
+
+    pub fn order_sql(&self) -> String {
+        let keys: Vec<String> = self.order
+            .iter()
+            .map(|key| format!("{} {}", key.column, match key.direction {
+                Direction::Asc => "ASC",
+                Direction::Desc => "DESC",
+            }))
+            .collect();
+
+        format!(" ORDER BY {}", keys.join(", "))
+    }
+
+    /// The columns to read: those selected plus the sort keys, which the
+    /// cursor needs, in table order. Embedding needs the key columns, so
+    /// every column is read then.
+    pub fn columns(&self, schema: &TableSchema) -> Vec<ColumnSchema> {
+        schema.columns
+            .iter()
+            .filter(|column| match self.select {
+                Some(_) if self.embed.is_some() => true,
+                Some(ref select) => select.contains(&column.name) || self.order.iter().any(|key| key.column == column.name),
+                None => true,
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Drops the columns that were only read for the cursor or embedding,
+    /// keeping the embedded relations.
+    pub fn project(&self, mut row: Row) -> Row {
+        if let Some(ref select) = self.select {
+            let embedded = |name: &str| self.embed.as_ref().is_some_and(|embed| embed.contains(name));
+            row.retain(|name, _| select.iter().any(|column| column == name.as_str()) || embedded(name));
+        }
+
+        row
+    }
+
+    /// The cursor continuing after `row`.
+    pub fn cursor_after(&self, row: &Row) -> String {
+        let cursor = Cursor {
+            order: self.signature(),
+            after: self.order
+                .iter()
+                .map(|key| row.get(key.column.as_str()).cloned().unwrap_or(Value::Null))
+                .collect(),
+        };
+
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(&cursor).expect("cursor should be serializable"))
+    }
+
+    /// Recovers the sort key values of a cursor, encoded for comparison with
+    /// stored values. Values that were stored in an unexpected form are
+    /// used as they are.
+    fn decode_cursor(&self, schema: &TableSchema, cursor: &str) -> Result<Vec<SqlValue>, Error> {
+        let invalid = || Error::Validation("invalid cursor".to_string());
+
+        let bytes = URL_SAFE_NO_PAD.decode(cursor).map_err(|_| invalid())?;
+        let cursor: Cursor = serde_json::from_slice(&bytes).map_err(|_| invalid())?;
+
+        if cursor.order != self.signature() || cursor.after.len() != self.order.len() {
+            return Err(Error::Validation("cursor was issued for a different order".to_string()));
+        }
+
+        self.order
+            .iter()
+            .zip(&cursor.after)
+            .map(|(key, value)| match schema.column(key.column.as_str()) {
+                Some(column) => codec::encode_value(column, value).or_else(|_| SqlValue::from_json(value).ok_or_else(invalid)),
+                None => Err(invalid()),
+            })
+            .collect()
+    }
+
+    /// Identifies the order a cursor belongs to, e.g. `created_at.desc,id.asc`.
+    fn signature(&self) -> String {
+        let keys: Vec<String> = self.order
+            .iter()
+            .map(|key| format!("{}.{}", key.column.as_str(), match key.direction {
+                Direction::Asc => "asc",
+                Direction::Desc => "desc",
+            }))
+            .collect();
+
+        keys.join(",")
+    }
+
+    /// Matches rows sorting strictly after `after`: for sort keys
+    /// `(a, b, id)` that is `a > ? OR (a IS ? AND b > ?) OR (a IS ? AND b IS ? AND id > ?)`.
+    /// SQLite sorts NULL first, so nothing follows NULL in descending order
+    /// and everything non-NULL follows it in ascending order.
+    fn keyset_sql(&self, after: &[SqlValue], binds: &mut Vec<SqlValue>) -> String {
+        let mut alternatives = Vec::new();
+
+        for (i, key) in self.order.iter().enumerate() {
+            let mut terms = Vec::new();
+
+            for (previous, value) in self.order.iter().zip(after).take(i) {
+                binds.push(value.clone());
+                terms.push(format!("{} IS ?", previous.column));
+            }
+
+            let value = &after[i];
+            terms.push(match (key.direction, value) {
+                (Direction::Asc, SqlValue::Null) => format!("{} IS NOT NULL", key.column),
+                (Direction::Desc, SqlValue::Null) => "0".to_string(),
+                (Direction::Asc, value) => {
+                    binds.push(value.clone());
+                    format!("{} > ?", key.column)
+                }
+                (Direction::Desc, value) => {
+                    binds.push(value.clone());
+                    format!("({} < ? OR {} IS NULL)", key.column, key.column)
+                }
+            });
+
+            alternatives.push(format!("({})", terms.join(" AND ")));
+        }
+
+        alternatives.join(" OR ")
+    }
+}
+
+/// Parses `col[.asc|.desc],...` and appends the tie-breaker.
+fn parse_order(schema: &TableSchema, order: &str) -> Result<Vec<SortKey>, Error> {
+    let mut keys: Vec<SortKey> = Vec::new();
+
+    for item in order.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+        let (name, direction) = match item.split_once('.') {
+            Some((name, "asc")) => (name, Direction::Asc),
+            Some((name, "desc")) => (name, Direction::Desc),
+            Some((_, direction)) => {
+                return Err(Error::Validation(format!("order direction must be asc or desc, got {}", direction)));
+            }
+            None => (item, Direction::Asc),
+        };
+
+        let column = resolve(schema, name)?;
+        if keys.iter().any(|key| key.column == column) {
+            return Err(Error::Validation(format!("column {} appears twice in order", name)));
+        }
+
+        keys.push(SortKey { column, direction });
+    }
+
+    if !keys.iter().any(|key| key.column == TIE_BREAKER) {
+        keys.push(SortKey {
+            column: resolve(schema, TIE_BREAKER)?,
+            direction: Direction::Asc,
+        });
+    }
+
+    Ok(keys)
+}
+
+fn resolve(schema: &TableSchema, name: &str) -> Result<Identifier, Error> {
+    schema
+        .column(name)
+        .map(|column| column.name.clone())
+        .ok_or_else(|| Error::Validation(format!("table {} has no column {}", schema.name.as_str(), name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schema() -> TableSchema {
+        let mut schema: TableSchema = serde_json::from_value(json!({
+            "name": "people",
+            "columns": [
+                {"name": "name", "type": "text"},
+                {"name": "age", "type": "integer"},
+            ]
+        })).unwrap();
+
+        let mut id = schema.columns[1].clone();
+        id.name = Identifier::managed("id");
+        schema.columns.insert(0, id);
+        schema
+    }
+
+    fn parse(query: &[(&str, &str)]) -> Result<ListQuery, Error> {
+        let params: Vec<(String, String)> = query.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        ListQuery::parse(&schema(), &params, 10, 100)
+    }
+
+    #[test]
+    fn test_order_gets_tie_breaker() {
+        let query = parse(&[("order", "age.desc,name")]).ok().unwrap();
+        assert_eq!(query.order_sql(), " ORDER BY \"age\" DESC, \"name\" ASC, \"id\" ASC");
+        assert_eq!(query.limit, 10);
+
+        let query = parse(&[("order", "id.desc")]).ok().unwrap();
+        assert_eq!(query.order_sql(), " ORDER BY \"id\" DESC");
+    }
+
+    #[test]
+    fn test_cursor_round_trip() {
+        let query = parse(&[("order", "age.desc")]).ok().unwrap();
+        let row: Row = serde_json::from_value(json!({"id": 7, "name": "bob", "age": 30})).unwrap();
+        let cursor = query.cursor_after(&row);
+
+        let next = parse(&[("order", "age.desc"), ("cursor", &cursor)]).ok().unwrap();
+        assert_eq!(next.after, Some(vec![SqlValue::Integer(30), SqlValue::Integer(7)]));
+
+        let mut binds = Vec::new();
+        assert_eq!(next.where_sql(&mut binds),
+                   " WHERE (((\"age\" < ? OR \"age\" IS NULL)) OR (\"age\" IS ? AND \"id\" > ?))");
+        assert_eq!(binds, vec![SqlValue::Integer(30), SqlValue::Integer(30), SqlValue::Integer(7)]);
+
+        assert!(matches!(parse(&[("order", "name"), ("cursor", &cursor)]), Err(Error::Validation(_))));
+        assert!(matches!(parse(&[("cursor", "not-a-cursor")]), Err(Error::Validation(_))));
+    }
+
+    #[test]
+    fn test_select_keeps_sort_columns_for_the_cursor() {
+        let query = parse(&[("select", "name"), ("order", "age")]).ok().unwrap();
+        let columns: Vec<String> = query.columns(&schema()).into_iter().map(|column| column.name.into()).collect();
+        assert_eq!(columns, vec!["id", "name", "age"]);
+
+        let row: Row = serde_json::from_value(json!({"id": 7, "name": "bob", "age": 30})).unwrap();
+        assert_eq!(Value::Object(query.project(row)), json!({"name": "bob"}));
+    }
+
+    #[test]
+    fn test_rejected_params() {
+        for query in [
+            ("order", "email"),
+            ("order", "age.sideways"),
+            ("order", "age,age.desc"),
+            ("select", "id,email"),
+            ("limit", "101"),
+            ("count", "roughly"),
+        ] {
+            assert!(matches!(parse(&[query]), Err(Error::Validation(_))), "{:?} should be rejected", query);
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! The `_fastfood_migrations` system table. Every schema change made
+//! through `CrudService` is recorded as a numbered migration holding the
+//! statements that performed it, the statements undoing it and the schema
+//! it left behind, giving an audit trail and a way to roll changes back.
+
+use diesel::{OptionalExtension, QueryableByName, QueryResult, RunQueryDsl, SqliteConnection};
+use diesel::result::Error;
+use diesel::sql_types::{BigInt, Nullable, Text};
+use serde::Serialize;
+use crate::services::crud::TableSchema;
+use crate::services::identifier::Identifier;
+
+pub const MIGRATIONS_TABLE: &str = "_fastfood_migrations";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationKind {
+    Create,
+    Alter,
+    Drop,
+}
+
+impl MigrationKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MigrationKind::Create => "create",
+            MigrationKind::Alter => "alter",
+            MigrationKind::Drop => "drop",
+        }
+    }
+
+    fn parse(kind: &str) -> Option<MigrationKind> {
+        match kind {
+            "create" => Some(MigrationKind::Create),
+            "alter" => Some(MigrationKind::Alter),
+            "drop" => Some(MigrationKind::Drop),
+            _ => None,
+        }
+    }
+}
+
+/// A schema change about to be recorded.
+#[derive(Debug, Clone, Serialize)]
+pub struct Change {
+    pub table_name: String,
+    pub kind: MigrationKind,
+    pub forward: Vec<String>,
+    /// Undoes `forward`. Rows of a dropped table are not kept, so undoing a
+    /// drop recreates the table empty.
+    pub reverse: Vec<String>,
+    /// The stored schema before the change, `None` if the table is new.
+    pub previous_schema: Option<TableSchema>,
+    /// The stored schema after the change, `None` once dropped.
+    pub schema: Option<TableSchema>,
+    /// For an alteration, the column of `schema` each column of
+    /// `previous_schema` is restored from by `reverse`, `None` for columns
+    /// it leaves empty. Missing from alterations recorded before it was.
+    #[serde(skip)]
+    pub reverse_sources: Option<Vec<Option<Identifier>>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Migration {
+    pub version: i64,
+    #[serde(flatten)]
+    pub change: Change,
+    pub created_at: String,
+    pub rolled_back_at: Option<String>,
+}
+
+/// A migration just rolled back.
+#[derive(Debug, Clone, Serialize)]
+pub struct Rollback {
+    #[serde(flatten)]
+    pub migration: Migration,
+    /// Set when the rollback could not restore everything: undoing a drop
+    /// brings back the table's structure only, not its rows.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warning: Option<String>,
+}
+
+#[derive(QueryableByName)]
+struct MigrationRow {
+    #[diesel(sql_type = BigInt)]
+    version: i64,
+    #[diesel(sql_type = Text)]
+    table_name: String,
+    #[diesel(sql_type = Text)]
+    kind: String,
+    #[diesel(sql_type = Text)]
+    forward: String,
+    #[diesel(sql_type = Text)]
+    reverse: String,
+    #[diesel(sql_type = Nullable<Text>)]
+    previous_schema: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    schema: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    reverse_sources: Option<String>,
+    #[diesel(sql_type = Text)]
+    created_at: String,
+    #[diesel(sql_type = Nullable<Text>)]
+    rolled_back_at: Option<String>,
+}
+
+#[derive(QueryableByName)]
+struct Count {
+    #[diesel(sql_type = BigInt)]
+    count: i64,
+}
+
+#[derive(QueryableByName)]
+struct SchemaRow {
+    #[diesel(sql_type = Nullable<Text>)]
+    schema: Option<String>,
+}
+
+const COLUMNS: &str = "version, table_name, kind, forward, reverse, previous_schema, schema, reverse_sources, \
+    strftime('%Y-%m-%dT%H:%M:%SZ', created_at) AS created_at, \
+    strftime('%Y-%m-%dT%H:%M:%SZ', rolled_back_at) AS rolled_back_at";
+
+/// Creates the migrations table if this database has never been used by fastfood.
+pub fn ensure(conn: &mut SqliteConnection) -> QueryResult<()> {
+    let create_query = format!("CREATE TABLE IF NOT EXISTS {} (
+        version INTEGER PRIMARY KEY AUTOINCREMENT,
+        table_name TEXT NOT NULL COLLATE NOCASE,
+        kind TEXT NOT NULL,
+        forward TEXT NOT NULL,
+        reverse TEXT NOT NULL,
+        previous_schema TEXT,
+        schema TEXT,
+        created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        rolled_back_at TIMESTAMP,
+        reverse_sources TEXT
+    )", MIGRATIONS_TABLE);
+
+    diesel::sql_query(create_query).execute(conn)?;
+
+    // databases created before reverse sources were recorded
+    let recorded = diesel::sql_query("SELECT COUNT(*) AS count FROM pragma_table_info(?) WHERE name = 'reverse_sources'")
+        .bind::<Text, _>(MIGRATIONS_TABLE)
+        .get_result::<Count>(conn)?;
+    if recorded.count == 0 {
+        diesel::sql_query(format!("ALTER TABLE {} ADD COLUMN reverse_sources TEXT", MIGRATIONS_TABLE)).execute(conn)?;
+    }
+
+    Ok(())
+}
+
+/// Records `change` and returns its version.
+pub fn record(conn: &mut SqliteConnection, change: &Change) -> QueryResult<i64> {
+    let insert_query = format!("INSERT INTO {} (table_name, kind, forward, reverse, previous_schema, schema, reverse_sources) \
+                                VALUES (?, ?, ?, ?, ?, ?, ?) RETURNING {}", MIGRATIONS_TABLE, COLUMNS);
+
+    let row = diesel::sql_query(insert_query)
+        .bind::<Text, _>(&change.table_name)
+        .bind::<Text, _>(change.kind.as_str())
+        .bind::<Text, _>(to_json(&change.forward)?)
+        .bind::<Text, _>(to_json(&change.reverse)?)
+        .bind::<Nullable<Text>, _>(change.previous_schema.as_ref().map(to_json).transpose()?)
+        .bind::<Nullable<Text>, _>(change.schema.as_ref().map(to_json).transpose()?)
+        .bind::<Nullable<Text>, _>(change.reverse_sources.as_ref().map(to_json).transpose()?)
+        .get_result::<MigrationRow>(conn)?;
+
+    Ok(row.version)
+}
+
+/// Every migration, oldest first.
+pub fn list(conn: &mut SqliteConnection) -> QueryResult<Vec<Migration>> {
+    let select_query = format!("SELECT {} FROM {} ORDER BY version", COLUMNS, MIGRATIONS_TABLE);
+
+    diesel::sql_query(select_query)
+        .load::<MigrationRow>(conn)?
+        .into_iter()
+        .map(from_row)
+        .collect()
+}
+
+pub fn get(conn: &mut SqliteConnection, version: i64) -> QueryResult<Option<Migration>> {
+    let select_query = format!("SELECT {} FROM {} WHERE version = ?", COLUMNS, MIGRATIONS_TABLE);
+
+    diesel::sql_query(select_query)
+        .bind::<BigInt, _>(version)
+        .get_result::<MigrationRow>(conn)
+        .optional()?
+        .map(from_row)
+        .transpose()
+}
+
+/// The most recent migration that has not been rolled back.
+pub fn last_applied(conn: &mut SqliteConnection) -> QueryResult<Option<Migration>> {
+    let select_query = format!("SELECT {} FROM {} WHERE rolled_back_at IS NULL ORDER BY version DESC LIMIT 1",
+                               COLUMNS, MIGRATIONS_TABLE);
+
+    diesel::sql_query(select_query)
+        .get_result::<MigrationRow>(conn)
+        .optional()?
+        .map(from_row)
+        .transpose()
+}
+
+pub fn mark_rolled_back(conn: &mut SqliteConnection, version: i64) -> QueryResult<()> {
+    let update_query = format!("UPDATE {} SET rolled_back_at = CURRENT_TIMESTAMP WHERE version = ?", MIGRATIONS_TABLE);
+
+    diesel::sql_query(update_query)
+        .bind::<BigInt, _>(version)
+        .execute(conn)?;
+    Ok(())
+}
+
+/// The schema `table_name` had once migration `version` was applied:
+/// `None` if no applied migration up to `version` touched the table,
+/// `Some(None)` if it had been dropped by then.
+pub fn schema_at(conn: &mut SqliteConnection, table_name: &str, version: i64) -> QueryResult<Option<Option<TableSchema>>> {
+    let select_query = format!("SELECT schema FROM {} WHERE table_name = ? AND version <= ? AND rolled_back_at IS NULL \
+                                ORDER BY version DESC LIMIT 1", MIGRATIONS_TABLE);
+
+    let row = diesel::sql_query(select_query)
+        .bind::<Text, _>(table_name)
+        .bind::<BigInt, _>(version)
+        .get_result::<SchemaRow>(conn)
+        .optional()?;
+
+    row.map(|row| row.schema.map(|schema| from_json(&schema)).transpose()).transpose()
+}
+
+fn to_json<T: Serialize>(value: &T) -> QueryResult<String> {
+    serde_json::to_string(value).map_err(|e| Error::SerializationError(Box::new(e)))
+}
+
+fn from_json<T: serde::de::DeserializeOwned>(json: &str) -> QueryResult<T> {
+    serde_json::from_str(json).map_err(|e| Error::DeserializationError(Box::new(e)))
+}
+
+fn from_row(row: MigrationRow) -> QueryResult<Migration> {
+    let kind = MigrationKind::parse(&row.kind)
+        .ok_or_else(|| Error::DeserializationError(format!("unknown migration kind {}", row.kind).into()))?;
+
+    Ok(Migration {
+        version: row.version,
+        change: Change {
+            table_name: row.table_name,
+            kind,
+            forward: from_json(&row.forward)?,
+            reverse: from_json(&row.reverse)?,
+            previous_schema: row.previous_schema.as_deref().map(from_json).transpose()?,
+            schema: row.schema.as_deref().map(from_json).transpose()?,
+            reverse_sources: row.reverse_sources.as_deref().map(from_json).transpose()?,
+        },
+        created_at: row.created_at,
+        rolled_back_at: row.rolled_back_at,
+    })
+}
@@ -1 +1,21 @@
-pub mod crud;
\ No newline at end of file
+pub mod alter;
+pub mod codec;
+pub mod crud;
+pub mod declarative;
+pub mod defaults;
+pub mod embed;
+pub mod filter;
+pub mod identifier;
+pub mod indexes;
+pub mod introspect;
+pub mod listing;
+pub mod migrations;
+pub mod options;
+pub mod rebuild;
+pub mod references;
+pub mod registry;
+pub mod rules;
+pub mod sequences;
+pub mod validation;
+pub mod value;
+pub mod versioning;
@@ -0,0 +1,253 @@
+//! Per-table options for the columns fastfood manages: how `id` values are
+//! generated, whether the `created_at` and `updated_at` timestamps are kept,
+//! under which names, and whether rows carry a `version`. `updated_at` and
+//! `version` are maintained by a trigger, which only exists when one of the
+//! columns does.
+
+use std::sync::LazyLock;
+use serde::{Deserialize, Deserializer, Serialize};
+use ulid::Ulid;
+use uuid::Uuid;
+use crate::services::crud::{ColumnSchema, DataType};
+use crate::services::defaults::{ColumnDefault, DefaultExpression};
+use crate::services::filter;
+use crate::services::identifier::Identifier;
+use crate::services::rules::ColumnRules;
+use crate::services::value::SqlValue;
+
+/// The name of the managed key column, whatever its strategy.
+pub const ID_COLUMN: &str = "id";
+
+pub static DEFAULT_OPTIONS: LazyLock<TableOptions> = LazyLock::new(TableOptions::default);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TableOptions {
+    #[serde(default)]
+    pub id: IdStrategy,
+    /// The column recording when a row was inserted, or `null` for none.
+    #[serde(default = "default_created_at", deserialize_with = "deserialize_column_name")]
+    pub created_at: Option<Identifier>,
+    /// The column recording when a row was last updated, or `null` for none.
+    #[serde(default = "default_updated_at", deserialize_with = "deserialize_column_name")]
+    pub updated_at: Option<Identifier>,
+    /// The column counting the updates of each row, or `null` (the default)
+    /// for none. See `services::versioning`.
+    #[serde(default, deserialize_with = "deserialize_column_name")]
+    pub version: Option<Identifier>,
+}
+
+/// How `id` values are generated. Autoincrement ids are sequential
+/// integers assigned by SQLite; the others are text generated on insert,
+/// which does not reveal how many rows a table holds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdStrategy {
+    #[default]
+    Autoincrement,
+    UuidV4,
+    /// Time-ordered UUIDs, so new rows sort last.
+    UuidV7,
+    Ulid,
+}
+
+fn default_created_at() -> Option<Identifier> {
+    Some(Identifier::managed("created_at"))
+}
+
+fn default_updated_at() -> Option<Identifier> {
+    Some(Identifier::managed("updated_at"))
+}
+
+fn deserialize_column_name<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Identifier>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+        Some(name) => Identifier::column(name).map(Some).map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+/// Options of a submitted or stored schema, which always has them.
+pub fn default_options() -> Option<TableOptions> {
+    Some(TableOptions::default())
+}
+
+/// Reads `null` options as the defaults, like missing ones.
+pub fn deserialize_options<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<TableOptions>, D::Error> {
+    Ok(Some(Option::<TableOptions>::deserialize(deserializer)?.unwrap_or_default()))
+}
+
+impl Default for TableOptions {
+    fn default() -> Self {
+        Self {
+            id: IdStrategy::default(),
+            created_at: default_created_at(),
+            updated_at: default_updated_at(),
+            version: None,
+        }
+    }
+}
+
+impl IdStrategy {
+    pub fn data_type(&self) -> DataType {
+        match self {
+            IdStrategy::Autoincrement => DataType::Integer,
+            IdStrategy::UuidV4 | IdStrategy::UuidV7 | IdStrategy::Ulid => DataType::Text,
+        }
+    }
+
+    /// A new id, or `None` when SQLite assigns it.
+    pub fn generate(&self) -> Option<String> {
+        match self {
+            IdStrategy::Autoincrement => None,
+            IdStrategy::UuidV4 => Some(Uuid::new_v4().to_string()),
+            IdStrategy::UuidV7 => Some(Uuid::now_v7().to_string()),
+            IdStrategy::Ulid => Some(Ulid::new().to_string()),
+        }
+    }
+
+    /// The stored form of an id taken from a URL, or `None` when no row
+    /// can have it.
+    pub fn parse(&self, id: &str) -> Option<SqlValue> {
+        match self {
+            IdStrategy::Autoincrement => id.parse().ok().map(SqlValue::Integer),
+            IdStrategy::UuidV4 | IdStrategy::UuidV7 => Uuid::try_parse(id).ok().map(|id| SqlValue::Text(id.to_string())),
+            IdStrategy::Ulid => Ulid::from_string(id).ok().map(|id| SqlValue::Text(id.to_string())),
+        }
+    }
+}
+
+impl TableOptions {
+    /// The managed columns of a table, `id` first. With a `natural_key` as
+    /// primary key, `id` is only unique; autoincrement ids are then
+    /// assigned by fastfood rather than SQLite.
+    pub fn managed_columns(&self, natural_key: bool) -> Vec<ColumnSchema> {
+        let autoincrement = self.id == IdStrategy::Autoincrement;
+        let mut columns = vec![ColumnSchema {
+            name: Identifier::managed(ID_COLUMN),
+            data_type: self.id.data_type(),
+            primary_key: Some(!natural_key),
+            auto_increment: Some(autoincrement && !natural_key),
+            unique: Some(true),
+            not_null: Some(true),
+            default: None,
+            references: None,
+            rules: ColumnRules::default(),
+        }];
+
+        for name in [&self.created_at, &self.updated_at].into_iter().flatten() {
+            columns.push(ColumnSchema {
+                name: name.clone(),
+                data_type: DataType::TimeStamp,
+                primary_key: Some(false),
+                auto_increment: Some(false),
+                unique: Some(false),
+                not_null: Some(true),
+                default: Some(ColumnDefault::Expression { expr: DefaultExpression::Now }),
+                references: None,
+                rules: ColumnRules::default(),
+            });
+        }
+
+        if let Some(ref version) = self.version {
+            columns.push(ColumnSchema {
+                name: version.clone(),
+                data_type: DataType::Integer,
+                primary_key: Some(false),
+                auto_increment: Some(false),
+                unique: Some(false),
+                not_null: Some(true),
+                default: Some(ColumnDefault::Literal(1.into())),
+                references: None,
+                rules: ColumnRules::default(),
+            });
+        }
+
+        columns
+    }
+
+    /// Whether column `name` is one of the managed columns.
+    pub fn is_managed(&self, name: &str) -> bool {
+        name.eq_ignore_ascii_case(ID_COLUMN)
+            || [&self.created_at, &self.updated_at, &self.version]
+                .into_iter()
+                .flatten()
+                .any(|managed| managed.as_str().eq_ignore_ascii_case(name))
+    }
+
+    /// Checks that the managed columns have distinct names.
+    pub fn check(&self) -> Result<(), String> {
+        let columns = self.managed_columns(false);
+
+        for (i, column) in columns.iter().enumerate() {
+            if columns[..i].iter().any(|other| other.name.as_str().eq_ignore_ascii_case(column.name.as_str())) {
+                return Err(format!("column {} is managed twice", column.name.as_str()));
+            }
+            if filter::is_reserved_param(column.name.as_str()) {
+                return Err(format!("column {} is named like a listing parameter", column.name.as_str()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::services::crud::TableSchema;
+
+    fn options(value: serde_json::Value) -> TableOptions {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_options() {
+        let names = |options: &TableOptions| -> Vec<String> {
+            options.managed_columns(false).iter().map(|column| column.name.as_str().to_string()).collect()
+        };
+
+        assert_eq!(names(&options(json!({}))), vec!["id", "created_at", "updated_at"]);
+        assert_eq!(names(&options(json!({"created_at": null, "updated_at": "modified"}))), vec!["id", "modified"]);
+        assert_eq!(options(json!({})), TableOptions::default());
+        assert_eq!(names(&options(json!({"version": "revision"}))), vec!["id", "created_at", "updated_at", "revision"]);
+        assert!(options(json!({"version": "revision"})).is_managed("Revision"));
+        assert!(options(json!({"version": "updated_at"})).check().is_err());
+
+        let uuid = options(json!({"id": "uuid_v7", "updated_at": "Updated_At"}));
+        assert!(uuid.check().is_ok());
+        assert!(matches!(uuid.managed_columns(false)[0].data_type, DataType::Text));
+        assert_eq!(uuid.updated_at.unwrap().as_str(), "updated_at");
+
+        assert!(options(json!({"created_at": "ID"})).check().is_err());
+        assert!(options(json!({"created_at": "stamp", "updated_at": "stamp"})).check().is_err());
+        assert!(options(json!({"version": "count"})).check().is_err());
+        assert!(serde_json::from_value::<TableOptions>(json!({"id": "serial"})).is_err());
+        assert!(serde_json::from_value::<TableOptions>(json!({"created_at": "a b"})).is_err());
+
+        for given in [json!({"name": "t", "columns": []}), json!({"name": "t", "columns": [], "options": null})] {
+            let schema: TableSchema = serde_json::from_value(given).unwrap();
+            assert_eq!(schema.options, Some(TableOptions::default()));
+        }
+
+        let natural = options(json!({})).managed_columns(true);
+        assert_eq!((natural[0].primary_key, natural[0].auto_increment), (Some(false), Some(false)));
+    }
+
+    #[test]
+    fn test_ids() {
+        for strategy in [IdStrategy::UuidV4, IdStrategy::UuidV7, IdStrategy::Ulid] {
+            let id = strategy.generate().unwrap();
+            assert_eq!(strategy.parse(&id), Some(SqlValue::Text(id.clone())));
+            assert_ne!(strategy.generate().unwrap(), id);
+        }
+
+        let first = IdStrategy::UuidV7.generate().unwrap();
+        assert!(IdStrategy::UuidV7.generate().unwrap() > first);
+
+        assert_eq!(IdStrategy::Autoincrement.generate(), None);
+        assert_eq!(IdStrategy::Autoincrement.parse("12"), Some(SqlValue::Integer(12)));
+        assert_eq!(IdStrategy::Autoincrement.parse("x"), None);
+        assert_eq!(IdStrategy::UuidV4.parse("12"), None);
+    }
+}
@@ -0,0 +1,339 @@
+//! Changing a column's type or constraints means rebuilding the table, as
+//! SQLite cannot alter them in place. The rebuild follows SQLite's
+//! documented procedure: with foreign keys off, create the new table under a
+//! temporary name, copy the rows across converting each value, drop the old
+//! table, rename the new one and recreate its indexes and triggers, then
+//! check foreign keys before committing.
+//!
+//! This module holds the SQL for converting values between `DataType`s and
+//! for finding the rows whose values would not survive the conversion.
+
+use diesel::{QueryableByName, RunQueryDsl, SqliteConnection};
+use diesel::sql_types::{Integer, Text};
+use serde::Serialize;
+use serde_json::Value;
+use crate::services::crud::{ColumnSchema, DataType, Error};
+use crate::services::defaults::ColumnDefault;
+use crate::services::identifier::Identifier;
+
+/// Failing rows reported per column; enough to act on without flooding
+/// the response.
+pub const MAX_REPORTED_FAILURES: i64 = 100;
+
+/// A stored value that cannot be carried over to the changed column.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConversionFailure {
+    /// The row's `id`, an integer or text depending on the table's options.
+    pub id: Value,
+    pub column: String,
+    pub value: Value,
+    pub reason: String,
+}
+
+#[derive(QueryableByName)]
+struct FailingRow {
+    #[diesel(sql_type = Text)]
+    id: String,
+    #[diesel(sql_type = Text)]
+    value: String,
+}
+
+#[derive(QueryableByName)]
+struct SchemaObject {
+    #[diesel(sql_type = Text)]
+    name: String,
+    #[diesel(sql_type = Text)]
+    sql: String,
+}
+
+#[derive(QueryableByName)]
+struct TextValue {
+    #[diesel(sql_type = Text)]
+    value: String,
+}
+
+#[derive(QueryableByName)]
+struct Pragma {
+    #[diesel(sql_type = Integer)]
+    value: i32,
+}
+
+/// SQL turning the stored value of `source` into a value of `target`'s
+/// type. NULLs are replaced with the target's default, if it has one.
+pub fn convert_sql(source: &ColumnSchema, target: &ColumnSchema) -> String {
+    let c = source.name.to_string();
+
+    let converted = match (source.data_type, target.data_type) {
+        (from, to) if from == to => c,
+        (DataType::Boolean, DataType::Text) => format!("CASE {c} WHEN 1 THEN 'true' WHEN 0 THEN 'false' ELSE CAST({c} AS TEXT) END"),
+        (_, DataType::Text) => format!("CAST({} AS TEXT)", c),
+        (DataType::TimeStamp, DataType::Integer) => format!("CAST(strftime('%s', {}) AS INTEGER)", c),
+        (DataType::TimeStamp, DataType::Float) => format!("CAST(strftime('%s', {}) AS REAL)", c),
+        (_, DataType::Integer) => format!("CAST({} AS INTEGER)", c),
+        (_, DataType::Float) => format!("CAST({} AS REAL)", c),
+        (DataType::Text, DataType::Boolean) => {
+            format!("CASE lower({c}) WHEN 'true' THEN 1 WHEN '1' THEN 1 WHEN 'false' THEN 0 WHEN '0' THEN 0 END")
+        }
+        (_, DataType::Boolean) => format!("CAST({} AS INTEGER)", c),
+        (DataType::Integer | DataType::Float, DataType::TimeStamp) => format!("datetime({}, 'unixepoch')", c),
+        (_, DataType::TimeStamp) => format!("datetime({})", c),
+    };
+
+    match target.default {
+        Some(ColumnDefault::Literal(Value::Null)) | None => converted,
+        Some(ref default) => format!("COALESCE({}, {})", converted, default.sql(&target.data_type)),
+    }
+}
+
+/// SQL that is true when a non-NULL value of `source` converts cleanly;
+/// `None` when every value does.
+fn convertible_sql(source: &ColumnSchema, target: &ColumnSchema) -> Option<String> {
+    let c = source.name.to_string();
+    let json_type = format!("CASE WHEN json_valid({c}) THEN json_type({c}) ELSE '' END");
+
+    match (source.data_type, target.data_type) {
+        (from, to) if from == to => None,
+        (_, DataType::Text) => None,
+        (DataType::Integer | DataType::Boolean, DataType::Integer | DataType::Float) => None,
+        (DataType::Float, DataType::Float) => None,
+        (DataType::Float, DataType::Integer) => Some(format!("{c} = CAST({c} AS INTEGER)")),
+        (DataType::Text, DataType::Integer) => Some(format!("{} = 'integer'", json_type)),
+        (DataType::Text, DataType::Float) => Some(format!("{} IN ('integer', 'real')", json_type)),
+        (DataType::TimeStamp, DataType::Integer | DataType::Float) => Some(format!("strftime('%s', {}) IS NOT NULL", c)),
+        (DataType::Text, DataType::Boolean) => Some(format!("lower({}) IN ('true', 'false', '1', '0')", c)),
+        (DataType::Integer | DataType::Float, DataType::Boolean) => Some(format!("{} IN (0, 1)", c)),
+        (DataType::Integer | DataType::Float, DataType::TimeStamp) => Some(format!("datetime({}, 'unixepoch') IS NOT NULL", c)),
+        (DataType::Text | DataType::TimeStamp, DataType::TimeStamp) => Some(format!("datetime({}) IS NOT NULL", c)),
+        (DataType::TimeStamp | DataType::Boolean, _) => Some("0".to_string()),
+    }
+}
+
+/// Finds rows of `table` whose `source` value cannot be stored in `target`:
+/// values that do not convert, NULLs in a column becoming `not_null`
+/// without a default, values breaking the rules SQLite checks, and
+/// duplicates in a column becoming `unique`.
+pub fn find_failures(conn: &mut SqliteConnection, table: &Identifier, id: &Identifier, source: &ColumnSchema, target: &ColumnSchema) -> Result<Vec<ConversionFailure>, Error> {
+    let converted = convert_sql(source, target);
+    let mut checks = Vec::new();
+
+    if let Some(convertible) = convertible_sql(source, target) {
+        checks.push((format!("{} IS NOT NULL AND NOT ({})", source.name, convertible),
+                     format!("cannot be converted to {}", target.data_type)));
+    }
+    if target.not_null.unwrap_or(false) {
+        checks.push((format!("({}) IS NULL", converted), "null in a not_null column".to_string()));
+    }
+    if let Some(check) = target.rules.sql(target, &format!("({})", converted)) {
+        checks.push((format!("({converted}) IS NOT NULL AND NOT ({check})"), "breaks the column's rules".to_string()));
+    }
+    if target.unique.unwrap_or(false) {
+        checks.push((format!("({converted}) IN (SELECT {converted} FROM {table} GROUP BY 1 HAVING COUNT(*) > 1)"),
+                     "duplicate value in a unique column".to_string()));
+    }
+
+    let mut failures = Vec::new();
+
+    for (condition, reason) in checks {
+        let select_query = format!("SELECT json_quote({}) AS id, json_quote({}) AS value FROM {} WHERE {} ORDER BY {} LIMIT {}",
+                                   id, source.name, table, condition, id, MAX_REPORTED_FAILURES);
+
+        for row in diesel::sql_query(select_query).load::<FailingRow>(conn)? {
+            failures.push(ConversionFailure {
+                id: serde_json::from_str(&row.id).unwrap_or(Value::String(row.id)),
+                column: target.name.as_str().to_string(),
+                value: serde_json::from_str(&row.value).unwrap_or(Value::String(row.value)),
+                reason: reason.clone(),
+            });
+        }
+    }
+
+    Ok(failures)
+}
+
+/// `INSERT ... SELECT` copying `table` into `new_table`, given the
+/// `(source, target)` pair of each copied column.
+pub fn copy_sql(table: &Identifier, new_table: &Identifier, columns: &[(&ColumnSchema, &ColumnSchema)]) -> String {
+    let targets: Vec<String> = columns.iter().map(|(_, target)| target.name.to_string()).collect();
+    let values: Vec<String> = columns.iter().map(|(source, target)| convert_sql(source, target)).collect();
+
+    format!("INSERT INTO {} ({}) SELECT {} FROM {}", new_table, targets.join(", "), values.join(", "), table)
+}
+
+/// The `CREATE` statements of the explicit indexes and triggers on `table`,
+/// which dropping it removes, except those named in `skip`.
+pub fn dependent_objects(conn: &mut SqliteConnection, table: &Identifier, skip: &[&Identifier]) -> Result<Vec<String>, Error> {
+    let objects = diesel::sql_query("SELECT name, sql FROM sqlite_schema \
+                                     WHERE tbl_name = ? AND type IN ('index', 'trigger') AND sql IS NOT NULL")
+        .bind::<Text, _>(table.as_str())
+        .load::<SchemaObject>(conn)?;
+
+    Ok(objects
+        .into_iter()
+        .filter(|object| !skip.iter().any(|name| name.as_str().eq_ignore_ascii_case(&object.name)))
+        .map(|object| object.sql)
+        .collect())
+}
+
+/// Runs `f` with foreign key enforcement off, as rebuilding a table
+/// requires, restoring it afterwards on every way out of `f`, panics
+/// included, so the pooled connection never goes back without it. `f` is
+/// told whether enforcement was on, in which case it should
+/// `check_foreign_keys` before committing.
+pub fn without_foreign_keys<T>(conn: &mut SqliteConnection, f: impl FnOnce(&mut SqliteConnection, bool) -> Result<T, Error>) -> Result<T, Error> {
+    if !foreign_keys_enabled(conn)? {
+        return f(conn, false);
+    }
+
+    set_foreign_keys(conn, false)?;
+    let guard = ForeignKeysOff(conn);
+    f(&mut *guard.0, true)
+}
+
+/// Switches foreign key enforcement back on when dropped. A failure is
+/// logged rather than returned, as `f` may already have committed; the
+/// pool switches it on again at the next checkout.
+struct ForeignKeysOff<'a>(&'a mut SqliteConnection);
+
+impl Drop for ForeignKeysOff<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = set_foreign_keys(self.0, true) {
+            log::error!("Could not switch foreign keys back on: {}", e);
+        }
+    }
+}
+
+fn foreign_keys_enabled(conn: &mut SqliteConnection) -> Result<bool, Error> {
+    let pragma = diesel::sql_query("SELECT foreign_keys AS value FROM pragma_foreign_keys")
+        .get_result::<Pragma>(conn)?;
+
+    Ok(pragma.value != 0)
+}
+
+/// Has no effect inside a transaction, so it must be called around one.
+fn set_foreign_keys(conn: &mut SqliteConnection, enabled: bool) -> Result<(), Error> {
+    diesel::sql_query(format!("PRAGMA foreign_keys = {}", if enabled { "ON" } else { "OFF" })).execute(conn)?;
+    Ok(())
+}
+
+/// Fails if the rebuilt `table` left any foreign key dangling.
+pub fn check_foreign_keys(conn: &mut SqliteConnection, table: &Identifier) -> Result<(), Error> {
+    let violations = diesel::sql_query("SELECT \"table\" AS value FROM pragma_foreign_key_check(?)")
+        .bind::<Text, _>(table.as_str())
+        .load::<TextValue>(conn)?;
+
+    if violations.is_empty() {
+        return Ok(());
+    }
+
+    let mut tables: Vec<String> = violations.into_iter().map(|violation| violation.value).collect();
+    tables.dedup();
+
+    Err(Error::ForeignKeyViolation(format!("rebuilding table {} would leave rows of {} referencing missing rows",
+                                           table.as_str(), tables.join(", "))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::Connection;
+    use serde_json::json;
+    use crate::services::value::quote_literal;
+
+    fn column(value: Value) -> ColumnSchema {
+        serde_json::from_value(value).unwrap()
+    }
+
+    /// Converts `values` stored in a `from` column to `to`, returning the
+    /// converted values and the ids that fail.
+    fn convert(from: &str, to: &str, values: &[Value]) -> (Vec<Value>, Vec<i64>) {
+        let mut conn = SqliteConnection::establish(":memory:").unwrap();
+        let source = column(json!({"name": "v", "type": from}));
+        let target = column(json!({"name": "v", "type": to}));
+
+        diesel::sql_query(format!("CREATE TABLE t (id INTEGER PRIMARY KEY, {})", source)).execute(&mut conn).unwrap();
+        for (i, value) in values.iter().enumerate() {
+            let literal = match value {
+                Value::String(s) => quote_literal(s),
+                value => value.to_string(),
+            };
+            diesel::sql_query(format!("INSERT INTO t (id, v) VALUES ({}, {})", i + 1, literal)).execute(&mut conn).unwrap();
+        }
+
+        let table = Identifier::managed("t");
+        let failures = find_failures(&mut conn, &table, &Identifier::managed("id"), &source, &target).ok().unwrap();
+
+        let converted = diesel::sql_query(format!("SELECT json_quote({}) AS value FROM t ORDER BY id", convert_sql(&source, &target)))
+            .load::<TextValue>(&mut conn)
+            .unwrap()
+            .into_iter()
+            .map(|row| serde_json::from_str(&row.value).unwrap())
+            .collect();
+
+        (converted, failures.into_iter().map(|failure| failure.id.as_i64().unwrap()).collect())
+    }
+
+    #[test]
+    fn test_text_to_numbers() {
+        let (converted, failing) = convert("text", "integer", &[json!("12"), json!("1.5"), json!("abc")]);
+        assert_eq!(converted[0], json!(12));
+        assert_eq!(failing, vec![2, 3]);
+
+        let (converted, failing) = convert("text", "float", &[json!("1.50"), json!("x")]);
+        assert_eq!(converted[0], json!(1.5));
+        assert_eq!(failing, vec![2]);
+    }
+
+    #[test]
+    fn test_booleans_and_timestamps() {
+        let (converted, failing) = convert("text", "boolean", &[json!("TRUE"), json!("0"), json!("maybe")]);
+        assert_eq!(&converted[..2], &[json!(1), json!(0)]);
+        assert_eq!(failing, vec![3]);
+
+        let (converted, failing) = convert("boolean", "text", &[json!(1), json!(0)]);
+        assert_eq!(converted, vec![json!("true"), json!("false")]);
+        assert!(failing.is_empty());
+
+        let (converted, failing) = convert("text", "timestamp", &[json!("2024-05-01T20:30:00+02:00"), json!("later")]);
+        assert_eq!(converted[0], json!("2024-05-01 18:30:00"));
+        assert_eq!(failing, vec![2]);
+
+        let (converted, _) = convert("integer", "timestamp", &[json!(0)]);
+        assert_eq!(converted[0], json!("1970-01-01 00:00:00"));
+    }
+
+    #[test]
+    fn test_constraint_failures() {
+        let mut conn = SqliteConnection::establish(":memory:").unwrap();
+        diesel::sql_query("CREATE TABLE t (id INTEGER PRIMARY KEY, v TEXT)").execute(&mut conn).unwrap();
+        diesel::sql_query("INSERT INTO t (v) VALUES ('a'), ('a'), (NULL), ('b')").execute(&mut conn).unwrap();
+
+        let source = column(json!({"name": "v", "type": "text"}));
+        let target = column(json!({"name": "v", "type": "text", "not_null": true, "unique": true}));
+        let failures = find_failures(&mut conn, &Identifier::managed("t"), &Identifier::managed("id"), &source, &target).ok().unwrap();
+        let ids: Vec<i64> = failures.iter().map(|failure| failure.id.as_i64().unwrap()).collect();
+        assert_eq!(ids, vec![3, 1, 2]);
+
+        // a default fills in NULLs instead
+        let target = column(json!({"name": "v", "type": "text", "not_null": true, "default": "c"}));
+        assert!(find_failures(&mut conn, &Identifier::managed("t"), &Identifier::managed("id"), &source, &target).ok().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_foreign_keys_are_restored() {
+        let mut conn = SqliteConnection::establish(":memory:").unwrap();
+        set_foreign_keys(&mut conn, true).unwrap();
+
+        let result = without_foreign_keys(&mut conn, |conn, enabled| {
+            assert!(enabled && !foreign_keys_enabled(conn).unwrap());
+            Err::<(), _>(Error::Internal("failed".to_string()))
+        });
+        assert!(result.is_err());
+        assert!(foreign_keys_enabled(&mut conn).unwrap());
+
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            without_foreign_keys(&mut conn, |_, _| -> Result<(), Error> { panic!("rebuild failed") })
+        }));
+        assert!(panicked.is_err());
+        assert!(foreign_keys_enabled(&mut conn).unwrap());
+    }
+}
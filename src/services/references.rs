@@ -0,0 +1,168 @@
+//! Foreign keys. A column's `references` option names the table and column
+//! it points to and what happens to it when the referenced row is deleted or
+//! its key updated, rendered as a `REFERENCES` column constraint.
+
+use std::fmt;
+use serde::{Deserialize, Serialize};
+use crate::services::crud::{ColumnSchema, Error, TableSchema};
+use crate::services::identifier::{deserialize_column, Identifier};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct References {
+    pub table: Identifier,
+    /// Defaults to the referenced table's `id`.
+    #[serde(default = "default_column", deserialize_with = "deserialize_column")]
+    pub column: Identifier,
+    #[serde(default)]
+    pub on_delete: ReferentialAction,
+    #[serde(default)]
+    pub on_update: ReferentialAction,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReferentialAction {
+    #[default]
+    NoAction,
+    Restrict,
+    Cascade,
+    SetNull,
+    SetDefault,
+}
+
+impl ReferentialAction {
+    fn sql(&self) -> &'static str {
+        match self {
+            ReferentialAction::NoAction => "NO ACTION",
+            ReferentialAction::Restrict => "RESTRICT",
+            ReferentialAction::Cascade => "CASCADE",
+            ReferentialAction::SetNull => "SET NULL",
+            ReferentialAction::SetDefault => "SET DEFAULT",
+        }
+    }
+}
+
+fn default_column() -> Identifier {
+    Identifier::managed("id")
+}
+
+impl References {
+    /// Checks the options against the column they belong to.
+    pub fn check(&self, column: &ColumnSchema) -> Result<(), String> {
+        let sets_null = self.on_delete == ReferentialAction::SetNull || self.on_update == ReferentialAction::SetNull;
+
+        if sets_null && column.not_null.unwrap_or(false) {
+            return Err("set_null cannot be used on a not_null column".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Checks that `target`, the referenced table including its managed
+    /// columns, has the referenced column, that it is a key SQLite can
+    /// enforce the reference against, on its own or as the only column of a
+    /// table-level key, and that `column` has its type.
+    pub fn check_target(&self, column: &ColumnSchema, target: &TableSchema) -> Result<(), Error> {
+        let referenced = target
+            .columns
+            .iter()
+            .find(|candidate| candidate.name.as_str().eq_ignore_ascii_case(self.column.as_str()))
+            .ok_or_else(|| Error::Validation(format!("column {} references {}.{}, which does not exist",
+                                                     column.name.as_str(), self.table.as_str(), self.column.as_str())))?;
+
+        let is_key = |key: &[Identifier]| matches!(key, [only] if only.as_str().eq_ignore_ascii_case(self.column.as_str()));
+        let keyed = referenced.primary_key.unwrap_or(false)
+            || referenced.unique.unwrap_or(false)
+            || target.primary_key.as_deref().is_some_and(is_key)
+            || target.unique.iter().any(|unique| is_key(unique));
+
+        if !keyed {
+            return Err(Error::Validation(format!("column {} references {}.{}, which is neither a primary key nor unique",
+                                                 column.name.as_str(), self.table.as_str(), self.column.as_str())));
+        }
+
+        if referenced.data_type != column.data_type {
+            return Err(Error::Validation(format!("column {} is {} but references {}.{}, which is {}",
+                                                 column.name.as_str(), column.data_type,
+                                                 self.table.as_str(), self.column.as_str(), referenced.data_type)));
+        }
+
+        Ok(())
+    }
+}
+
+/// Renders the constraint, leaving out the default `NO ACTION` clauses.
+impl fmt::Display for References {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "REFERENCES {} ({})", self.table, self.column)?;
+
+        if self.on_delete != ReferentialAction::NoAction {
+            write!(f, " ON DELETE {}", self.on_delete.sql())?;
+        }
+        if self.on_update != ReferentialAction::NoAction {
+            write!(f, " ON UPDATE {}", self.on_update.sql())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn column(value: serde_json::Value) -> ColumnSchema {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_render() {
+        let references: References = serde_json::from_value(json!({"table": "customers"})).unwrap();
+        assert_eq!(references.to_string(), "REFERENCES \"customers\" (\"id\")");
+
+        let references: References = serde_json::from_value(json!({
+            "table": "customers", "column": "code", "on_delete": "set_null", "on_update": "cascade"
+        })).unwrap();
+        assert_eq!(references.to_string(), "REFERENCES \"customers\" (\"code\") ON DELETE SET NULL ON UPDATE CASCADE");
+
+        let column = column(json!({"name": "customer_id", "type": "integer", "references": {"table": "customers", "on_delete": "restrict"}}));
+        assert_eq!(column.to_string(), "\"customer_id\" INTEGER REFERENCES \"customers\" (\"id\") ON DELETE RESTRICT");
+
+        assert!(serde_json::from_value::<References>(json!({"table": "customers", "on_delete": "explode"})).is_err());
+        assert!(serde_json::from_value::<References>(json!({"table": "customers", "column": "a b"})).is_err());
+    }
+
+    #[test]
+    fn test_checks() {
+        let owner = column(json!({"name": "owner_id", "type": "integer", "not_null": true,
+                                  "references": {"table": "people", "on_delete": "set_null"}}));
+        assert!(owner.validate().is_err());
+
+        let mut target: TableSchema = serde_json::from_value(json!({"name": "people", "columns": [
+            {"name": "email", "type": "text", "unique": true},
+            {"name": "age", "type": "integer"},
+        ]})).unwrap();
+        target.columns.insert(0, column(json!({"name": "x", "type": "integer", "primary_key": true})));
+        target.columns[0].name = Identifier::managed("id");
+
+        let check_against = |target: &TableSchema, value: serde_json::Value| {
+            let column = column(value);
+            column.references.as_ref().unwrap().check_target(&column, target)
+        };
+        let check = |value: serde_json::Value| check_against(&target, value);
+        assert!(check(json!({"name": "owner_id", "type": "integer", "references": {"table": "people"}})).is_ok());
+        assert!(check(json!({"name": "owner", "type": "text", "references": {"table": "people", "column": "email"}})).is_ok());
+        assert!(check(json!({"name": "owner", "type": "text", "references": {"table": "people"}})).is_err());
+        assert!(check(json!({"name": "owner", "type": "integer", "references": {"table": "people", "column": "age"}})).is_err());
+        assert!(check(json!({"name": "owner", "type": "text", "references": {"table": "people", "column": "name"}})).is_err());
+
+        target.unique = vec![vec![Identifier::column("Age").unwrap()]];
+        assert!(check_against(&target, json!({"name": "owner", "type": "integer", "references": {"table": "people", "column": "age"}})).is_ok());
+        target.unique = vec![vec![Identifier::column("age").unwrap(), Identifier::column("email").unwrap()]];
+        assert!(check_against(&target, json!({"name": "owner", "type": "integer", "references": {"table": "people", "column": "age"}})).is_err());
+        target.primary_key = Some(vec![Identifier::column("age").unwrap()]);
+        assert!(check_against(&target, json!({"name": "owner", "type": "integer", "references": {"table": "people", "column": "age"}})).is_ok());
+    }
+}
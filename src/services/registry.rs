@@ -0,0 +1,87 @@
+//! The `_fastfood_tables` system table, which records the schema of every
+//! table created through fastfood exactly as it was submitted.
+
+use diesel::{OptionalExtension, QueryableByName, QueryResult, RunQueryDsl, SqliteConnection};
+use diesel::result::Error;
+use diesel::sql_types::Text;
+use crate::services::crud::TableSchema;
+
+pub const REGISTRY_TABLE: &str = "_fastfood_tables";
+
+#[derive(QueryableByName)]
+struct RegistryRow {
+    #[diesel(sql_type = Text)]
+    schema: String,
+}
+
+/// Creates the registry table if this database has never been used by fastfood.
+pub fn ensure(conn: &mut SqliteConnection) -> QueryResult<()> {
+    let create_query = format!("CREATE TABLE IF NOT EXISTS {} (
+        name TEXT PRIMARY KEY NOT NULL COLLATE NOCASE,
+        schema TEXT NOT NULL,
+        created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+    )", REGISTRY_TABLE);
+
+    diesel::sql_query(create_query).execute(conn)?;
+    Ok(())
+}
+
+pub fn insert(conn: &mut SqliteConnection, schema: &TableSchema) -> QueryResult<()> {
+    let insert_query = format!("INSERT INTO {} (name, schema) VALUES (?, ?)", REGISTRY_TABLE);
+
+    diesel::sql_query(insert_query)
+        .bind::<Text, _>(schema.name.as_str())
+        .bind::<Text, _>(to_json(schema)?)
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Replaces the stored schema of a table after it has been altered.
+pub fn update(conn: &mut SqliteConnection, schema: &TableSchema) -> QueryResult<()> {
+    let update_query = format!("UPDATE {} SET schema = ?, updated_at = CURRENT_TIMESTAMP WHERE name = ?", REGISTRY_TABLE);
+
+    diesel::sql_query(update_query)
+        .bind::<Text, _>(to_json(schema)?)
+        .bind::<Text, _>(schema.name.as_str())
+        .execute(conn)?;
+    Ok(())
+}
+
+pub fn get(conn: &mut SqliteConnection, name: &str) -> QueryResult<Option<TableSchema>> {
+    let select_query = format!("SELECT schema FROM {} WHERE name = ?", REGISTRY_TABLE);
+
+    diesel::sql_query(select_query)
+        .bind::<Text, _>(name)
+        .get_result::<RegistryRow>(conn)
+        .optional()?
+        .map(from_json)
+        .transpose()
+}
+
+pub fn list(conn: &mut SqliteConnection) -> QueryResult<Vec<TableSchema>> {
+    let select_query = format!("SELECT schema FROM {} ORDER BY name", REGISTRY_TABLE);
+
+    diesel::sql_query(select_query)
+        .load::<RegistryRow>(conn)?
+        .into_iter()
+        .map(from_json)
+        .collect()
+}
+
+pub fn remove(conn: &mut SqliteConnection, name: &str) -> QueryResult<()> {
+    let delete_query = format!("DELETE FROM {} WHERE name = ?", REGISTRY_TABLE);
+
+    diesel::sql_query(delete_query)
+        .bind::<Text, _>(name)
+        .execute(conn)?;
+    Ok(())
+}
+
+fn to_json(schema: &TableSchema) -> QueryResult<String> {
+    serde_json::to_string(schema).map_err(|e| Error::SerializationError(Box::new(e)))
+}
+
+fn from_json(row: RegistryRow) -> QueryResult<TableSchema> {
+    serde_json::from_str(&row.schema).map_err(|e| Error::DeserializationError(Box::new(e)))
+}
@@ -0,0 +1,265 @@
+//! Rules restricting the values of a column: `min`/`max` for numbers,
+//! `min_length`/`max_length` and `pattern` for text, and `one_of` for any
+//! type. They are checked when rows are written, so clients get an error per
+//! field, and rendered as a `CHECK` constraint so SQLite holds every writer
+//! to them; `pattern` is the exception, as SQLite has no regular expressions.
+
+use std::fmt;
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Number, Value};
+use crate::services::codec;
+use crate::services::crud::{ColumnSchema, DataType};
+use crate::services::value::SqlValue;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ColumnRules {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<Number>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<Number>,
+    /// In characters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_length: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_length: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<Pattern>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub one_of: Option<Vec<Value>>,
+}
+
+/// A regular expression the whole value must match.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    source: String,
+    regex: Regex,
+}
+
+impl Pattern {
+    pub fn new(source: &str) -> Result<Self, regex::Error> {
+        Ok(Self {
+            source: source.to_string(),
+            regex: Regex::new(&format!("^(?:{})$", source))?,
+        })
+    }
+
+    pub fn is_match(&self, value: &str) -> bool {
+        self.regex.is_match(value)
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        Pattern::new(&source).map_err(serde::de::Error::custom)
+    }
+}
+
+impl ColumnRules {
+    /// Checks the rules against the column they belong to.
+    pub fn check(&self, column: &ColumnSchema) -> Result<(), String> {
+        let numeric = matches!(column.data_type, DataType::Integer | DataType::Float);
+        let text = matches!(column.data_type, DataType::Text);
+
+        if (self.min.is_some() || self.max.is_some()) && !numeric {
+            return Err(format!("min and max apply to integer and float columns, not {}", column.data_type));
+        }
+        if (self.min_length.is_some() || self.max_length.is_some() || self.pattern.is_some()) && !text {
+            return Err(format!("min_length, max_length and pattern apply to text columns, not {}", column.data_type));
+        }
+        if let (Some(min), Some(max)) = (&self.min, &self.max) {
+            if bound(min) > bound(max) {
+                return Err(format!("min {} is greater than max {}", min, max));
+            }
+        }
+        if let (Some(min), Some(max)) = (self.min_length, self.max_length) {
+            if min > max {
+                return Err(format!("min_length {} is greater than max_length {}", min, max));
+            }
+        }
+
+        if let Some(ref values) = self.one_of {
+            if values.is_empty() {
+                return Err("one_of must list at least one value".to_string());
+            }
+            for value in values {
+                if value.is_null() {
+                    return Err("one_of cannot list null; leave the column nullable instead".to_string());
+                }
+                codec::encode_value(column, value).map_err(|e| format!("one_of: {}", e))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The condition of the `CHECK` constraint for `column`, or `None` when
+    /// no rule can be checked by SQLite. `expr` is the value checked, the
+    /// column itself or an expression of its type.
+    pub fn sql(&self, column: &ColumnSchema, expr: &str) -> Option<String> {
+        let mut conditions = Vec::new();
+
+        if let Some(ref min) = self.min {
+            conditions.push(format!("{} >= {}", expr, min));
+        }
+        if let Some(ref max) = self.max {
+            conditions.push(format!("{} <= {}", expr, max));
+        }
+        if let Some(min_length) = self.min_length {
+            conditions.push(format!("length({}) >= {}", expr, min_length));
+        }
+        if let Some(max_length) = self.max_length {
+            conditions.push(format!("length({}) <= {}", expr, max_length));
+        }
+        if let Some(ref values) = self.one_of {
+            let literals: Vec<String> = values
+                .iter()
+                .filter_map(|value| codec::encode_value(column, value).ok())
+                .map(|value| value.literal())
+                .collect();
+            conditions.push(format!("{} IN ({})", expr, literals.join(", ")));
+        }
+
+        if conditions.is_empty() {
+            None
+        } else {
+            Some(conditions.join(" AND "))
+        }
+    }
+
+    /// Checks `value`, encoded for `column`, against the rules. NULL passes,
+    /// as it does a `CHECK` constraint; `not_null` is what rules it out.
+    pub fn check_value(&self, column: &ColumnSchema, value: &SqlValue) -> Result<(), String> {
+        let number = match *value {
+            SqlValue::Null => return Ok(()),
+            SqlValue::Integer(i) => Some(i as f64),
+            SqlValue::Float(f) => Some(f),
+            SqlValue::Text(_) => None,
+        };
+
+        if let Some(number) = number {
+            if let Some(min) = self.min.as_ref().filter(|min| number < bound(min)) {
+                return Err(format!("must be at least {}", min));
+            }
+            if let Some(max) = self.max.as_ref().filter(|max| number > bound(max)) {
+                return Err(format!("must be at most {}", max));
+            }
+        }
+
+        if let SqlValue::Text(ref text) = *value {
+            let length = text.chars().count();
+
+            if let Some(min_length) = self.min_length.filter(|min| length < *min as usize) {
+                return Err(format!("must be at least {} characters long", min_length));
+            }
+            if let Some(max_length) = self.max_length.filter(|max| length > *max as usize) {
+                return Err(format!("must be at most {} characters long", max_length));
+            }
+            if let Some(ref pattern) = self.pattern {
+                if !pattern.is_match(text) {
+                    return Err(format!("must match the pattern {}", pattern));
+                }
+            }
+        }
+
+        if let Some(ref values) = self.one_of {
+            let allowed = values
+                .iter()
+                .any(|allowed| codec::encode_value(column, allowed).ok().as_ref() == Some(value));
+
+            if !allowed {
+                let listed: Vec<String> = values.iter().map(Value::to_string).collect();
+                return Err(format!("must be one of {}", listed.join(", ")));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn bound(number: &Number) -> f64 {
+    number.as_f64().unwrap_or(f64::NAN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn column(value: Value) -> ColumnSchema {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_render() {
+        let age = column(json!({"name": "age", "type": "integer", "not_null": true, "min": 0, "max": 150}));
+        assert_eq!(age.to_string(), "\"age\" INTEGER NOT NULL CHECK (\"age\" >= 0 AND \"age\" <= 150)");
+
+        let code = column(json!({"name": "code", "type": "text", "min_length": 2, "max_length": 3, "pattern": "[A-Z]+"}));
+        assert_eq!(code.to_string(), "\"code\" TEXT CHECK (length(\"code\") >= 2 AND length(\"code\") <= 3)");
+
+        let status = column(json!({"name": "status", "type": "text", "one_of": ["new", "o'k"]}));
+        assert_eq!(status.to_string(), "\"status\" TEXT CHECK (\"status\" IN ('new', 'o''k'))");
+
+        let pattern = column(json!({"name": "code", "type": "text", "pattern": "[a-z]+"}));
+        assert_eq!(pattern.to_string(), "\"code\" TEXT");
+        assert_eq!(serde_json::to_value(&pattern).unwrap()["pattern"], json!("[a-z]+"));
+        assert!(serde_json::to_value(&pattern).unwrap().get("min").is_none());
+    }
+
+    #[test]
+    fn test_checks() {
+        assert!(column(json!({"name": "age", "type": "integer", "min": 0, "max": 1})).validate().is_ok());
+        assert!(column(json!({"name": "age", "type": "integer", "min": 2, "max": 1})).validate().is_err());
+        assert!(column(json!({"name": "age", "type": "text", "min": 2})).validate().is_err());
+        assert!(column(json!({"name": "age", "type": "integer", "pattern": "[0-9]"})).validate().is_err());
+        assert!(column(json!({"name": "age", "type": "text", "min_length": 3, "max_length": 2})).validate().is_err());
+        assert!(column(json!({"name": "age", "type": "integer", "one_of": []})).validate().is_err());
+        assert!(column(json!({"name": "age", "type": "integer", "one_of": [1, "two"]})).validate().is_err());
+        assert!(serde_json::from_value::<ColumnSchema>(json!({"name": "age", "type": "text", "pattern": "("})).is_err());
+    }
+
+    #[test]
+    fn test_check_value() {
+        let check = |column: Value, value: Value| {
+            let column = self::column(column);
+            let encoded = codec::encode_value(&column, &value).unwrap();
+            column.rules.check_value(&column, &encoded)
+        };
+
+        let price = json!({"name": "price", "type": "float", "min": 0.5, "max": 10});
+        assert!(check(price.clone(), json!(0.5)).is_ok());
+        assert_eq!(check(price.clone(), json!(0.4)), Err("must be at least 0.5".to_string()));
+        assert_eq!(check(price.clone(), json!(11)), Err("must be at most 10".to_string()));
+        assert!(check(price, json!(null)).is_ok());
+
+        let code = json!({"name": "code", "type": "text", "min_length": 2, "max_length": 3, "pattern": "[a-zé]+"});
+        assert!(check(code.clone(), json!("éé")).is_ok());
+        assert!(check(code.clone(), json!("a")).is_err());
+        assert!(check(code.clone(), json!("abcd")).is_err());
+        assert!(check(code, json!("ab1")).is_err());
+
+        let starts = json!({"name": "starts", "type": "timestamp", "one_of": ["2024-01-01T00:00:00Z"]});
+        assert!(check(starts.clone(), json!("2024-01-01T01:00:00+01:00")).is_ok());
+        assert_eq!(check(starts, json!("2024-01-02T00:00:00Z")), Err("must be one of \"2024-01-01T00:00:00Z\"".to_string()));
+    }
+}
@@ -0,0 +1,74 @@
+//! The `_fastfood_sequences` system table, which hands out the `id` of
+//! tables keyed by a natural primary key. There `id` is not the rowid, so
+//! SQLite's `AUTOINCREMENT` cannot assign it; the sequence plays its part,
+//! never giving out an id twice, even once the row holding it is deleted.
+
+use diesel::{QueryableByName, QueryResult, RunQueryDsl, SqliteConnection};
+use diesel::sql_types::{BigInt, Text};
+use crate::services::identifier::Identifier;
+use crate::services::options::ID_COLUMN;
+use crate::services::value::quote_literal;
+
+pub const SEQUENCES_TABLE: &str = "_fastfood_sequences";
+
+#[derive(QueryableByName)]
+struct Sequence {
+    #[diesel(sql_type = BigInt)]
+    seq: i64,
+}
+
+/// Creates the sequences table if this database has never been used by fastfood.
+pub fn ensure(conn: &mut SqliteConnection) -> QueryResult<()> {
+    let create_query = format!("CREATE TABLE IF NOT EXISTS {} (
+        name TEXT PRIMARY KEY NOT NULL COLLATE NOCASE,
+        seq INTEGER NOT NULL
+    )", SEQUENCES_TABLE);
+
+    diesel::sql_query(create_query).execute(conn)?;
+    Ok(())
+}
+
+/// The next id of `table`, past both the last one handed out and any
+/// stored in the table. Must run in the transaction inserting the row.
+pub fn next(conn: &mut SqliteConnection, table: &Identifier) -> QueryResult<i64> {
+    let highest = format!("(SELECT COALESCE(MAX({}), 0) FROM {})", Identifier::managed(ID_COLUMN), table);
+    let upsert_query = format!("INSERT INTO {sequences} (name, seq) VALUES (?, {highest} + 1) \
+                                ON CONFLICT (name) DO UPDATE SET seq = MAX(seq, {highest}) + 1 RETURNING seq",
+                               sequences = SEQUENCES_TABLE, highest = highest);
+
+    let sequence = diesel::sql_query(upsert_query)
+        .bind::<Text, _>(table.as_str())
+        .get_result::<Sequence>(conn)?;
+
+    Ok(sequence.seq)
+}
+
+/// The highest id `table` has handed out or holds, 0 if none.
+pub fn high_water(conn: &mut SqliteConnection, table: &Identifier) -> QueryResult<i64> {
+    let select_query = format!("SELECT MAX(COALESCE((SELECT seq FROM {} WHERE name = ?), 0), \
+                                (SELECT COALESCE(MAX({}), 0) FROM {})) AS seq",
+                               SEQUENCES_TABLE, Identifier::managed(ID_COLUMN), table);
+
+    let sequence = diesel::sql_query(select_query)
+        .bind::<Text, _>(table.as_str())
+        .get_result::<Sequence>(conn)?;
+
+    Ok(sequence.seq)
+}
+
+/// The statement raising the sequence of `table` to at least `seq`, which
+/// restores it when a dropped table is recreated.
+pub fn restore_sql(table: &Identifier, seq: i64) -> String {
+    format!("INSERT INTO {} (name, seq) VALUES ({}, {seq}) ON CONFLICT (name) DO UPDATE SET seq = MAX(seq, {seq})",
+            SEQUENCES_TABLE, quote_literal(table.as_str()), seq = seq)
+}
+
+/// Forgets the sequence of a dropped table.
+pub fn remove(conn: &mut SqliteConnection, table: &str) -> QueryResult<()> {
+    let delete_query = format!("DELETE FROM {} WHERE name = ?", SEQUENCES_TABLE);
+
+    diesel::sql_query(delete_query)
+        .bind::<Text, _>(table)
+        .execute(conn)?;
+    Ok(())
+}
@@ -0,0 +1,141 @@
+//! Checks row payloads against the table's schema before they reach SQLite,
+//! collecting every problem so clients can fix them all in one go.
+
+use serde::Serialize;
+use crate::services::codec;
+use crate::services::crud::{Error, Row, TableSchema};
+use crate::services::identifier::Identifier;
+use crate::services::value::SqlValue;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    fn new(field: &str, message: impl Into<String>) -> Self {
+        Self {
+            field: field.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Write {
+    /// Every `not_null` column without a default must be present.
+    Insert,
+    /// Only the fields present are checked.
+    Update,
+}
+
+/// Validates `row` against `schema` and splits it into column names and
+/// values encoded for storage. `managed` lists the columns fastfood
+/// maintains itself, which clients may not write.
+pub fn validate_row(schema: &TableSchema, managed: &[&Identifier], row: Row, write: Write) -> Result<(Vec<Identifier>, Vec<SqlValue>), Error> {
+    let mut errors = Vec::new();
+    let mut names = Vec::with_capacity(row.len());
+    let mut values = Vec::with_capacity(row.len());
+
+    for (name, value) in &row {
+        let column = match schema.column(name) {
+            Some(column) => column,
+            None => {
+                errors.push(FieldError::new(name, format!("table {} has no column {}", schema.name.as_str(), name)));
+                continue;
+            }
+        };
+
+        if managed.iter().any(|managed| **managed == column.name) {
+            errors.push(FieldError::new(name, format!("column {} is managed by fastfood and cannot be written", name)));
+            continue;
+        }
+
+        if value.is_null() && column.not_null.unwrap_or(false) {
+            errors.push(FieldError::new(name, format!("column {} must not be null", name)));
+            continue;
+        }
+
+        match codec::encode_value(column, value) {
+            Ok(value) => match column.rules.check_value(column, &value) {
+                Ok(()) => {
+                    names.push(column.name.clone());
+                    values.push(value);
+                }
+                Err(e) => errors.push(FieldError::new(name, format!("column {} {}", name, e))),
+            },
+            Err(e) => errors.push(FieldError::new(name, e.to_string())),
+        }
+    }
+
+    if write == Write::Insert {
+        for column in &schema.columns {
+            let required = column.not_null.unwrap_or(false) && column.default.is_none();
+
+            if required && !row.contains_key(column.name.as_str()) && !managed.contains(&&column.name) {
+                errors.push(FieldError::new(column.name.as_str(), format!("column {} is required", column.name.as_str())));
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok((names, values))
+    } else {
+        Err(Error::InvalidRow(errors))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schema() -> TableSchema {
+        let mut schema: TableSchema = serde_json::from_value(json!({
+            "name": "people",
+            "columns": [
+                {"name": "name", "type": "text", "not_null": true},
+                {"name": "age", "type": "integer"},
+                {"name": "active", "type": "boolean", "not_null": true, "default": true},
+            ]
+        })).unwrap();
+
+        let mut id = schema.columns[1].clone();
+        id.name = Identifier::managed("id");
+        id.not_null = Some(true);
+        schema.columns.insert(0, id);
+        schema
+    }
+
+    fn validate(body: serde_json::Value, write: Write) -> Result<(Vec<Identifier>, Vec<SqlValue>), Error> {
+        let schema = schema();
+        let id = Identifier::managed("id");
+        validate_row(&schema, &[&id], serde_json::from_value(body).unwrap(), write)
+    }
+
+    fn fields(result: Result<(Vec<Identifier>, Vec<SqlValue>), Error>) -> Vec<String> {
+        match result {
+            Err(Error::InvalidRow(errors)) => errors.into_iter().map(|error| error.field).collect(),
+            _ => panic!("expected field errors"),
+        }
+    }
+
+    #[test]
+    fn test_valid_rows() {
+        let (names, values) = validate(json!({"name": "bob", "active": false}), Write::Insert).ok().unwrap();
+        assert_eq!(names, vec![Identifier::managed("active"), Identifier::managed("name")]);
+        assert_eq!(values, vec![SqlValue::Integer(0), SqlValue::Text("bob".to_string())]);
+
+        assert!(validate(json!({"age": null}), Write::Update).is_ok());
+    }
+
+    #[test]
+    fn test_every_error_is_reported() {
+        let result = validate(json!({"id": 4, "age": "old", "email": "x"}), Write::Insert);
+        assert_eq!(fields(result), vec!["age", "email", "id", "name"]);
+
+        let result = validate(json!({"name": null, "active": null}), Write::Update);
+        assert_eq!(fields(result), vec!["active", "name"]);
+    }
+}
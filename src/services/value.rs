@@ -0,0 +1,128 @@
+use diesel::query_builder::{BoxedSqlQuery, SqlQuery};
+use diesel::sql_types::{BigInt, Double, Nullable, Text};
+use diesel::sqlite::Sqlite;
+use serde_json::Value;
+use time::format_description::well_known::Rfc3339;
+use time::macros::format_description;
+use time::{OffsetDateTime, PrimitiveDateTime, UtcOffset};
+
+/// A value bound as a parameter of a dynamically built query.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlValue {
+    Null,
+    Integer(i64),
+    Float(f64),
+    Text(String),
+}
+
+impl SqlValue {
+    /// Converts a scalar JSON value. Arrays and objects have no column
+    /// representation and yield `None`.
+    pub fn from_json(value: &Value) -> Option<SqlValue> {
+        match value {
+            Value::Null => Some(SqlValue::Null),
+            Value::Bool(b) => Some(SqlValue::Integer(*b as i64)),
+            Value::Number(n) => match n.as_i64() {
+                Some(i) => Some(SqlValue::Integer(i)),
+                None => n.as_f64().map(SqlValue::Float),
+            },
+            Value::String(s) => Some(SqlValue::Text(s.clone())),
+            Value::Array(_) | Value::Object(_) => None,
+        }
+    }
+
+    /// Renders the value as a SQL literal, for statements that cannot take
+    /// parameters such as index definitions. Parsed values are finite, but
+    /// should another slip through it renders as SQLite would store it:
+    /// infinities as an overflowing literal and NaN as NULL.
+    pub fn literal(&self) -> String {
+        match self {
+            SqlValue::Null => "NULL".to_string(),
+            SqlValue::Integer(i) => i.to_string(),
+            SqlValue::Float(f) if f.is_nan() => "NULL".to_string(),
+            SqlValue::Float(f) if f.is_infinite() => if *f > 0.0 { "9e999" } else { "-9e999" }.to_string(),
+            SqlValue::Float(f) => format!("{:?}", f),
+            SqlValue::Text(s) => quote_literal(s),
+        }
+    }
+}
+
+/// Renders `value` as a single-quoted SQL string literal.
+pub fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Parses an RFC 3339 timestamp or SQLite's own `YYYY-MM-DD HH:MM:SS`
+/// format, which is taken to be UTC like `CURRENT_TIMESTAMP`.
+pub fn parse_timestamp(value: &str) -> Option<OffsetDateTime> {
+    if let Ok(timestamp) = OffsetDateTime::parse(value, &Rfc3339) {
+        return Some(timestamp);
+    }
+
+    let sqlite_format = format_description!("[year]-[month]-[day] [hour]:[minute]:[second]");
+    PrimitiveDateTime::parse(value, sqlite_format)
+        .ok()
+        .map(PrimitiveDateTime::assume_utc)
+}
+
+/// Formats a timestamp the way SQLite's `CURRENT_TIMESTAMP` does, so stored
+/// values sort and compare consistently.
+pub fn sqlite_timestamp(timestamp: OffsetDateTime) -> String {
+    let sqlite_format = format_description!("[year]-[month]-[day] [hour]:[minute]:[second]");
+    timestamp
+        .to_offset(UtcOffset::UTC)
+        .format(sqlite_format)
+        .expect("timestamp should be formattable")
+}
+
+pub type DynQuery<'f> = BoxedSqlQuery<'f, Sqlite, SqlQuery>;
+
+/// Builds a boxed query and binds `values` to its `?` placeholders in order.
+pub fn query_with_binds<'f>(sql: String, values: Vec<SqlValue>) -> DynQuery<'f> {
+    let mut query = diesel::sql_query(sql).into_boxed::<Sqlite>();
+
+    for value in values {
+        query = match value {
+            SqlValue::Null => query.bind::<Nullable<Text>, _>(None::<String>),
+            SqlValue::Integer(i) => query.bind::<BigInt, _>(i),
+            SqlValue::Float(f) => query.bind::<Double, _>(f),
+            SqlValue::Text(s) => query.bind::<Text, _>(s),
+        };
+    }
+
+    query
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_from_json() {
+        assert_eq!(SqlValue::from_json(&json!(null)), Some(SqlValue::Null));
+        assert_eq!(SqlValue::from_json(&json!(true)), Some(SqlValue::Integer(1)));
+        assert_eq!(SqlValue::from_json(&json!(42)), Some(SqlValue::Integer(42)));
+        assert_eq!(SqlValue::from_json(&json!(1.5)), Some(SqlValue::Float(1.5)));
+        assert_eq!(SqlValue::from_json(&json!("bob")), Some(SqlValue::Text("bob".to_string())));
+        assert_eq!(SqlValue::from_json(&json!([1, 2])), None);
+    }
+
+    #[test]
+    fn test_literal() {
+        assert_eq!(SqlValue::Float(1.0).literal(), "1.0");
+        assert_eq!(SqlValue::Float(f64::INFINITY).literal(), "9e999");
+        assert_eq!(SqlValue::Float(f64::NEG_INFINITY).literal(), "-9e999");
+        assert_eq!(SqlValue::Float(f64::NAN).literal(), "NULL");
+        assert_eq!(SqlValue::Text("o'brien".to_string()).literal(), "'o''brien'");
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        let expected = "2024-03-01 10:30:00";
+        for value in ["2024-03-01 10:30:00", "2024-03-01T10:30:00Z", "2024-03-01T12:30:00+02:00"] {
+            assert_eq!(parse_timestamp(value).map(sqlite_timestamp).as_deref(), Some(expected));
+        }
+        assert!(parse_timestamp("yesterday").is_none());
+    }
+}
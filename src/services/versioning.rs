@@ -0,0 +1,124 @@
+//! Optimistic concurrency for tables with a `version` column. A row's
+//! version is its entity tag; updates and deletes sent with `If-Match` only
+//! apply while the row is still at one of the versions the client names, so
+//! a client editing a stale copy gets `Error::PreconditionFailed` instead of
+//! overwriting someone else's changes.
+
+use serde_json::Value;
+use crate::services::crud::{Row, TableSchema};
+use crate::services::identifier::Identifier;
+use crate::services::value::SqlValue;
+
+/// A row together with its entity tag, `None` unless the table keeps a
+/// version column.
+#[derive(Debug)]
+pub struct VersionedRow {
+    pub row: Row,
+    pub etag: Option<String>,
+}
+
+impl VersionedRow {
+    pub fn new(schema: &TableSchema, row: Row) -> Self {
+        let etag = version(schema, &row).map(etag);
+        Self { row, etag }
+    }
+}
+
+/// The precondition of an `If-Match` header.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IfMatch {
+    /// `*`: the row must exist, at any version.
+    Any,
+    /// The versions named by the listed entity tags. Weak tags and tags
+    /// fastfood did not issue name no version, as `If-Match` compares tags
+    /// strongly.
+    Versions(Vec<i64>),
+}
+
+impl IfMatch {
+    pub fn parse(header: &str) -> Result<Self, String> {
+        let header = header.trim();
+        if header == "*" {
+            return Ok(IfMatch::Any);
+        }
+
+        let mut versions = Vec::new();
+        for tag in header.split(',').map(str::trim) {
+            let (weak, quoted) = match tag.strip_prefix("W/") {
+                Some(quoted) => (true, quoted),
+                None => (false, tag),
+            };
+            let opaque = quoted
+                .strip_prefix('"')
+                .and_then(|quoted| quoted.strip_suffix('"'))
+                .filter(|opaque| !opaque.contains('"'))
+                .ok_or_else(|| format!("invalid entity tag {:?} in If-Match", tag))?;
+
+            if let Some(version) = opaque.parse::<i64>().ok().filter(|version| !weak && etag(*version) == quoted) {
+                versions.push(version);
+            }
+        }
+
+        Ok(IfMatch::Versions(versions))
+    }
+
+    /// Whether a row at `version`, `None` for tables without versions,
+    /// meets the precondition.
+    pub fn accepts(&self, version: Option<i64>) -> bool {
+        match self {
+            IfMatch::Any => true,
+            IfMatch::Versions(versions) => version.is_some_and(|version| versions.contains(&version)),
+        }
+    }
+
+    /// The condition to append to the `WHERE` clause of a write, so rows
+    /// failing the precondition are left alone.
+    pub fn where_sql(&self, version: Option<&Identifier>, binds: &mut Vec<SqlValue>) -> String {
+        match (self, version) {
+            (IfMatch::Any, _) => String::new(),
+            (IfMatch::Versions(_), None) => " AND 0".to_string(),
+            (IfMatch::Versions(versions), Some(column)) => {
+                binds.extend(versions.iter().map(|version| SqlValue::Integer(*version)));
+                format!(" AND {} IN ({})", column, vec!["?"; versions.len()].join(", "))
+            }
+        }
+    }
+}
+
+/// The entity tag of a row at `version`.
+pub fn etag(version: i64) -> String {
+    format!("\"{}\"", version)
+}
+
+/// The version of `row`, `None` when its table keeps none.
+pub fn version(schema: &TableSchema, row: &Row) -> Option<i64> {
+    let column = schema.options().version.as_ref()?;
+    row.get(column.as_str()).and_then(Value::as_i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_if_match() {
+        assert_eq!(IfMatch::parse(" * "), Ok(IfMatch::Any));
+        assert_eq!(IfMatch::parse("\"3\""), Ok(IfMatch::Versions(vec![3])));
+        assert_eq!(IfMatch::parse("\"3\", W/\"4\", \"x\", \"+5\",\"6\""), Ok(IfMatch::Versions(vec![3, 6])));
+        assert!(IfMatch::parse("3").is_err());
+        assert!(IfMatch::parse("").is_err());
+        assert!(IfMatch::parse("\"3\",").is_err());
+
+        assert!(IfMatch::Any.accepts(None));
+        assert!(IfMatch::Versions(vec![3]).accepts(Some(3)));
+        assert!(!IfMatch::Versions(vec![3]).accepts(Some(4)));
+        assert!(!IfMatch::Versions(vec![3]).accepts(None));
+
+        let version = Identifier::managed("version");
+        let mut binds = Vec::new();
+        assert_eq!(IfMatch::Versions(vec![1, 2]).where_sql(Some(&version), &mut binds), " AND \"version\" IN (?, ?)");
+        assert_eq!(binds, vec![SqlValue::Integer(1), SqlValue::Integer(2)]);
+        assert_eq!(IfMatch::Versions(vec![1]).where_sql(None, &mut binds), " AND 0");
+        assert_eq!(IfMatch::Any.where_sql(Some(&version), &mut binds), "");
+    }
+}